@@ -0,0 +1,352 @@
+use anyhow::Result;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bittorrent_starter_rust::{
+    ipfilter::IpFilter,
+    log::LogLevel,
+    rpc::{RPC_PATH, RPC_PORT},
+    storage, SessionConfig,
+};
+
+use crate::config::ConfigFile;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "A small BitTorrent client and tracker")]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// Directory downloads are written to when no -o is given
+    #[arg(long, global = true, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+    /// Port we tell trackers we accept peer connections on
+    #[arg(long, global = true, value_name = "PORT")]
+    pub listen_port: Option<u16>,
+    /// How much to report on stderr
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+    /// Per-module log levels on top of --log-level, like bittorrent_starter_rust::peer=debug. Defaults to RUST_LOG.
+    #[arg(long, global = true, value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+    /// Log every peer message sent and received, for debugging misbehaving peers
+    #[arg(long, global = true)]
+    pub wire_trace: bool,
+    /// Record the raw bytes of every peer connection to a file in DIR, for the replay command
+    #[arg(long, global = true, value_name = "DIR")]
+    pub capture: Option<PathBuf>,
+    /// Print results as JSON documents, see docs/json-output.md
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Read defaults from this TOML file instead of ~/.config/bittorrent-starter-rust/config.toml, see docs/config.md
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Never connect to or accept peers in the address ranges of this blocklist (eMule ipfilter.dat, P2P text or CIDR)
+    #[arg(long, global = true, value_name = "FILE")]
+    pub ip_filter: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Decode a bencoded value and print it as JSON
+    Decode {
+        encoded_value: String,
+    },
+    /// Print the metainfo of a torrent file
+    Info {
+        torrent: PathBuf,
+    },
+    /// Ask the torrent's trackers for peers
+    Peers {
+        torrent: PathBuf,
+    },
+    /// Ask the torrent's trackers for swarm statistics
+    Scrape {
+        torrent: PathBuf,
+    },
+    /// Handshake with a peer and print its peer id
+    Handshake {
+        torrent: PathBuf,
+        /// Peer address as <ip>:<port>
+        peer: String,
+    },
+    /// Download a single piece
+    #[command(name = "download_piece", visible_alias = "download-piece")]
+    DownloadPiece {
+        /// Where to write the piece
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        torrent: PathBuf,
+        piece: u32,
+    },
+    /// Download the whole torrent, showing progress on stderr (--log-level warn hides it)
+    Download {
+        /// Where to write the file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        torrent: PathBuf,
+    },
+    /// Check whether addresses are blocked by the IP filter
+    #[command(name = "ip_filter", visible_alias = "ip-filter")]
+    IpFilter {
+        #[arg(required = true)]
+        addresses: Vec<IpAddr>,
+    },
+    /// Play a connection recorded with --capture back through the protocol code, as if the peer were still there
+    Replay {
+        /// Recording from the --capture directory
+        recording: PathBuf,
+        torrent: PathBuf,
+    },
+    /// Create a torrent from a file or directory
+    Create {
+        /// Where to write the torrent, <name>.torrent by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Tracker URL, each one a tier of its own; separate URLs with commas to put them in one tier
+        #[arg(short, long = "tracker", value_name = "URL")]
+        trackers: Vec<String>,
+        /// Piece length in bytes, picked from the content size by default
+        #[arg(long)]
+        piece_length: Option<usize>,
+        path: PathBuf,
+    },
+    /// Run an HTTP tracker
+    Tracker {
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: String,
+        #[arg(long, default_value_t = 6969)]
+        port: u16,
+        /// Announce interval in seconds
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+        /// Only track the info hashes listed in this file
+        #[arg(long, value_name = "FILE")]
+        whitelist: Option<PathBuf>,
+    },
+    /// Run torrents in the background, controlled over JSON-RPC
+    Daemon(DaemonArgs),
+    /// Manage torrents interactively in a full-screen terminal UI
+    Tui {
+        #[command(flatten)]
+        session: SessionArgs,
+        /// Torrent files to add on startup
+        torrents: Vec<PathBuf>,
+    },
+    /// Control a running daemon
+    Remote {
+        /// The daemon's RPC endpoint
+        #[arg(long, default_value_t = format!("http://127.0.0.1:{}{}", RPC_PORT, RPC_PATH))]
+        url: String,
+        #[command(subcommand)]
+        action: RemoteAction,
+    },
+    /// Print a shell completion script
+    Completions {
+        shell: Shell,
+    },
+    /// Print the man page
+    Man,
+}
+
+#[derive(Debug, Args)]
+pub struct DaemonArgs {
+    /// Address the RPC endpoint listens on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub rpc_bind: String,
+    #[arg(long, default_value_t = RPC_PORT)]
+    pub rpc_port: u16,
+    /// Also serve Transmission's RPC protocol on /transmission/rpc, for its clients and web UIs
+    #[arg(long)]
+    pub transmission: bool,
+    #[command(flatten)]
+    pub session: SessionArgs,
+}
+
+// What a long-running session is configured with, shared by daemon and tui
+#[derive(Debug, Args)]
+pub struct SessionArgs {
+    /// Where torrents and their progress are kept across restarts, <output dir>/.session by default
+    #[arg(long, value_name = "DIR")]
+    pub state_dir: Option<PathBuf>,
+    /// Torrents downloading at the same time
+    #[arg(long, value_name = "N")]
+    pub max_active_downloads: Option<usize>,
+    /// Torrents seeding at the same time, 0 to stop torrents once they complete
+    #[arg(long, value_name = "N")]
+    pub max_active_seeds: Option<usize>,
+    /// Download limit across all torrents in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub download_rate: Option<u64>,
+    /// Upload limit across all torrents in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub upload_rate: Option<u64>,
+    /// Download limit for each peer connection in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub peer_download_rate: Option<u64>,
+    /// Upload limit for each peer connection in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub peer_upload_rate: Option<u64>,
+    /// Serve Prometheus metrics on http://ADDR/metrics, e.g. 127.0.0.1:9100
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemoteAction {
+    /// Add a torrent file
+    Add {
+        /// Where the daemon should download to, <daemon output dir>/<name> by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        torrent: PathBuf,
+    },
+    /// List every torrent
+    List,
+    /// Show one torrent
+    Show { info_hash: String },
+    Pause { info_hash: String },
+    Resume { info_hash: String },
+    Remove {
+        info_hash: String,
+        /// Delete the downloaded files too
+        #[arg(long)]
+        delete_data: bool,
+    },
+    /// Show or change the session's rate limits, or a torrent's
+    Limits {
+        /// Download limit in bytes per second, 0 for unlimited
+        #[arg(long, value_name = "BYTES")]
+        download: Option<u64>,
+        /// Upload limit in bytes per second, 0 for unlimited
+        #[arg(long, value_name = "BYTES")]
+        upload: Option<u64>,
+        /// Download limit for each peer connection, 0 for unlimited
+        #[arg(long, value_name = "BYTES", conflicts_with = "torrent")]
+        peer_download: Option<u64>,
+        /// Upload limit for each peer connection, 0 for unlimited
+        #[arg(long, value_name = "BYTES", conflicts_with = "torrent")]
+        peer_upload: Option<u64>,
+        /// Show or change this torrent's own limits instead
+        #[arg(long, value_name = "INFO_HASH")]
+        torrent: Option<String>,
+    },
+    /// Stop the daemon
+    Shutdown,
+}
+
+// The global options once the config file, environment and command line are
+// merged
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub output_dir: PathBuf,
+    // Defaults for sessions, the one behind the download command included
+    pub session: SessionConfig,
+    pub log_level: LogLevel,
+    pub log_filter: Option<String>,
+    pub wire_trace: bool,
+    pub capture: Option<PathBuf>,
+    pub json: bool,
+    // The blocklist behind session.ip_filter, if there is one
+    pub ip_filter: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn load(args: &GlobalArgs) -> Result<Self> {
+        let config = ConfigFile::load(args.config.as_deref())?;
+        let mut session = config.session_config();
+        session.listen_port = args.listen_port.unwrap_or(session.listen_port);
+        Ok(Options {
+            output_dir: args.output_dir.clone().or(config.storage.output_dir).unwrap_or_else(|| PathBuf::from(".")),
+            session,
+            log_level: args.log_level.or(config.logging.level).unwrap_or(LogLevel::Info),
+            log_filter: args.log_filter.clone().or(config.logging.filter),
+            wire_trace: args.wire_trace || config.logging.wire_trace.unwrap_or(false),
+            capture: args.capture.clone().or(config.logging.capture),
+            json: args.json,
+            ip_filter: args.ip_filter.clone().or(config.network.ip_filter),
+        })
+    }
+
+    // Separate from load so what's wrong with the blocklist gets logged
+    pub fn load_ip_filter(&mut self) -> Result<()> {
+        if let Some(path) = &self.ip_filter {
+            self.session.ip_filter = Arc::new(IpFilter::load(path)?);
+        }
+        Ok(())
+    }
+
+    // An explicit output path is used as is, otherwise the default name goes
+    // in the output directory. The default comes from the torrent's name, so
+    // it's held to the same check as the torrent's own file names.
+    pub fn output_path(&self, output: Option<&Path>, default_name: &str) -> Result<PathBuf> {
+        match output {
+            Some(output) => Ok(output.to_path_buf()),
+            None => Ok(self.output_dir.join(storage::file_name(default_name)?)),
+        }
+    }
+}
+
+pub fn print_completions(shell: Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+pub fn print_man_page() -> Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_legacy_command_names() {
+        let cli = Cli::parse_from(["bt", "download_piece", "-o", "/tmp/piece", "sample.torrent", "3"]);
+        assert!(matches!(cli.command, Command::DownloadPiece { piece: 3, .. }));
+        let cli = Cli::parse_from(["bt", "download", "sample.torrent", "--output-dir", "/tmp"]);
+        assert!(matches!(cli.command, Command::Download { output: None, .. }));
+        assert_eq!(cli.global.output_dir, Some(PathBuf::from("/tmp")));
+        // Extra arguments used to be silently accepted
+        assert!(Cli::try_parse_from(["bt", "download_piece", "-o", "x", "sample.torrent"]).is_err());
+        assert!(Cli::try_parse_from(["bt", "info", "a.torrent", "b.torrent"]).is_err());
+    }
+
+    #[test]
+    fn test_command_line_overrides_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = "[network]\nlisten_port = 7000\n\n[storage]\noutput_dir = \"/downloads\"\n\n[logging]\nlevel = \"debug\"\nfilter = \"wire=trace\"\n";
+        fs::write(&path, contents).unwrap();
+        let config = path.to_str().unwrap();
+
+        let cli = Cli::parse_from(["bt", "--config", config, "--listen-port", "7001", "info", "a.torrent"]);
+        let options = Options::load(&cli.global).unwrap();
+        assert_eq!(options.output_dir, PathBuf::from("/downloads"));
+        assert_eq!(options.session.listen_port, 7001);
+        assert_eq!(options.log_level, LogLevel::Debug);
+        assert_eq!(options.log_filter.as_deref(), Some("wire=trace"));
+
+        fs::write(&path, "[network]\nlisten_prot = 7000\n").unwrap();
+        assert!(Options::load(&cli.global).is_err());
+    }
+}
@@ -1,10 +1,42 @@
 use anyhow::{anyhow, Result};
-use std::{cmp, fs, io::Write, net::TcpStream};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::Write,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
 
-use crate::{
+use bittorrent_starter_rust::{
     decoder::decode_bencoded_value,
-    protocol::{download_piece, get_peers_from_tracker, perform_handshake_with_peer, wait_for_bitfield, send_am_interested, wait_for_unchoke},
-    types::{Files, Torrent},
+    download::piece_length,
+    metrics,
+    peer::PeerConnection,
+    protocol::perform_handshake_with_peer,
+    ratelimit::RateLimits,
+    tracker::{
+        scrape,
+        server::{load_whitelist, TrackerServer},
+        TrackerEvent, TrackerSession, TrackerTiers,
+    },
+    rpc::{RpcClient, RpcServer, SessionStatus, TorrentStatus},
+    wire::{self, Recording},
+    Event, Session, SessionConfig, Torrent, TorrentBuilder,
+};
+
+use crate::{
+    cli::{DaemonArgs, Options, RemoteAction, SessionArgs},
+    output::{
+        self, CreateOutput, DaemonOutput, DownloadOutput, HandshakeOutput, InfoOutput, IpFilterOutput, PeersOutput, PieceOutput,
+        RemoteActionOutput, RemoteLimitsOutput, RemoteListOutput, RemoteTorrentOutput, ReplayOutput, ScrapeOutput,
+        TrackerOutput,
+    },
+    progress::{ProgressDisplay, REDRAW_INTERVAL},
+    tui::{self, App},
 };
 
 pub fn cmd_decode(encoded_value: &str) -> Result<()> {
@@ -14,121 +46,342 @@ pub fn cmd_decode(encoded_value: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn cmd_info(torrent_name: &str) -> Result<()> {
-    let encoded_value = fs::read(torrent_name)?;
-    let torrent: Torrent = serde_bencode::from_bytes(&encoded_value)?;
-    println!("Tracker URL: {}", torrent.announce);
-    println!("Length: {}", torrent.info.files.length());
+pub fn cmd_info(torrent_name: &Path, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
     let info_hash = torrent.info.calculate_info_hash()?;
-    println!("Info Hash: {}", hex::encode(info_hash));
-    println!("Piece Length: {}", torrent.info.piece_length);
-    println!("Piece Hashes:");
-    for hash in torrent.info.pieces.0 {
-        println!("{}", hex::encode(hash));
-    }
-    Ok(())
+    output::print(&InfoOutput::new(&torrent, &info_hash), options.json)
 }
 
-pub fn cmd_peers(torrent_name: &str) -> Result<()> {
-    let encoded_value = fs::read(torrent_name)?;
-    let torrent: Torrent = serde_bencode::from_bytes(&encoded_value)?;
+pub fn cmd_peers(torrent_name: &Path, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
     let info_hash = torrent.info.calculate_info_hash()?;
-    let left = match torrent.info.files {
-        Files::Single { length } => length,
-        Files::Multiple { files } => files.iter().map(|file| file.length).sum(),
-    };
-    let peers = get_peers_from_tracker(torrent.announce, &info_hash, left)?;
-    for peer in peers {
-        println!("{}:{}", peer.ip(), peer.port());
-    }
-    Ok(())
+    let left = torrent.info.files.length();
+    let mut tracker = TrackerSession::new(info_hash, options.session.peer_id, TrackerTiers::from_torrent(&torrent), left as u64);
+    tracker.set_port(options.session.listen_port);
+    tracker.set_numwant(options.session.numwant);
+    tracker.set_ip_filter(options.session.ip_filter.clone());
+    let peers = tracker.start();
+    report_tracker_events(&mut tracker);
+    output::print(&PeersOutput::new(&info_hash, &peers?), options.json)
 }
 
-pub fn cmd_handshake(torrent_name: &str, peer_addr: &str) -> Result<()> {
-    let encoded_value = fs::read(torrent_name)?;
-    let torrent: Torrent = serde_bencode::from_bytes(&encoded_value)?;
+pub fn cmd_scrape(torrent_name: &Path, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
     let info_hash = torrent.info.calculate_info_hash()?;
 
-    let mut stream = TcpStream::connect(peer_addr)?;
-    let peer_id = perform_handshake_with_peer(&mut stream, &info_hash)?;
-    println!("Peer ID: {}", hex::encode(&peer_id));
-    Ok(())
+    let (url, stats) = TrackerTiers::from_torrent(&torrent).announce(|url| {
+        let stats = scrape(url, &[info_hash])?;
+        let stats = stats.get(&info_hash).copied().ok_or_else(|| anyhow!("Tracker does not know this torrent"))?;
+        Ok((url.to_string(), stats))
+    })?;
+    output::print(&ScrapeOutput::new(url, &info_hash, &stats), options.json)
 }
 
-pub fn cmd_download_piece(output_name: &str, torrent_name: &str, piece_num: &str) -> Result<()> {
-    let encoded_value = fs::read(torrent_name)?;
-    let torrent: Torrent = serde_bencode::from_bytes(&encoded_value)?;
+pub fn cmd_handshake(torrent_name: &Path, peer_addr: &str, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
     let info_hash = torrent.info.calculate_info_hash()?;
-    let left = torrent.info.files.length();
 
-    let peers = get_peers_from_tracker(torrent.announce, &info_hash, left)?;
-    let peer = peers[0];
-    let mut stream = TcpStream::connect(peer)?;
-    let _ = perform_handshake_with_peer(&mut stream, &info_hash)?;
-
-    let bitfield = wait_for_bitfield(&mut stream)?;
-    let bitfield_bytes = (torrent.info.pieces.0.len() + 7) / 8;
-    if bitfield_bytes != bitfield.len() {
-        return Err(anyhow!(
-            "Expected bitfield of length {}, got {}", bitfield_bytes, bitfield.len()
-        ));
+    let timeouts = &options.session.timeouts;
+    let addr = peer_addr.to_socket_addrs()?.next().ok_or_else(|| anyhow!("Peer address {} resolves to nothing", peer_addr))?;
+    if options.session.ip_filter.is_blocked(addr.ip()) {
+        return Err(anyhow!("Peer {} is blocked by the IP filter", addr));
     }
-    eprintln!("Bitfield: {}", hex::encode(&bitfield));
+    let stream = TcpStream::connect_timeout(&addr, timeouts.connect)?;
+    stream.set_read_timeout(Some(timeouts.handshake))?;
+    stream.set_write_timeout(Some(timeouts.handshake))?;
+    let mut stream = wire::capture(stream, false);
+    let handshake = perform_handshake_with_peer(&mut stream, &info_hash, &options.session.peer_id)?;
+    output::print(&HandshakeOutput::new(peer_addr, &handshake), options.json)
+}
 
-    send_am_interested(&mut stream)?;
+pub fn cmd_ip_filter(addresses: &[IpAddr], options: &Options) -> Result<()> {
+    let path = options.ip_filter.as_deref().ok_or_else(|| anyhow!("No IP filter to check against, pass --ip-filter or set ip_filter in [network]"))?;
+    output::print(&IpFilterOutput::new(path, &options.session.ip_filter, addresses), options.json)
+}
+
+// A replay that goes wrong is what it's for, so the error is part of the
+// output rather than a failed command
+pub fn cmd_replay(recording_name: &Path, torrent_name: &Path, options: &Options) -> Result<()> {
+    let recording = Recording::load(recording_name)?;
+    let torrent = Torrent::read(torrent_name)?;
+    let info_hash = torrent.info.calculate_info_hash()?;
+    let replayed = wire::replay(&recording, &torrent.info, &info_hash);
+    output::print(&ReplayOutput::new(&recording, &replayed), options.json)
+}
+
+pub fn cmd_download_piece(output_name: Option<&Path>, torrent_name: &Path, piece_index: u32, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
+    let info_hash = torrent.info.calculate_info_hash()?;
+    let left = torrent.info.files.length();
+    if piece_index as usize >= torrent.info.pieces.0.len() {
+        return Err(anyhow!("Piece index {} out of range, torrent has {} pieces", piece_index, torrent.info.pieces.0.len()));
+    }
 
-    wait_for_unchoke(&mut stream)?;
+    let mut tracker = TrackerSession::new(info_hash, options.session.peer_id, TrackerTiers::from_torrent(&torrent), left as u64);
+    tracker.set_port(options.session.listen_port);
+    tracker.set_numwant(options.session.numwant);
+    tracker.set_ip_filter(options.session.ip_filter.clone());
+    let peers = tracker.start();
+    report_tracker_events(&mut tracker);
+    let mut connection = connect_to_peer_with_piece(&peers?, &torrent, &info_hash, &options.session, piece_index)?;
 
-    let piece_index = piece_num.parse::<u32>()?;
-    let piece_length = (torrent.info.piece_length) as u32;
-    let piece_length = cmp::min(piece_length, (torrent.info.files.length() as u32) - piece_index * piece_length);
     let expected_piece_hash = &torrent.info.pieces.0[piece_index as usize];
-    let piece_data = download_piece(&mut stream, piece_index, piece_length, expected_piece_hash)?;
+    let piece_length = piece_length(&torrent.info, piece_index);
+    connection.block_len = options.session.block_len;
+    let piece_data = connection.download_piece(piece_index, piece_length, expected_piece_hash)?;
+    tracker.add_downloaded(piece_data.len() as u64);
 
-    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(output_name)?;
+    let output_name = options.output_path(output_name, &format!("{}.piece-{}", torrent.info.name, piece_index))?;
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&output_name)?;
     file.write_all(&piece_data)?;
     file.sync_all()?;
 
-    Ok(())
+    if let Err(e) = tracker.stop() {
+        warn!("Failed to send stopped event to tracker: {}", e);
+    }
+    report_tracker_events(&mut tracker);
+
+    let result = PieceOutput {
+        output: output_name,
+        piece: piece_index,
+        length: piece_data.len(),
+        hash: hex::encode(expected_piece_hash),
+    };
+    output::print(&result, options.json)
 }
 
-#[allow(unused_variables)]
-pub fn cmd_download(output_name: &str, torrent_name: &str) -> Result<()> {
-    let encoded_value = fs::read(torrent_name)?;
-    let torrent: Torrent = serde_bencode::from_bytes(&encoded_value)?;
+pub fn cmd_download(output_name: Option<&Path>, torrent_name: &Path, options: &Options) -> Result<()> {
+    let torrent = Torrent::read(torrent_name)?;
+    let output_name = options.output_path(output_name, &torrent.info.name)?;
+    let (length, pieces) = (torrent.info.files.length(), torrent.info.pieces.0.len());
+
+    let started = Instant::now();
+    // Stop as soon as the download is done rather than seeding
+    let config = SessionConfig { state_dir: None, max_active_seeds: 0, ..options.session.clone() };
+    let session = Session::new(config)?;
+    let handle = session.add_torrent(torrent, &output_name)?;
+    let events = handle.subscribe();
+    let mut display = ProgressDisplay::new(handle.clone());
+    loop {
+        match events.recv_timeout(REDRAW_INTERVAL) {
+            Ok(event) => {
+                display.event(&event);
+                match event {
+                    Event::TrackerWarning { url, message } => warn!("Tracker warning from {}: {}", url, message),
+                    Event::Completed | Event::Failed(_) => break,
+                    _ => {}
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        display.tick();
+    }
+    display.finish();
+    handle.wait()?;
+
+    let result = DownloadOutput {
+        output: output_name,
+        info_hash: hex::encode(handle.info_hash()),
+        length,
+        pieces,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    };
+    output::print(&result, options.json)
+}
+
+pub fn cmd_create(
+    output_name: Option<&Path>,
+    path: &Path,
+    trackers: &[String],
+    piece_length: Option<usize>,
+    options: &Options,
+) -> Result<()> {
+    let mut builder = TorrentBuilder::new(path);
+    for tier in trackers {
+        builder = builder.tier(tier.split(',').map(str::to_string).collect());
+    }
+    if let Some(piece_length) = piece_length {
+        builder = builder.piece_length(piece_length);
+    }
+    let torrent = builder.build()?;
     let info_hash = torrent.info.calculate_info_hash()?;
-    let left = torrent.info.files.length();
 
-    let peers = get_peers_from_tracker(torrent.announce, &info_hash, left)?;
-    let peer = peers[0];
-    let mut stream = TcpStream::connect(peer)?;
-    let _ = perform_handshake_with_peer(&mut stream, &info_hash)?;
-
-    let bitfield = wait_for_bitfield(&mut stream)?;
-    let bitfield_bytes = (torrent.info.pieces.0.len() + 7) / 8;
-    if bitfield_bytes != bitfield.len() {
-        return Err(anyhow!(
-            "Expected bitfield of length {}, got {}", bitfield_bytes, bitfield.len()
-        ));
+    let output_name = options.output_path(output_name, &format!("{}.torrent", torrent.info.name))?;
+    fs::write(&output_name, torrent.to_bytes()?)?;
+    output::print(&CreateOutput { output: output_name, info: InfoOutput::new(&torrent, &info_hash) }, options.json)
+}
+
+pub fn cmd_tracker(bind: &str, port: u16, interval: Duration, whitelist: Option<&Path>, options: &Options) -> Result<()> {
+    let mut server = TrackerServer::new(interval);
+    if let Some(whitelist) = whitelist {
+        server.set_whitelist(load_whitelist(whitelist)?);
     }
-    eprintln!("Bitfield: {}", hex::encode(&bitfield));
 
-    send_am_interested(&mut stream)?;
+    let listener = TcpListener::bind((bind, port))?;
+    output::print(&TrackerOutput { listen_addr: listener.local_addr()? }, options.json)?;
+    std::io::stdout().flush()?;
+    server.serve(listener)
+}
 
-    wait_for_unchoke(&mut stream)?;
+// Config for a session that keeps running and persists its torrents, with
+// downloads going to `output_dir`
+fn session_config(args: &SessionArgs, output_dir: &Path, options: &Options) -> SessionConfig {
+    let defaults = &options.session;
+    let state_dir = args.state_dir.clone().or_else(|| defaults.state_dir.clone()).unwrap_or_else(|| output_dir.join(".session"));
+    SessionConfig {
+        state_dir: Some(state_dir),
+        max_active_downloads: args.max_active_downloads.unwrap_or(defaults.max_active_downloads),
+        max_active_seeds: args.max_active_seeds.unwrap_or(defaults.max_active_seeds),
+        download_rate: args.download_rate.or(defaults.download_rate),
+        upload_rate: args.upload_rate.or(defaults.upload_rate),
+        peer_download_rate: args.peer_download_rate.or(defaults.peer_download_rate),
+        peer_upload_rate: args.peer_upload_rate.or(defaults.peer_upload_rate),
+        ..defaults.clone()
+    }
+}
 
-    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(output_name)?;
-    let num_pieces = torrent.info.pieces.0.len();
-    for i in 0..num_pieces {
-        let piece_index = i as u32;
-        let piece_length = (torrent.info.piece_length) as u32;
-        let piece_length = cmp::min(piece_length, (torrent.info.files.length() as u32) - piece_index * piece_length);
-        let expected_piece_hash = &torrent.info.pieces.0[piece_index as usize];
-        let piece_data = download_piece(&mut stream, piece_index, piece_length, expected_piece_hash)?;
+// Serves the session's metrics from a thread of its own, if asked to.
+// Returns the address they're served on.
+fn serve_metrics(args: &SessionArgs, session: &Arc<Session>) -> Result<Option<SocketAddr>> {
+    let Some(addr) = &args.metrics else { return Ok(None) };
+    let listener = TcpListener::bind(addr.as_str()).map_err(|e| anyhow!("Can't serve metrics on {}: {}", addr, e))?;
+    let addr = listener.local_addr()?;
+    let session = session.clone();
+    thread::spawn(move || {
+        if let Err(e) = metrics::serve(session, listener) {
+            warn!("Metrics endpoint stopped: {:#}", e);
+        }
+    });
+    Ok(Some(addr))
+}
 
-        file.write_all(&piece_data)?;
+pub fn cmd_daemon(args: &DaemonArgs, options: &Options) -> Result<()> {
+    // Clients may add torrents from anywhere, so relative paths are pinned now
+    let output_dir = std::path::absolute(&options.output_dir)?;
+    let session = Arc::new(Session::new(session_config(&args.session, &output_dir, options))?);
+    let metrics_addr = serve_metrics(&args.session, &session)?;
+
+    let listener = TcpListener::bind((args.rpc_bind.as_str(), args.rpc_port))?;
+    let result = DaemonOutput { rpc_addr: listener.local_addr()?, listen_port: session.listen_addr().port(), metrics_addr };
+    output::print(&result, options.json)?;
+    std::io::stdout().flush()?;
+    let mut server = RpcServer::new(session.clone(), output_dir);
+    if args.transmission {
+        server = server.with_transmission();
     }
-    file.sync_all()?;
+    Arc::new(server).serve(listener)?;
+    session.shutdown();
+    Ok(())
+}
 
+pub fn cmd_tui(args: &SessionArgs, torrents: &[PathBuf], options: &Options) -> Result<()> {
+    let output_dir = std::path::absolute(&options.output_dir)?;
+    let session = Arc::new(Session::new(session_config(args, &output_dir, options))?);
+    if let Some(addr) = serve_metrics(args, &session)? {
+        info!("Metrics on http://{}{}", addr, metrics::METRICS_PATH);
+    }
+    let mut app = App::new(session.clone(), output_dir);
+    for torrent in torrents {
+        if let Err(e) = app.add(torrent) {
+            warn!("Can't add {}: {:#}", torrent.display(), e);
+        }
+    }
+    tui::run(&mut app)?;
+    session.shutdown();
     Ok(())
 }
+
+pub fn cmd_remote(url: &str, action: &RemoteAction, options: &Options) -> Result<()> {
+    let client = RpcClient::new(url);
+    let done = |action: &'static str, info_hash: Option<&String>| {
+        output::print(&RemoteActionOutput { action, info_hash: info_hash.cloned() }, options.json)
+    };
+    match action {
+        RemoteAction::Add { output, torrent } => {
+            let output = output.as_deref().map(std::path::absolute).transpose()?;
+            let status = client.add_torrent(&fs::read(torrent)?, output)?;
+            output::print(&RemoteTorrentOutput(status), options.json)
+        }
+        RemoteAction::List => {
+            let torrents: Vec<TorrentStatus> = client.call("torrent.list", Value::Null)?;
+            output::print(&RemoteListOutput { torrents }, options.json)
+        }
+        RemoteAction::Show { info_hash } => {
+            let status: TorrentStatus = client.call("torrent.get", json!({ "info_hash": info_hash }))?;
+            output::print(&RemoteTorrentOutput(status), options.json)
+        }
+        RemoteAction::Pause { info_hash } => {
+            client.call::<Value>("torrent.pause", json!({ "info_hash": info_hash }))?;
+            done("paused", Some(info_hash))
+        }
+        RemoteAction::Resume { info_hash } => {
+            client.call::<Value>("torrent.resume", json!({ "info_hash": info_hash }))?;
+            done("resumed", Some(info_hash))
+        }
+        RemoteAction::Remove { info_hash, delete_data } => {
+            client.call::<Value>("torrent.remove", json!({ "info_hash": info_hash, "delete_data": delete_data }))?;
+            done("removed", Some(info_hash))
+        }
+        RemoteAction::Limits { download, upload, torrent: Some(info_hash), .. } => {
+            let status: TorrentStatus = match (download, upload) {
+                (None, None) => client.call("torrent.get", json!({ "info_hash": info_hash }))?,
+                _ => client.call("torrent.set_limits", json!({ "info_hash": info_hash, "download_rate": download, "upload_rate": upload }))?,
+            };
+            output::print(&RemoteTorrentOutput(status), options.json)
+        }
+        RemoteAction::Limits { download, upload, peer_download, peer_upload, torrent: None } => {
+            let status: SessionStatus = match (download, upload, peer_download, peer_upload) {
+                (None, None, None, None) => client.call("session.get", Value::Null)?,
+                _ => client.call(
+                    "session.set_limits",
+                    json!({ "download_rate": download, "upload_rate": upload, "peer_download_rate": peer_download, "peer_upload_rate": peer_upload }),
+                )?,
+            };
+            output::print(&RemoteLimitsOutput(status), options.json)
+        }
+        RemoteAction::Shutdown => {
+            client.call::<Value>("session.shutdown", Value::Null)?;
+            done("shut down", None)
+        }
+    }
+}
+
+// The first of the peers that has the piece, ready to download it within
+// the configured limits
+fn connect_to_peer_with_piece(
+    peers: &[SocketAddr],
+    torrent: &Torrent,
+    info_hash: &[u8; 20],
+    config: &SessionConfig,
+    piece_index: u32,
+) -> Result<PeerConnection> {
+    let mut last_err = anyhow!("No peers to download from");
+    for peer in peers {
+        // Peers are tried one at a time, so each has the session's limits to itself
+        let limits = vec![
+            Arc::new(RateLimits::new(config.download_rate, config.upload_rate)),
+            Arc::new(RateLimits::new(config.peer_download_rate, config.peer_upload_rate)),
+        ];
+        match PeerConnection::connect(*peer, info_hash, &config.peer_id, torrent.info.pieces.0.len(), limits, config.timeouts) {
+            Ok(connection) if connection.has_piece(piece_index) => {
+                debug!("Bitfield: {}", hex::encode(&connection.bitfield));
+                return Ok(connection);
+            }
+            Ok(_) => last_err = anyhow!("Peer {} does not have piece {}", peer, piece_index),
+            Err(e) => {
+                warn!("Peer {} failed: {:#}", peer, e);
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+fn report_tracker_events(tracker: &mut TrackerSession) {
+    for event in tracker.take_events() {
+        if let TrackerEvent::Warning { url, message } = event {
+            warn!("Tracker warning from {}: {}", url, message);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use bittorrent_starter_rust::{
+    download::ReconnectPolicy, log::LogLevel, peer::Timeouts, protocol::CHUNK_LEN, ratelimit::Schedule, SessionConfig,
+};
+
+// Environment variables override single keys of the config file, like
+// BITTORRENT_NETWORK_LISTEN_PORT for listen_port in [network]
+pub const ENV_PREFIX: &str = "BITTORRENT_";
+// Where the config file is looked for under $XDG_CONFIG_HOME, or ~/.config
+const CONFIG_PATH: &str = "bittorrent-starter-rust/config.toml";
+// Smaller requests than this are more overhead than they're worth
+const MIN_BLOCK_LEN: u32 = 1024;
+
+// Defaults for the command line options and the sessions we run. Every key
+// is optional, see docs/config.md.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub network: Network,
+    pub limits: Limits,
+    pub storage: Storage,
+    pub trackers: Trackers,
+    pub dht: Dht,
+    pub logging: Logging,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Network {
+    pub listen_port: Option<u16>,
+    // Size of the blocks pieces are requested in
+    pub block_size: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub max_peers_per_torrent: Option<usize>,
+    // Seconds peers get to accept our connection, answer our handshake and
+    // our requests, and to send anything at all
+    pub connect_timeout: Option<f64>,
+    pub handshake_timeout: Option<f64>,
+    pub request_timeout: Option<f64>,
+    pub idle_timeout: Option<f64>,
+    // Seconds of quiet before we send a keep-alive
+    pub keep_alive_interval: Option<f64>,
+    // Seconds before a failed peer is tried again, doubling each time, and
+    // how many times in a row it may fail
+    pub reconnect_backoff: Option<f64>,
+    pub max_peer_failures: Option<u32>,
+    // Blocklist of addresses we don't talk to
+    pub ip_filter: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // Bytes per second across all torrents, 0 for unlimited
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    // Bytes per second for each peer connection on its own
+    pub peer_download_rate: Option<u64>,
+    pub peer_upload_rate: Option<u64>,
+    // Times of day with other session limits, like none at night
+    pub schedule: Schedule,
+    pub max_active_downloads: Option<usize>,
+    pub max_active_seeds: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub output_dir: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Trackers {
+    // Peers asked for in each announce
+    pub numwant: Option<u32>,
+}
+
+// Only here so a config shared with other clients can say DHT is off
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dht {
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    pub level: Option<LogLevel>,
+    pub filter: Option<String>,
+    pub wire_trace: Option<bool>,
+    pub capture: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    // The config file at `path`, or at the default location when there's one
+    // there, with the environment's overrides on top
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = path.map(Path::to_path_buf).or_else(|| default_path().filter(|path| path.exists()));
+        ConfigFile::load_from(path.as_deref(), env::vars())
+    }
+
+    fn load_from(path: Option<&Path>, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut table = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+                // Straight from the text first, so errors point at a line
+                let config: ConfigFile = toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))?;
+                config.validate().with_context(|| format!("Invalid config file {}", path.display()))?;
+                toml::from_str(&contents)?
+            }
+            None => toml::Table::new(),
+        };
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else { continue };
+            let key = key.to_lowercase();
+            let (section, key) = key
+                .split_once('_')
+                .ok_or_else(|| anyhow!("Invalid environment variable {}, expected {}<SECTION>_<KEY>", name, ENV_PREFIX))?;
+            let value = env_value(section, key, &value).with_context(|| format!("Invalid environment variable {}", name))?;
+            if let Some(section) = table.entry(section).or_insert_with(|| toml::Table::new().into()).as_table_mut() {
+                section.insert(key.to_string(), value);
+            }
+        }
+        from_table(table)
+    }
+
+    // Checks what the types don't, naming the key that's wrong
+    fn validate(&self) -> Result<()> {
+        if let Some(block_size) = self.network.block_size {
+            if !block_size.is_power_of_two() || !(MIN_BLOCK_LEN..=CHUNK_LEN).contains(&block_size) {
+                return Err(anyhow!("network.block_size must be a power of two from {} to {}, got {}", MIN_BLOCK_LEN, CHUNK_LEN, block_size));
+            }
+        }
+        for (key, value) in [
+            ("network.max_connections", self.network.max_connections),
+            ("network.max_peers_per_torrent", self.network.max_peers_per_torrent),
+            ("network.max_peer_failures", self.network.max_peer_failures.map(|failures| failures as usize)),
+            ("trackers.numwant", self.trackers.numwant.map(|numwant| numwant as usize)),
+        ] {
+            if value == Some(0) {
+                return Err(anyhow!("{} must be at least 1", key));
+            }
+        }
+        for (key, value) in self.network.durations() {
+            if let Some(value) = value {
+                if !(value > 0.0 && Duration::try_from_secs_f64(value).is_ok()) {
+                    return Err(anyhow!("{} must be a positive number of seconds, got {}", key, value));
+                }
+            }
+        }
+        let timeouts = self.network.timeouts();
+        if timeouts.keep_alive >= timeouts.idle {
+            return Err(anyhow!("network.keep_alive_interval must be shorter than network.idle_timeout, or peers time out before we keep them alive"));
+        }
+        if self.dht.enabled == Some(true) {
+            return Err(anyhow!("dht.enabled can't be true, DHT isn't supported yet and peers only come from trackers"));
+        }
+        if let Some(filter) = &self.logging.filter {
+            EnvFilter::builder().parse(filter).map_err(|e| anyhow!("logging.filter is not a valid filter: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // The defaults for sessions, before any command line options
+    pub fn session_config(&self) -> SessionConfig {
+        let defaults = SessionConfig::default();
+        let rate = |rate: Option<u64>| rate.filter(|rate| *rate > 0);
+        SessionConfig {
+            peer_id: defaults.peer_id,
+            listen_port: self.network.listen_port.unwrap_or(defaults.listen_port),
+            state_dir: self.storage.state_dir.clone(),
+            max_active_downloads: self.limits.max_active_downloads.unwrap_or(defaults.max_active_downloads),
+            max_active_seeds: self.limits.max_active_seeds.unwrap_or(defaults.max_active_seeds),
+            max_connections: self.network.max_connections.unwrap_or(defaults.max_connections),
+            max_peers_per_torrent: self.network.max_peers_per_torrent.unwrap_or(defaults.max_peers_per_torrent),
+            block_len: self.network.block_size.unwrap_or(defaults.block_len),
+            numwant: self.trackers.numwant.unwrap_or(defaults.numwant),
+            download_rate: rate(self.limits.download_rate),
+            upload_rate: rate(self.limits.upload_rate),
+            peer_download_rate: rate(self.limits.peer_download_rate),
+            peer_upload_rate: rate(self.limits.peer_upload_rate),
+            schedule: self.limits.schedule.clone(),
+            timeouts: self.network.timeouts(),
+            reconnect: ReconnectPolicy {
+                backoff: seconds(self.network.reconnect_backoff).unwrap_or(defaults.reconnect.backoff),
+                max_failures: self.network.max_peer_failures.unwrap_or(defaults.reconnect.max_failures),
+            },
+            // Loaded along with the command line's, see Options::load
+            ip_filter: defaults.ip_filter,
+        }
+    }
+}
+
+impl Network {
+    fn durations(&self) -> [(&'static str, Option<f64>); 6] {
+        [
+            ("network.connect_timeout", self.connect_timeout),
+            ("network.handshake_timeout", self.handshake_timeout),
+            ("network.request_timeout", self.request_timeout),
+            ("network.idle_timeout", self.idle_timeout),
+            ("network.keep_alive_interval", self.keep_alive_interval),
+            ("network.reconnect_backoff", self.reconnect_backoff),
+        ]
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        let defaults = Timeouts::default();
+        Timeouts {
+            connect: seconds(self.connect_timeout).unwrap_or(defaults.connect),
+            handshake: seconds(self.handshake_timeout).unwrap_or(defaults.handshake),
+            request: seconds(self.request_timeout).unwrap_or(defaults.request),
+            keep_alive: seconds(self.keep_alive_interval).unwrap_or(defaults.keep_alive),
+            idle: seconds(self.idle_timeout).unwrap_or(defaults.idle),
+        }
+    }
+}
+
+// Only ever called on validated values, anything else is left at the default
+fn seconds(value: Option<f64>) -> Option<Duration> {
+    value.and_then(|value| Duration::try_from_secs_f64(value).ok())
+}
+
+fn from_table(table: toml::Table) -> Result<ConfigFile> {
+    let config: ConfigFile = toml::Value::Table(table).try_into()?;
+    config.validate()?;
+    Ok(config)
+}
+
+// An environment variable's value as TOML, so `7000` is a number and `true`
+// a boolean. Anything that isn't valid TOML, or doesn't fit the key as a
+// number or boolean, is taken as a string so paths need no quotes.
+fn env_value(section: &str, key: &str, value: &str) -> Result<toml::Value> {
+    let check = |value: toml::Value| -> Result<toml::Value> {
+        let table = toml::Table::from_iter([(key.to_string(), value.clone())]);
+        from_table(toml::Table::from_iter([(section.to_string(), table.into())]))?;
+        Ok(value)
+    };
+    let parsed = toml::from_str::<toml::Table>(&format!("value = {}", value)).ok().and_then(|mut table| table.remove("value"));
+    match parsed {
+        Some(parsed) if !parsed.is_str() => check(parsed).or_else(|e| check(value.into()).map_err(|_| e)),
+        _ => check(value.into()),
+    }
+}
+
+// $XDG_CONFIG_HOME/bittorrent-starter-rust/config.toml, or under ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join(CONFIG_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bittorrent_starter_rust::ratelimit::TimeOfDay;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn write_config(contents: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let (_dir, path) = write_config(
+            "[network]\nlisten_port = 7000\nblock_size = 8192\nidle_timeout = 90.5\n\n[limits]\ndownload_rate = 1000\n\n[[limits.schedule]]\nfrom = \"23:00\"\nto = \"7:00\"\ndownload_rate = 0\n",
+        );
+        let env = vars(&[
+            ("BITTORRENT_NETWORK_LISTEN_PORT", "7001"),
+            ("BITTORRENT_STORAGE_OUTPUT_DIR", "/downloads/1234"),
+            ("BITTORRENT_LOGGING_LEVEL", "debug"),
+            ("BITTORRENT_LIMITS_UPLOAD_RATE", "0"),
+            ("BITTORRENT_NETWORK_REQUEST_TIMEOUT", "5"),
+            ("OTHER", "ignored"),
+        ]);
+        let config = ConfigFile::load_from(Some(&path), env).unwrap();
+        assert_eq!(config.network.listen_port, Some(7001));
+        assert_eq!(config.storage.output_dir, Some(PathBuf::from("/downloads/1234")));
+        assert_eq!(config.logging.level, Some(LogLevel::Debug));
+
+        let session = config.session_config();
+        assert_eq!((session.listen_port, session.block_len), (7001, 8192));
+        assert_eq!((session.download_rate, session.upload_rate), (Some(1000), None));
+        assert_eq!(session.max_active_downloads, SessionConfig::default().max_active_downloads);
+        assert_eq!(session.schedule.limits_at(TimeOfDay::new(3, 0), (Some(1000), None)), (None, None));
+        assert_eq!((session.timeouts.request, session.timeouts.idle), (Duration::from_secs(5), Duration::from_millis(90_500)));
+        assert_eq!(session.timeouts.connect, Timeouts::default().connect);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let error = |contents: &str| format!("{:#}", ConfigFile::load_from(Some(&write_config(contents).1), vec![]).unwrap_err());
+        assert!(error("[network]\nblock_size = 1000\n").contains("network.block_size must be a power of two"));
+        assert!(error("[trackers]\nnumwant = 0\n").contains("trackers.numwant must be at least 1"));
+        assert!(error("[dht]\nenabled = true\n").contains("dht.enabled"));
+        assert!(error("[network]\nrequest_timeout = -1\n").contains("network.request_timeout must be a positive number"));
+        assert!(error("[network]\nkeep_alive_interval = 30\nidle_timeout = 20\n").contains("network.keep_alive_interval must be shorter"));
+        assert!(error("[logging]\nfilter = \"wire=loud\"\n").contains("logging.filter"));
+        assert!(error("[[limits.schedule]]\nfrom = \"25:00\"\nto = \"07:00\"\n").contains("like 23:30, got \"25:00\""));
+        let unknown = error("[network]\nlisten_prot = 7000\n");
+        assert!(unknown.contains("listen_prot") && unknown.contains("line 2"), "{}", unknown);
+
+        let env_error = |name: &str, value: &str| format!("{:#}", ConfigFile::load_from(None, vars(&[(name, value)])).unwrap_err());
+        let invalid = env_error("BITTORRENT_NETWORK_LISTEN_PORT", "70000");
+        assert!(invalid.contains("BITTORRENT_NETWORK_LISTEN_PORT") && invalid.contains("70000"), "{}", invalid);
+        assert!(env_error("BITTORRENT_NETWORK_BLOCK_SIZE", "3").contains("network.block_size"));
+        assert!(env_error("BITTORRENT_NETWORK_LISTEN_PROT", "1").contains("listen_prot"));
+        assert!(env_error("BITTORRENT_PORT", "1").contains("BITTORRENT_<SECTION>_<KEY>"));
+    }
+}
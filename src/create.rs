@@ -0,0 +1,201 @@
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::types::{File, Files, Hashes, Info, Torrent};
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+// Aim for about this many pieces when no piece length is given
+const TARGET_PIECES: usize = 1500;
+
+// Builds a torrent from a file, or from every file below a directory
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    tiers: Vec<Vec<String>>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TorrentBuilder { path: path.into(), piece_length: None, tiers: Vec::new() }
+    }
+
+    // Must be a power of two of at least 16 KiB
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    // Adds a tier of trackers; the first tracker also becomes `announce`
+    pub fn tier(mut self, urls: Vec<String>) -> Self {
+        self.tiers.push(urls);
+        self
+    }
+
+    pub fn build(self) -> Result<Torrent> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Can't name a torrent after {}", self.path.display()))?
+            .to_string();
+
+        // Files in a stable order, relative to the root, as the info dict lists them
+        let mut paths = Vec::new();
+        if self.path.is_dir() {
+            collect_files(&self.path, &mut Vec::new(), &mut paths)?;
+            if paths.is_empty() {
+                return Err(anyhow!("No files in {}", self.path.display()));
+            }
+        }
+        let total: u64 = match paths.is_empty() {
+            true => fs::metadata(&self.path)?.len(),
+            false => paths.iter().map(|path| fs::metadata(join(&self.path, path)).map(|m| m.len())).sum::<std::io::Result<u64>>()?,
+        };
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() => {
+                return Err(anyhow!("Piece length must be a power of two of at least {}, got {}", MIN_PIECE_LENGTH, piece_length))
+            }
+            Some(piece_length) => piece_length,
+            None => default_piece_length(total),
+        };
+
+        let mut hasher = PieceHasher::new(piece_length);
+        let files = if paths.is_empty() {
+            hasher.update_from(fs::File::open(&self.path)?)?;
+            Files::Single { length: total as usize }
+        } else {
+            let mut files = Vec::new();
+            for path in paths {
+                let file = fs::File::open(join(&self.path, &path))?;
+                let length = hasher.update_from(file)?;
+                files.push(File { length: length as usize, path });
+            }
+            Files::Multiple { files }
+        };
+
+        let info = Info { name, piece_length, pieces: Hashes(hasher.finish()), files };
+        let announce = self.tiers.iter().flatten().next().cloned().unwrap_or_default();
+        let announce_list = (self.tiers.len() > 1 || self.tiers.iter().any(|tier| tier.len() > 1)).then_some(self.tiers);
+        Ok(Torrent { announce, announce_list, info })
+    }
+}
+
+fn collect_files(root: &Path, prefix: &mut Vec<String>, paths: &mut Vec<Vec<String>>) -> Result<()> {
+    let dir = join(root, prefix);
+    let mut entries: Vec<_> = fs::read_dir(&dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().into_string().map_err(|name| anyhow!("File name is not UTF-8: {:?}", name))?;
+        prefix.push(name);
+        if entry.file_type()?.is_dir() {
+            collect_files(root, prefix, paths)?;
+        } else {
+            paths.push(prefix.clone());
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+fn join(root: &Path, parts: &[String]) -> PathBuf {
+    root.join(parts.iter().collect::<PathBuf>())
+}
+
+pub fn default_piece_length(total: u64) -> usize {
+    let piece_length = (total / TARGET_PIECES as u64).next_power_of_two() as usize;
+    piece_length.clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+// Hashes a byte stream that spans files piece by piece
+struct PieceHasher {
+    piece_length: usize,
+    buf: Vec<u8>,
+    hashes: Vec<[u8; 20]>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: usize) -> Self {
+        PieceHasher { piece_length, buf: Vec::with_capacity(piece_length), hashes: Vec::new() }
+    }
+
+    fn update_from(&mut self, mut reader: impl Read) -> Result<u64> {
+        let mut total = 0;
+        loop {
+            let start = self.buf.len();
+            self.buf.resize(self.piece_length, 0);
+            let n = reader.read(&mut self.buf[start..])?;
+            self.buf.truncate(start + n);
+            if n == 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+            if self.buf.len() == self.piece_length {
+                self.hashes.push(Sha1::digest(&self.buf).into());
+                self.buf.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<[u8; 20]> {
+        if !self.buf.is_empty() {
+            self.hashes.push(Sha1::digest(&self.buf).into());
+        }
+        self.hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_piece_length() {
+        assert_eq!(default_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(default_piece_length(100 * 1024 * 1024), 128 * 1024);
+        assert_eq!(default_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_multi_file_pieces_span_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let a = vec![1u8; 20_000];
+        let b = vec![2u8; 30_000];
+        fs::write(root.join("sub/b"), &b).unwrap();
+        fs::write(root.join("a"), &a).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .tier(vec!["http://a/announce".to_string()])
+            .tier(vec!["http://b/announce".to_string()])
+            .build()
+            .unwrap();
+        assert_eq!(torrent.info.name, "content");
+        assert_eq!(torrent.announce, "http://a/announce");
+        assert_eq!(torrent.announce_tiers().len(), 2);
+        let Files::Multiple { files } = &torrent.info.files else { panic!("expected multiple files") };
+        assert_eq!(files[0].path, ["a"]);
+        assert_eq!(files[1].path, ["sub", "b"]);
+
+        let data = [a, b].concat();
+        let expected: Vec<[u8; 20]> = data.chunks(MIN_PIECE_LENGTH).map(|piece| Sha1::digest(piece).into()).collect();
+        assert_eq!(torrent.info.pieces.0, expected);
+    }
+
+    #[test]
+    fn test_rejects_bad_piece_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"hello").unwrap();
+        assert!(TorrentBuilder::new(&path).piece_length(20_000).build().is_err());
+        assert!(TorrentBuilder::new(&path).piece_length(8192).build().is_err());
+        assert_eq!(TorrentBuilder::new(&path).piece_length(32_768).build().unwrap().info.pieces.0.len(), 1);
+    }
+}
@@ -0,0 +1,492 @@
+use anyhow::{anyhow, Result};
+use std::{
+    cmp,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    metrics::TorrentMetrics,
+    peer::{has_piece, PeerConnection, Timeouts},
+    peer_id::generate_peer_id,
+    protocol::{HashMismatch, CHUNK_LEN},
+    ratelimit::{PeerLimits, RateLimits},
+    smartban::{BanList, SmartBan},
+    types::Info,
+};
+
+// How many peers we download from at the same time
+pub const MAX_PEERS: usize = 5;
+const IDLE_POLL: Duration = Duration::from_millis(50);
+// Longest we wait before trying a failed peer again
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    InProgress,
+    Done,
+}
+
+// Hands out pieces rarest first among the peers we're connected to, so the
+// pieces only one peer has get fetched before that peer goes away.
+#[derive(Debug)]
+pub struct PiecePicker {
+    states: Vec<PieceState>,
+    availability: Vec<u32>,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> Self {
+        PiecePicker { states: vec![PieceState::Missing; num_pieces], availability: vec![0; num_pieces] }
+    }
+
+    pub fn add_peer(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if has_piece(bitfield, index) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if has_piece(bitfield, index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn pick(&mut self, bitfield: &[u8]) -> Option<u32> {
+        let index = (0..self.states.len())
+            .filter(|&index| self.states[index] == PieceState::Missing && has_piece(bitfield, index))
+            .min_by_key(|&index| self.availability[index])?;
+        self.states[index] = PieceState::InProgress;
+        Some(index as u32)
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        self.states[index as usize] = PieceState::Done;
+    }
+
+    pub fn release(&mut self, index: u32) {
+        if self.states[index as usize] == PieceState::InProgress {
+            self.states[index as usize] = PieceState::Missing;
+        }
+    }
+
+    // Whether the peer has anything we still need, even if it's in flight
+    // from another peer right now
+    pub fn wants(&self, bitfield: &[u8]) -> bool {
+        (0..self.states.len()).any(|index| self.states[index] != PieceState::Done && has_piece(bitfield, index))
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.states.iter().filter(|state| **state != PieceState::Done).count()
+    }
+}
+
+// Caps the number of peer connections open at once across everything that
+// shares it. A permit is held for as long as the connection is open.
+#[derive(Debug)]
+pub struct ConnectionBudget {
+    max: usize,
+    used: Mutex<usize>,
+}
+
+pub struct ConnectionPermit {
+    budget: Arc<ConnectionBudget>,
+}
+
+impl ConnectionBudget {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(ConnectionBudget { max, used: Mutex::new(0) })
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        if *used >= self.max {
+            return None;
+        }
+        *used += 1;
+        Some(ConnectionPermit { budget: self.clone() })
+    }
+
+    pub fn used(&self) -> usize {
+        *self.used.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+    }
+}
+
+// How peers that fail are tried again: after `backoff`, doubling with every
+// failure in a row, until they've failed `max_failures` times in a row.
+// A peer that sends us a verified piece starts over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub backoff: Duration,
+    pub max_failures: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy { backoff: Duration::from_secs(2), max_failures: 5 }
+    }
+}
+
+impl ReconnectPolicy {
+    // How long to wait after the peer's `failures`th failure in a row, or
+    // None to give up on it
+    pub fn delay(&self, failures: u32) -> Option<Duration> {
+        if failures >= self.max_failures {
+            return None;
+        }
+        let doublings = failures.saturating_sub(1).min(16);
+        Some(self.backoff.saturating_mul(1 << doublings).min(MAX_BACKOFF))
+    }
+}
+
+// Who to connect to next: peers we haven't tried in the order we got them,
+// then failed ones once their backoff is over
+#[derive(Debug)]
+struct PeerQueue {
+    policy: ReconnectPolicy,
+    bans: Arc<BanList>,
+    fresh: VecDeque<SocketAddr>,
+    retries: Vec<(Instant, SocketAddr)>,
+    failures: HashMap<SocketAddr, u32>,
+}
+
+impl PeerQueue {
+    fn new(peers: &[SocketAddr], policy: ReconnectPolicy, bans: Arc<BanList>) -> Self {
+        PeerQueue { policy, bans, fresh: peers.iter().copied().collect(), retries: Vec::new(), failures: HashMap::new() }
+    }
+
+    fn next(&mut self) -> Option<SocketAddr> {
+        // Peers may have been banned since they were queued
+        let bans = &self.bans;
+        self.fresh.retain(|peer| !bans.contains(peer.ip()));
+        self.retries.retain(|(_, peer)| !bans.contains(peer.ip()));
+        if let Some(peer) = self.fresh.pop_front() {
+            return Some(peer);
+        }
+        let now = Instant::now();
+        let (at, _) = self.retries.iter().enumerate().filter(|(_, (due, _))| *due <= now).min_by_key(|(_, (due, _))| *due)?;
+        Some(self.retries.remove(at).1)
+    }
+
+    // Nobody left to try, now or later
+    fn is_empty(&self) -> bool {
+        self.fresh.iter().chain(self.retries.iter().map(|(_, peer)| peer)).all(|peer| self.bans.contains(peer.ip()))
+    }
+
+    fn succeeded(&mut self, peer: SocketAddr) {
+        self.failures.remove(&peer);
+    }
+
+    fn failed(&mut self, peer: SocketAddr) {
+        let failures = self.failures.entry(peer).or_default();
+        *failures += 1;
+        match self.policy.delay(*failures) {
+            Some(delay) => {
+                debug!("Trying {} again in {:?}", peer, delay);
+                self.retries.push((Instant::now() + delay, peer));
+            }
+            None => info!("Giving up on {} after {} failed attempts", peer, failures),
+        }
+    }
+}
+
+pub fn piece_length(info: &Info, piece_index: u32) -> u32 {
+    let total = info.files.length() as u64;
+    let start = piece_index as u64 * info.piece_length as u64;
+    cmp::min(info.piece_length as u64, total.saturating_sub(start)) as u32
+}
+
+// What the peer workers report back while a download runs
+#[derive(Debug)]
+pub enum DownloadEvent {
+    // With the peer id from its handshake
+    PeerConnected(SocketAddr, [u8; 20]),
+    // The error, if the peer was dropped for misbehaving or going away
+    PeerDisconnected(SocketAddr, Option<String>),
+    // A verified piece and the peer it came from
+    Piece(SocketAddr, u32, Vec<u8>),
+    // Found to have sent corrupt data, now on the ban list
+    PeerBanned(IpAddr),
+}
+
+pub struct DownloadOptions {
+    // How many peers we download from at the same time
+    pub max_peers: usize,
+    // Ours, sent in every handshake
+    pub peer_id: [u8; 20],
+    // Size of the blocks pieces are requested in
+    pub block_len: u32,
+    pub timeouts: Timeouts,
+    pub reconnect: ReconnectPolicy,
+    pub budget: Arc<ConnectionBudget>,
+    // Limits every connection counts against, like the session's and the
+    // torrent's, and the ones each gets on its own
+    pub limits: Vec<Arc<RateLimits>>,
+    pub peer_limits: Arc<PeerLimits>,
+    // Peers we don't download from, which peers found sending corrupt data
+    // are added to
+    pub bans: Arc<BanList>,
+    // Set to stop the download early; download_torrent then returns Ok
+    pub cancel: Arc<AtomicBool>,
+    // Pieces we already have, by index. Empty if we have none.
+    pub have: Vec<bool>,
+    pub metrics: Arc<TorrentMetrics>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            max_peers: MAX_PEERS,
+            peer_id: generate_peer_id(),
+            block_len: CHUNK_LEN,
+            timeouts: Timeouts::default(),
+            reconnect: ReconnectPolicy::default(),
+            budget: ConnectionBudget::new(usize::MAX),
+            limits: Vec::new(),
+            peer_limits: Arc::new(PeerLimits::unlimited()),
+            bans: Arc::new(BanList::default()),
+            cancel: Arc::new(AtomicBool::new(false)),
+            have: Vec::new(),
+            metrics: Arc::new(TorrentMetrics::default()),
+        }
+    }
+}
+
+// Downloads every missing piece of the torrent from up to `max_peers` of
+// `peers` at a time, handing verified pieces to `on_event` in completion
+// order. Peers that fail are replaced by the next ones in the list, and
+// tried again later as `options.reconnect` says.
+pub fn download_torrent<F>(
+    info: &Info,
+    info_hash: &[u8; 20],
+    peers: &[SocketAddr],
+    options: &DownloadOptions,
+    mut on_event: F,
+) -> Result<()>
+where
+    F: FnMut(DownloadEvent) -> Result<()>,
+{
+    let mut picker = PiecePicker::new(info.pieces.0.len());
+    for (index, _) in options.have.iter().enumerate().filter(|(_, have)| **have) {
+        picker.complete(index as u32);
+    }
+    let picker = Arc::new(Mutex::new(picker));
+    let info = Arc::new(info.clone());
+    let (tx, rx) = mpsc::channel();
+    let mut queue = PeerQueue::new(peers, options.reconnect, options.bans.clone());
+    let mut smart_ban = SmartBan::new(options.block_len);
+    let mut active = 0;
+    let mut last_err = None;
+
+    loop {
+        let remaining = picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?.remaining();
+        if remaining == 0 || options.cancel.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        while active < options.max_peers {
+            // Out of connections for now, try again once one closes
+            let Some(permit) = options.budget.try_acquire() else { break };
+            let Some(peer) = queue.next() else { break };
+            let (picker, info, tx) = (picker.clone(), info.clone(), tx.clone());
+            let limits = options.limits.iter().cloned().chain([options.peer_limits.for_peer()]).collect();
+            let (cancel, metrics, bans) = (options.cancel.clone(), options.metrics.clone(), options.bans.clone());
+            let (info_hash, peer_id, block_len, timeouts) = (*info_hash, options.peer_id, options.block_len, options.timeouts);
+            let span = info_span!("peer", addr = %peer);
+            thread::spawn(move || {
+                let _span = span.enter();
+                let result = peer_worker(peer, &info, &info_hash, &peer_id, block_len, limits, timeouts, &picker, &bans, &cancel, &metrics, &tx);
+                drop(permit);
+                let _ = tx.send(WorkerEvent::Done(peer, result));
+            });
+            active += 1;
+        }
+        if active == 0 && queue.is_empty() {
+            let err = last_err.unwrap_or_else(|| anyhow!("No peers to download from"));
+            return Err(err.context(format!("Download failed with {} pieces missing", remaining)));
+        }
+
+        let event = match rx.recv_timeout(IDLE_POLL) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match event {
+            WorkerEvent::Connected(peer, peer_id) => on_event(DownloadEvent::PeerConnected(peer, peer_id))?,
+            WorkerEvent::Piece(peer, index, data) => {
+                for culprit in smart_ban.verified(index, &data) {
+                    if options.bans.ban(culprit) {
+                        warn!("Banning {} for sending corrupt data for piece {}", culprit, index);
+                        on_event(DownloadEvent::PeerBanned(culprit))?;
+                    }
+                }
+                on_event(DownloadEvent::Piece(peer, index, data))?;
+                queue.succeeded(peer);
+                picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?.complete(index);
+            }
+            WorkerEvent::HashFailed(peer, index, data) => smart_ban.failed(index, peer.ip(), &data),
+            WorkerEvent::Done(peer, result) => {
+                active -= 1;
+                let error = result.as_ref().err().map(|e| format!("{:#}", e));
+                if let Some(error) = &error {
+                    warn!("Peer {} failed: {}", peer, error);
+                }
+                on_event(DownloadEvent::PeerDisconnected(peer, error))?;
+                if let Err(e) = result {
+                    queue.failed(peer);
+                    last_err = Some(e);
+                }
+            }
+        }
+    }
+}
+
+enum WorkerEvent {
+    Connected(SocketAddr, [u8; 20]),
+    Piece(SocketAddr, u32, Vec<u8>),
+    HashFailed(SocketAddr, u32, Vec<u8>),
+    Done(SocketAddr, Result<()>),
+}
+
+#[allow(clippy::too_many_arguments)]
+fn peer_worker(
+    peer: SocketAddr,
+    info: &Info,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    block_len: u32,
+    limits: Vec<Arc<RateLimits>>,
+    timeouts: Timeouts,
+    picker: &Mutex<PiecePicker>,
+    bans: &BanList,
+    cancel: &AtomicBool,
+    metrics: &TorrentMetrics,
+    tx: &mpsc::Sender<WorkerEvent>,
+) -> Result<()> {
+    metrics.peers_half_open.inc();
+    let connection = PeerConnection::connect(peer, info_hash, peer_id, info.pieces.0.len(), limits, timeouts);
+    metrics.peers_half_open.dec();
+    let mut connection = connection?;
+    connection.block_len = block_len;
+    debug!("Bitfield: {}", hex::encode(&connection.bitfield));
+    tx.send(WorkerEvent::Connected(peer, connection.handshake.peer_id))?;
+    let bitfield = connection.bitfield.clone();
+    picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?.add_peer(&bitfield);
+
+    let result = (|| {
+        connection.unchoke()?;
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            if bans.contains(peer.ip()) {
+                return Err(anyhow!("Banned for sending corrupt data"));
+            }
+            let index = {
+                let mut picker = picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?;
+                match picker.pick(&bitfield) {
+                    Some(index) => index,
+                    None if picker.wants(&bitfield) => {
+                        // Stay around in case another peer fails its piece
+                        drop(picker);
+                        connection.keep_alive()?;
+                        thread::sleep(IDLE_POLL);
+                        continue;
+                    }
+                    None => return Ok(()),
+                }
+            };
+            let expected_piece_hash = &info.pieces.0[index as usize];
+            let length = piece_length(info, index);
+            match connection.download_piece(index, length, expected_piece_hash) {
+                Ok(data) => {
+                    metrics.downloaded.add(length as u64);
+                    metrics.pieces_verified.inc();
+                    tx.send(WorkerEvent::Piece(peer, index, data))?
+                }
+                Err(e) => {
+                    metrics.pieces_failed.inc();
+                    if let Some(mismatch) = e.downcast_ref::<HashMismatch>() {
+                        // The whole piece arrived, it was just wrong
+                        metrics.downloaded.add(length as u64);
+                        metrics.hash_failures.inc();
+                        tx.send(WorkerEvent::HashFailed(peer, index, mismatch.data.clone()))?;
+                    }
+                    picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?.release(index);
+                    return Err(e);
+                }
+            }
+        }
+    })();
+
+    picker.lock().map_err(|_| anyhow!("Piece picker poisoned"))?.remove_peer(&bitfield);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Files, Hashes};
+
+    #[test]
+    fn test_picker_prefers_rarest_piece() {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&[0b1111_0000]);
+        picker.add_peer(&[0b1101_0000]);
+        picker.add_peer(&[0b1001_0000]);
+        // Piece 2 is only held by the first peer, piece 1 by two of them
+        assert_eq!(picker.pick(&[0b1111_0000]), Some(2));
+        assert_eq!(picker.pick(&[0b1111_0000]), Some(1));
+        assert_eq!(picker.pick(&[0b1001_0000]), Some(0));
+        assert_eq!(picker.pick(&[0b1001_0000]), Some(3));
+        assert_eq!(picker.pick(&[0b1111_0000]), None);
+    }
+
+    #[test]
+    fn test_picker_releases_failed_piece() {
+        let mut picker = PiecePicker::new(2);
+        picker.add_peer(&[0b1100_0000]);
+        let index = picker.pick(&[0b1100_0000]).unwrap();
+        picker.release(index);
+        assert_eq!(picker.remaining(), 2);
+        while let Some(index) = picker.pick(&[0b1100_0000]) {
+            picker.complete(index);
+        }
+        assert_eq!(picker.remaining(), 0);
+        assert!(!picker.wants(&[0b1100_0000]));
+    }
+
+    #[test]
+    fn test_piece_length() {
+        let info = Info { name: "test".to_string(), piece_length: 4, pieces: Hashes(vec![[0; 20]; 4]), files: Files::Single { length: 10 } };
+        assert_eq!([0, 2, 3].map(|index| piece_length(&info, index)), [4, 2, 0]);
+    }
+
+    #[test]
+    fn test_budget_permits() {
+        let budget = ConnectionBudget::new(2);
+        let first = budget.try_acquire().unwrap();
+        let _second = budget.try_acquire().unwrap();
+        assert!(budget.try_acquire().is_none());
+        drop(first);
+        assert_eq!(budget.used(), 1);
+        assert!(budget.try_acquire().is_some());
+    }
+}
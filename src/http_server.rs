@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+const MAX_HEADER_LINES: usize = 100;
+// Lines of the request head, and all of its header lines together
+const MAX_LINE_LEN: usize = 16 * 1024;
+const MAX_HEADERS_LEN: usize = 64 * 1024;
+const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// How often serve_until checks whether it should stop
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+// Just enough HTTP/1.1 to serve trackers and local control endpoints: one
+// request per connection, answered and closed.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, Vec<u8>)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub remote_addr: SocketAddr,
+}
+
+impl Request {
+    pub fn query_value(&self, key: &str) -> Option<&[u8]> {
+        self.query.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn query_values(&self, key: &str) -> Vec<&[u8]> {
+        self.query.iter().filter(|(k, _)| k == key).map(|(_, v)| v.as_slice()).collect()
+    }
+
+    pub fn query_str(&self, key: &str) -> Option<&str> {
+        self.query_value(key).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response { status, headers: vec![("Content-Type".to_string(), content_type.to_string())], body }
+    }
+
+    pub fn not_found() -> Self {
+        Response::new(404, "text/plain", b"Not Found".to_vec())
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+// Accepts connections forever, handling each on its own thread
+pub fn serve(listener: TcpListener, handler: Handler) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, handler) {
+                debug!("HTTP connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Like serve, but returns once `stop` is set and every request that was in
+// flight has been answered, so a handler can stop the server and still get
+// its own response out
+pub fn serve_until(listener: TcpListener, handler: Handler, stop: Arc<AtomicBool>) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut in_flight: Vec<thread::JoinHandle<()>> = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(STOP_POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        stream.set_nonblocking(false)?;
+        let handler = handler.clone();
+        in_flight.retain(|handle| !handle.is_finished());
+        in_flight.push(thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, handler) {
+                debug!("HTTP connection error: {}", e);
+            }
+        }));
+    }
+    for handle in in_flight {
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, handler: Handler) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let remote_addr = stream.peer_addr()?;
+    let request = read_request(&mut BufReader::new(&mut stream), remote_addr);
+    let response = match request {
+        Ok(request) => handler(&request),
+        Err(e) if e.is::<HeadersTooLarge>() => Response::new(431, "text/plain", e.to_string().into_bytes()),
+        Err(e) => Response::new(400, "text/plain", e.to_string().into_bytes()),
+    };
+    write_response(&mut stream, &response)
+}
+
+#[derive(Debug, Error)]
+#[error("Request header fields too large")]
+struct HeadersTooLarge;
+
+// A line of the request head, None if it's longer than `limit`. Reading is
+// capped so a client can't stream a line that never ends.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(limit as u64 + 1).read_until(b'\n', &mut line)?;
+    if line.len() > limit {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+fn read_request<R: BufRead>(reader: &mut R, remote_addr: SocketAddr) -> Result<Request> {
+    let line = read_line(reader, MAX_LINE_LEN)?.ok_or_else(|| anyhow!("Request line longer than {} bytes", MAX_LINE_LEN))?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or_else(|| anyhow!("Missing request method"))?.to_string();
+    let target = parts.next().ok_or_else(|| anyhow!("Missing request target"))?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (target, Vec::new()),
+    };
+    let path = String::from_utf8_lossy(&percent_decode(path)).into_owned();
+
+    let mut headers = Vec::new();
+    let mut headers_left = MAX_HEADERS_LEN;
+    for _ in 0..MAX_HEADER_LINES {
+        let line = read_line(reader, MAX_LINE_LEN.min(headers_left))?.ok_or(HeadersTooLarge)?;
+        headers_left -= line.len();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| anyhow!("Malformed header: {}", line))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    if content_length > MAX_BODY_LEN {
+        return Err(anyhow!("Request body too large: {} bytes", content_length));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request { method, path, query, headers, body, remote_addr })
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(format!("{}: {}\r\n", name, value).as_str());
+    }
+    head.push_str(format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()).as_str());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+pub fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (String::from_utf8_lossy(&percent_decode(key)).into_owned(), percent_decode(value))
+        })
+        .collect()
+}
+
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = (char::from(bytes[i + 1]).to_digit(16), char::from(bytes[i + 2]).to_digit(16));
+            if let (Some(high), Some(low)) = hex {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("info_hash=%ab%CDx&port=6881&flag&info_hash=%zz");
+        assert_eq!(query[0], ("info_hash".to_string(), vec![0xab, 0xcd, b'x']));
+        assert_eq!(query[1], ("port".to_string(), b"6881".to_vec()));
+        assert_eq!(query[2], ("flag".to_string(), Vec::new()));
+        assert_eq!(query[3], ("info_hash".to_string(), b"%zz".to_vec()));
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw = b"POST /rpc?a=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_request(&mut &raw[..], "127.0.0.1:1".parse().unwrap()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/rpc");
+        assert_eq!(request.query_str("a"), Some("1"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn test_request_head_is_limited() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        // A request line that never ends is cut off rather than read whole
+        let mut endless = std::io::BufReader::new(std::io::repeat(b'a'));
+        let error = read_request(&mut endless, addr).unwrap_err();
+        assert!(!error.is::<HeadersTooLarge>() && error.to_string().contains("longer than"), "{}", error);
+
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert!(read_request(&mut long_header.as_bytes(), addr).unwrap_err().is::<HeadersTooLarge>());
+        // Lines that are each fine but too much together
+        let header = format!("X-Header: {}\r\n", "a".repeat(1000));
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(MAX_HEADERS_LEN / 1000));
+        assert!(read_request(&mut many.as_bytes(), addr).unwrap_err().is::<HeadersTooLarge>());
+        let fine = format!("GET / HTTP/1.1\r\n{}\r\n", header.repeat(10));
+        assert_eq!(read_request(&mut fine.as_bytes(), addr).unwrap().headers.len(), 10);
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+use tracing::{debug, warn};
+
+// eMule access levels from this one up let the range through
+const EMULE_ALLOWED_LEVEL: u32 = 128;
+
+// Address ranges we don't talk to, from a blocklist. Lines may be in any of
+// the common formats, mixed in one file:
+//
+//   1.2.3.0/24                                  CIDR, or a single address
+//   001.002.003.000 - 001.002.003.255 , 000 , X eMule's ipfilter.dat
+//   Some Network:1.2.3.0-1.2.3.255              PeerGuardian's P2P text
+//
+// IPv4 addresses are kept as their IPv4-mapped IPv6 form, so both families
+// share one sorted list of non-overlapping ranges that lookups binary search.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    ranges: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    // Lines that aren't ranges are skipped with a warning, blocklists found
+    // in the wild are rarely spotless
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read IP filter {}", path.display()))?;
+        let (filter, skipped) = IpFilter::parse(&text);
+        if skipped > 0 {
+            warn!("Skipped {} lines of {} that aren't address ranges", skipped, path.display());
+        }
+        debug!("IP filter {} blocks {} ranges", path.display(), filter.len());
+        Ok(filter)
+    }
+
+    // The filter and how many lines weren't ranges
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut ranges = Vec::new();
+        let mut skipped = 0;
+        for line in text.lines() {
+            let line = line.trim_start_matches('\u{feff}').trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => ranges.push(range),
+                // Allowed by its eMule access level
+                Some(None) => {}
+                None => skipped += 1,
+            }
+        }
+        (IpFilter::from_ranges(ranges), skipped)
+    }
+
+    // Ranges are inclusive, and ones whose ends are of different families or
+    // the wrong way round are left out
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (IpAddr, IpAddr)>) -> Self {
+        let mut ranges: Vec<(u128, u128)> = ranges
+            .into_iter()
+            .map(|(start, end)| (key(start), key(end)))
+            .filter(|(start, end)| start <= end && is_mapped(*start) == is_mapped(*end))
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                // Overlapping or adjacent
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        IpFilter { ranges: merged }
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let ip = key(ip);
+        let after = self.ranges.partition_point(|(start, _)| *start <= ip);
+        after > 0 && self.ranges[after - 1].1 >= ip
+    }
+
+    // Ranges once overlapping ones are merged
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn is_mapped(key: u128) -> bool {
+    Ipv6Addr::from(key).to_ipv4_mapped().is_some()
+}
+
+// The range a line blocks, Some(None) for an eMule range that's allowed, or
+// None if it isn't a range at all
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some(range) = parse_range(line) {
+        return Some(Some(range));
+    }
+    // The description comes after the range in eMule's format and before it
+    // in P2P's, and either may have commas or colons of its own
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Some(range) = parse_range(range) {
+            let level = rest.split(',').next()?.trim().parse::<u32>().ok()?;
+            return Some(Some(range).filter(|_| level < EMULE_ALLOWED_LEVEL));
+        }
+    }
+    let (_, range) = line.rsplit_once(':')?;
+    parse_range(range).map(Some)
+}
+
+// "start - end", "address/prefix" or a single address
+fn parse_range(text: &str) -> Option<(IpAddr, IpAddr)> {
+    let text = text.trim();
+    if let Some((start, end)) = text.split_once('-') {
+        return Some((parse_ip(start)?, parse_ip(end)?));
+    }
+    if let Some((ip, prefix)) = text.split_once('/') {
+        let ip = parse_ip(ip)?;
+        let prefix: u32 = prefix.trim().parse().ok()?;
+        return match ip {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                Some((Ipv4Addr::from(start).into(), Ipv4Addr::from(start | !mask).into()))
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                Some((Ipv6Addr::from(start).into(), Ipv6Addr::from(start | !mask).into()))
+            }
+            _ => None,
+        };
+    }
+    parse_ip(text).map(|ip| (ip, ip))
+}
+
+// Also takes the zero padded IPv4 addresses of ipfilter.dat, like 001.002.003.004
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    let text = text.trim();
+    if let Ok(ip) = text.parse() {
+        return Some(ip);
+    }
+    let octets: Vec<u8> = text.split('.').map(|octet| octet.parse().ok()).collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(Ipv4Addr::from(octets).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let text = "\
+# comment
+1.2.3.0/24
+9.9.9.9
+001.010.000.000 - 001.010.255.255 , 000 , Some network, Inc
+002.000.000.000 - 002.255.255.255 , 200 , Allowed by its level
+Some Org, Inc: ltd:5.6.7.8-5.6.7.20
+2001:db8::/32
+not a range
+";
+        let (filter, skipped) = IpFilter::parse(text);
+        assert_eq!(skipped, 1);
+        for blocked in ["1.2.3.0", "1.2.3.255", "9.9.9.9", "1.10.4.5", "5.6.7.8", "5.6.7.20", "2001:db8::1", "::ffff:1.2.3.4"] {
+            assert!(filter.is_blocked(ip(blocked)), "{}", blocked);
+        }
+        for allowed in ["1.2.4.0", "9.9.9.8", "2.1.1.1", "5.6.7.21", "2001:db9::", "::1", "0.0.0.0"] {
+            assert!(!filter.is_blocked(ip(allowed)), "{}", allowed);
+        }
+    }
+
+    #[test]
+    fn test_ranges_are_merged() {
+        let range = |start: &str, end: &str| (ip(start), ip(end));
+        let filter = IpFilter::from_ranges([
+            range("10.0.0.0", "10.0.0.9"),
+            range("10.0.0.5", "10.0.0.20"),
+            range("10.0.0.21", "10.0.0.30"),
+            range("10.0.1.0", "10.0.1.0"),
+            range("255.255.255.0", "255.255.255.255"),
+            range("::", "::5"),
+            // Backwards and mixed, both dropped
+            range("10.9.9.9", "10.9.9.1"),
+            range("10.5.0.0", "ffff::"),
+        ]);
+        assert_eq!(filter.len(), 4);
+        assert!(filter.is_blocked(ip("10.0.0.25")));
+        assert!(!filter.is_blocked(ip("10.0.0.31")));
+        assert!(filter.is_blocked(ip("10.0.1.0")));
+        assert!(filter.is_blocked(ip("255.255.255.255")));
+        assert!(!filter.is_blocked(ip("::ffff:0:0")) && filter.is_blocked(ip("::3")));
+        assert!(!filter.is_blocked(ip("10.6.0.0")));
+    }
+
+    #[test]
+    fn test_cidr_edges() {
+        let (filter, skipped) = IpFilter::parse("0.0.0.0/0\n::/0\n1.2.3.4/33\n");
+        assert_eq!(skipped, 1);
+        assert!(filter.is_blocked(ip("255.255.255.255")) && filter.is_blocked(ip("ffff::1")));
+        assert!(IpFilter::default().is_empty() && !IpFilter::default().is_blocked(ip("1.2.3.4")));
+    }
+}
@@ -0,0 +1,29 @@
+// A small BitTorrent client and tracker. The `bittorrent-starter-rust`
+// binary is a command line front end to this library.
+
+pub mod create;
+pub mod decoder;
+pub mod download;
+pub mod http_server;
+pub mod ipfilter;
+pub mod log;
+pub mod metrics;
+pub mod peer;
+pub mod peer_id;
+pub mod protocol;
+pub mod ratelimit;
+pub mod ratemeter;
+pub mod rpc;
+mod random;
+pub mod session;
+pub mod smartban;
+pub mod storage;
+pub mod tracker;
+pub mod types;
+pub mod wire;
+
+pub use crate::{
+    create::TorrentBuilder,
+    session::{Event, PeerInfo, Progress, Session, SessionConfig, TorrentHandle, TorrentState},
+    types::Torrent,
+};
@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    env, fmt,
+    io::{self, Write},
+    sync::Mutex,
+};
+use tracing_subscriber::{filter::LevelFilter, fmt::MakeWriter, EnvFilter};
+
+// Target of the events logging every peer message, so the wire trace can be
+// switched on without turning everything else up
+pub const WIRE_TARGET: &str = "wire";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+// Where log lines go instead of stderr while something else owns the
+// terminal, like the TUI
+pub type Sink = Box<dyn Fn(String) + Send>;
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+// A block of lines kept at the bottom of the terminal, like a progress
+// display, and how many lines of it are on screen
+static STATUS: Mutex<(String, usize)> = Mutex::new((String::new(), 0));
+
+// Sends `tracing` events to stderr at `level` and up. `filter` holds extra
+// per-module directives like `bittorrent_starter_rust::peer=debug`, and falls
+// back to RUST_LOG. With `wire_trace` every peer message sent and received is
+// logged too.
+pub fn init(level: LogLevel, filter: Option<&str>, wire_trace: bool) -> Result<()> {
+    let mut directives = vec![LevelFilter::from(level).to_string()];
+    directives.extend(filter.map(str::to_string).or_else(|| env::var("RUST_LOG").ok()).filter(|filter| !filter.is_empty()));
+    if wire_trace {
+        directives.push(format!("{}=trace", WIRE_TARGET));
+    }
+    let filter = EnvFilter::builder().parse(directives.join(",")).map_err(|e| anyhow!("Invalid log filter: {}", e))?;
+    tracing_subscriber::fmt()
+        .compact()
+        .without_time()
+        .with_ansi(false)
+        .with_env_filter(filter)
+        .with_writer(Stderr)
+        .try_init()
+        .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
+
+// Sends log lines to `sink` until it's taken away again with None
+pub fn set_sink(sink: Option<Sink>) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = sink;
+}
+
+// Writes a line to stderr above the status block, if there is one
+fn print(args: fmt::Arguments) {
+    if let Some(sink) = SINK.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        sink(args.to_string());
+        return;
+    }
+    let status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+    // Nothing to draw around
+    if status.1 == 0 {
+        eprintln!("{}", args);
+        return;
+    }
+    let mut stderr = io::stderr().lock();
+    let _ = clear(&mut stderr, status.1);
+    let _ = writeln!(stderr, "{}", args);
+    let _ = write!(stderr, "{}", status.0);
+    let _ = stderr.flush();
+}
+
+// Replaces the status block with `text`, which should end in a newline.
+// Redrawing in place needs a terminal, so only call this when stderr is one.
+pub fn set_status(text: &str) {
+    let mut status = STATUS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stderr = io::stderr().lock();
+    let _ = clear(&mut stderr, status.1);
+    let _ = write!(stderr, "{}", text);
+    let _ = stderr.flush();
+    *status = (text.to_string(), text.lines().count());
+}
+
+pub fn clear_status() {
+    set_status("");
+}
+
+// Moves up to the start of the block and erases everything below
+fn clear(stderr: &mut impl Write, lines: usize) -> io::Result<()> {
+    if lines > 0 {
+        write!(stderr, "\x1b[{}F\x1b[J", lines)?;
+    }
+    Ok(())
+}
+
+// Hands each formatted event to `print`, so it goes to the sink or around
+// the status block like everything else
+struct Stderr;
+
+impl<'a> MakeWriter<'a> for Stderr {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Line {
+        Line(Vec::new())
+    }
+}
+
+// One event, printed once it's all been written
+struct Line(Vec<u8>);
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        if !self.0.is_empty() {
+            print(format_args!("{}", String::from_utf8_lossy(&self.0).trim_end_matches('\n')));
+        }
+    }
+}
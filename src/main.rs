@@ -1,65 +1,56 @@
-use anyhow::{anyhow, Result};
-use std::env;
+use anyhow::Result;
+use clap::Parser;
+use std::time::Duration;
 
+use bittorrent_starter_rust::{log, wire};
+
+mod cli;
 mod commands;
-mod decoder;
-mod protocol;
-mod types;
+mod config;
+mod output;
+mod progress;
+mod tui;
 
-use crate::commands::{
-    cmd_decode, cmd_download, cmd_download_piece, cmd_handshake, cmd_info, cmd_peers
+use crate::{
+    cli::{print_completions, print_man_page, Cli, Command, Options},
+    commands::{
+        cmd_create, cmd_daemon, cmd_decode, cmd_download, cmd_download_piece, cmd_handshake, cmd_info, cmd_ip_filter, cmd_peers,
+        cmd_remote, cmd_replay, cmd_scrape, cmd_tracker, cmd_tui,
+    },
 };
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        return Err(anyhow!("Usage: your_bittorrent.sh <command>"));
-    }
-    let command = &args[1];
+    let cli = Cli::parse();
+    let mut options = Options::load(&cli.global)?;
+    log::init(options.log_level, options.log_filter.as_deref(), options.wire_trace)?;
+    options.load_ip_filter()?;
+    wire::set_capture_dir(options.capture.clone());
 
-    match command.as_str() {
-        // Usage: your_bittorrent.sh decode "<encoded_value>"
-        "decode" => {
-            if args.len() != 3 {
-                return Err(anyhow!("Usage: your_bittorrent.sh decode \"<encoded_value>\""));
-            }
-            cmd_decode(&args[2])
-        }
-        // Usage: your_bittorrent.sh info <torrent_name>
-        "info" => {
-            if args.len() != 3 {
-                return Err(anyhow!("Usage: your_bittorrent.sh info <torrent_name>"));
-            }
-            cmd_info(&args[2])
-        }
-        // Usage: your_bittorrent.sh peers <torrent_name>
-        "peers" => {
-            if args.len() != 3 {
-                return Err(anyhow!("Usage: your_bittorrent.sh peers <torrent_name>"));
-            }
-            cmd_peers(&args[2])
+    match cli.command {
+        Command::Decode { encoded_value } => cmd_decode(&encoded_value),
+        Command::Info { torrent } => cmd_info(&torrent, &options),
+        Command::Peers { torrent } => cmd_peers(&torrent, &options),
+        Command::Scrape { torrent } => cmd_scrape(&torrent, &options),
+        Command::Handshake { torrent, peer } => cmd_handshake(&torrent, &peer, &options),
+        Command::DownloadPiece { output, torrent, piece } => {
+            cmd_download_piece(output.as_deref(), &torrent, piece, &options)
         }
-        // Usage: your_bittorrent.sh handshake <torrent_name> <peer_ip:peer_port>
-        "handshake" => {
-            if args.len() != 4 {
-                return Err(anyhow!("Usage: your_bittorrent.sh handshake <torrent_name> <peer_ip:peer_port>"));
-            }
-            cmd_handshake(&args[2], &args[3])
+        Command::Download { output, torrent } => cmd_download(output.as_deref(), &torrent, &options),
+        Command::IpFilter { addresses } => cmd_ip_filter(&addresses, &options),
+        Command::Replay { recording, torrent } => cmd_replay(&recording, &torrent, &options),
+        Command::Create { output, trackers, piece_length, path } => {
+            cmd_create(output.as_deref(), &path, &trackers, piece_length, &options)
         }
-        // Usage: your_bittorrent.sh download_piece -o <output_file_name> <torrent_name> <piece_num>
-        "download_piece" => {
-            if args.len() != 6 && args[2] != "-o" {
-                return Err(anyhow!("Usage: your_bittorrent.sh download_piece -o <output_file_name> <torrent_name> <piece_num>"));
-            }
-            cmd_download_piece(&args[3], &args[4], &args[5])
+        Command::Tracker { bind, port, interval, whitelist } => {
+            cmd_tracker(&bind, port, Duration::from_secs(interval), whitelist.as_deref(), &options)
         }
-        // Usage: your_bittorrent.sh download -o <output_file_name> <torrent_name>
-        "download" => {
-            if args.len() != 5 && args[2] != "-o" {
-                return Err(anyhow!("Usage: your_bittorrent.sh download -o <output_file_name> <torrent_name>"));
-            }
-            cmd_download(&args[3], &args[4])
+        Command::Daemon(args) => cmd_daemon(&args, &options),
+        Command::Tui { session, torrents } => cmd_tui(&session, &torrents, &options),
+        Command::Remote { url, action } => cmd_remote(&url, &action, &options),
+        Command::Completions { shell } => {
+            print_completions(shell);
+            Ok(())
         }
-        _ => Err(anyhow!("Unknown command: {}", command))
+        Command::Man => print_man_page(),
     }
 }
@@ -0,0 +1,366 @@
+use std::{
+    fmt::{Display, Write as _},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+    http_server::{self, Request, Response},
+    session::{Session, TorrentHandle},
+};
+
+pub const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// Upper bounds of the histogram buckets, in seconds. Trackers answer in
+// tens to thousands of milliseconds, disks in well under one.
+const ANNOUNCE_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const DISK_WRITE_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Durations counted into fixed buckets, the way Prometheus histograms are
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // Per bucket, not cumulative; the last one is everything over the bounds
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(), sum_micros: AtomicU64::new(0) }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self.bounds.iter().position(|bound| secs <= *bound).unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    // Each bound with how many observations were at most that, ending with
+    // the total for +Inf
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .copied()
+            .chain([f64::INFINITY])
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+
+    fn add(&self, other: &Histogram) {
+        for (count, other) in self.counts.iter().zip(&other.counts) {
+            count.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(other.sum_micros.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// What a torrent has been up to since the session started, updated as it
+// happens by the code doing it
+#[derive(Debug)]
+pub struct TorrentMetrics {
+    // Piece data received from peers, counting pieces that failed their hash
+    // check
+    pub downloaded: Counter,
+    pub uploaded: Counter,
+    pub pieces_verified: Counter,
+    // Pieces a peer started sending us but didn't finish intact, for
+    // whatever reason
+    pub pieces_failed: Counter,
+    // The pieces that failed because their data didn't match the hash
+    pub hash_failures: Counter,
+    // Connections being set up that haven't finished their handshake yet
+    pub peers_half_open: Gauge,
+    pub announces_succeeded: Counter,
+    pub announces_failed: Counter,
+    pub announce_latency: Histogram,
+    pub disk_write_latency: Histogram,
+}
+
+impl Default for TorrentMetrics {
+    fn default() -> Self {
+        TorrentMetrics {
+            downloaded: Counter::default(),
+            uploaded: Counter::default(),
+            pieces_verified: Counter::default(),
+            pieces_failed: Counter::default(),
+            hash_failures: Counter::default(),
+            peers_half_open: Gauge::default(),
+            announces_succeeded: Counter::default(),
+            announces_failed: Counter::default(),
+            announce_latency: Histogram::new(ANNOUNCE_BUCKETS),
+            disk_write_latency: Histogram::new(DISK_WRITE_BUCKETS),
+        }
+    }
+}
+
+impl TorrentMetrics {
+    // Adds the other torrent's counters and histograms to ours. Gauges are
+    // left out, they describe a torrent as it is right now.
+    pub fn add(&self, other: &TorrentMetrics) {
+        for (counter, other) in self.counters().into_iter().zip(other.counters()) {
+            counter.add(other.get());
+        }
+        self.announce_latency.add(&other.announce_latency);
+        self.disk_write_latency.add(&other.disk_write_latency);
+    }
+
+    fn counters(&self) -> [&Counter; 7] {
+        [
+            &self.downloaded,
+            &self.uploaded,
+            &self.pieces_verified,
+            &self.pieces_failed,
+            &self.hash_failures,
+            &self.announces_succeeded,
+            &self.announces_failed,
+        ]
+    }
+}
+
+// The session's metrics in Prometheus' text format: each torrent's under
+// `bittorrent_torrent_*`, labelled with its info hash and name, and the
+// session's totals under `bittorrent_*`. Totals keep counting what removed
+// torrents did.
+pub fn render(session: &Session) -> String {
+    let torrents = session.torrents();
+    let total = TorrentMetrics::default();
+    total.add(session.removed_metrics());
+    for handle in &torrents {
+        total.add(handle.metrics());
+    }
+    let half_open: u64 = torrents.iter().map(|handle| handle.metrics().peers_half_open.get()).sum();
+    let connected: usize = torrents.iter().map(|handle| handle.progress().peers).sum();
+
+    let mut out = Exposition::default();
+    out.family("bittorrent_torrents", "gauge", "Torrents in the session by state");
+    for state in ["queued", "downloading", "seeding", "completed", "paused", "failed"] {
+        let count = torrents.iter().filter(|handle| handle.state().name() == state).count();
+        out.sample("bittorrent_torrents", &[("state", state.to_string())], count);
+    }
+    out.family("bittorrent_connections", "gauge", "Peer connections open across all torrents, incoming and outgoing");
+    out.sample("bittorrent_connections", &[], session.connections());
+    out.family("bittorrent_connections_max", "gauge", "Peer connections the session allows at once");
+    out.sample("bittorrent_connections_max", &[], session.config().max_connections);
+
+    let counters: [(&str, &str, CounterOf); 5] = [
+        ("downloaded_bytes_total", "Piece data received from peers, including pieces that failed their hash check", |metrics| &metrics.downloaded),
+        ("uploaded_bytes_total", "Piece data sent to peers", |metrics| &metrics.uploaded),
+        ("pieces_verified_total", "Pieces downloaded that matched their hash", |metrics| &metrics.pieces_verified),
+        ("pieces_failed_total", "Pieces that didn't arrive intact, for any reason", |metrics| &metrics.pieces_failed),
+        ("hash_failures_total", "Pieces whose data didn't match their hash", |metrics| &metrics.hash_failures),
+    ];
+    for (name, help, counter) in counters {
+        out.family(&format!("bittorrent_{}", name), "counter", help);
+        out.sample(&format!("bittorrent_{}", name), &[], counter(&total).get());
+        out.family(&format!("bittorrent_torrent_{}", name), "counter", help);
+        for handle in &torrents {
+            out.sample(&format!("bittorrent_torrent_{}", name), &labels(handle), counter(handle.metrics()).get());
+        }
+    }
+
+    let help = "Peers we've finished a handshake with, both ways";
+    out.family("bittorrent_peers_connected", "gauge", help);
+    out.sample("bittorrent_peers_connected", &[], connected);
+    out.family("bittorrent_torrent_peers_connected", "gauge", help);
+    for handle in &torrents {
+        out.sample("bittorrent_torrent_peers_connected", &labels(handle), handle.progress().peers);
+    }
+    let help = "Outgoing peer connections still being set up";
+    out.family("bittorrent_peers_half_open", "gauge", help);
+    out.sample("bittorrent_peers_half_open", &[], half_open);
+    out.family("bittorrent_torrent_peers_half_open", "gauge", help);
+    for handle in &torrents {
+        out.sample("bittorrent_torrent_peers_half_open", &labels(handle), handle.metrics().peers_half_open.get());
+    }
+
+    let help = "Announces to trackers by whether they got an answer";
+    out.family("bittorrent_tracker_announces_total", "counter", help);
+    out.announces("bittorrent_tracker_announces_total", &[], &total);
+    out.family("bittorrent_torrent_tracker_announces_total", "counter", help);
+    for handle in &torrents {
+        out.announces("bittorrent_torrent_tracker_announces_total", &labels(handle), handle.metrics());
+    }
+
+    let histograms: [(&str, &str, HistogramOf); 2] = [
+        ("tracker_announce_duration_seconds", "How long announces took, failed ones included", |metrics| &metrics.announce_latency),
+        ("disk_write_duration_seconds", "How long writing a verified piece to disk took", |metrics| &metrics.disk_write_latency),
+    ];
+    for (name, help, histogram) in histograms {
+        out.family(&format!("bittorrent_{}", name), "histogram", help);
+        out.histogram(&format!("bittorrent_{}", name), &[], histogram(&total));
+        out.family(&format!("bittorrent_torrent_{}", name), "histogram", help);
+        for handle in &torrents {
+            out.histogram(&format!("bittorrent_torrent_{}", name), &labels(handle), histogram(handle.metrics()));
+        }
+    }
+    out.text
+}
+
+type CounterOf = fn(&TorrentMetrics) -> &Counter;
+type HistogramOf = fn(&TorrentMetrics) -> &Histogram;
+
+fn labels(handle: &TorrentHandle) -> [(&'static str, String); 2] {
+    [("info_hash", hex::encode(handle.info_hash())), ("name", handle.torrent().info.name.clone())]
+}
+
+// Answers GET /metrics with the session's metrics and anything else with a 404
+pub fn handle_http(session: &Session, request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", METRICS_PATH) => Response::new(200, CONTENT_TYPE, render(session).into_bytes()),
+        (_, METRICS_PATH) => Response::new(405, "text/plain", b"Method Not Allowed".to_vec()),
+        _ => Response::not_found(),
+    }
+}
+
+// Serves the session's metrics for Prometheus to scrape, forever
+pub fn serve(session: Arc<Session>, listener: TcpListener) -> Result<()> {
+    http_server::serve(listener, Arc::new(move |request| handle_http(&session, request)))
+}
+
+// Builds up a response in the text exposition format
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, String)], value: impl Display) {
+        let _ = writeln!(self.text, "{}{} {}", name, format_labels(labels), value);
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, String)], histogram: &Histogram) {
+        for (bound, count) in histogram.buckets() {
+            let le = if bound.is_infinite() { "+Inf".to_string() } else { bound.to_string() };
+            self.sample(&format!("{}_bucket", name), &[labels, &[("le", le)]].concat(), count);
+        }
+        self.sample(&format!("{}_sum", name), labels, histogram.sum().as_secs_f64());
+        self.sample(&format!("{}_count", name), labels, histogram.count());
+    }
+
+    fn announces(&mut self, name: &str, labels: &[(&str, String)], metrics: &TorrentMetrics) {
+        for (result, count) in [("success", &metrics.announces_succeeded), ("failure", &metrics.announces_failed)] {
+            self.sample(name, &[labels, &[("result", result.to_string())]].concat(), count.get());
+        }
+    }
+}
+
+fn format_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, escape(value))).collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(3));
+        assert_eq!(histogram.buckets(), vec![(0.1, 2), (1.0, 3), (f64::INFINITY, 4)]);
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(3650));
+    }
+
+    #[test]
+    fn test_add_leaves_gauges_out() {
+        let (first, second) = (TorrentMetrics::default(), TorrentMetrics::default());
+        first.downloaded.add(100);
+        first.peers_half_open.inc();
+        first.disk_write_latency.observe(Duration::from_millis(2));
+        second.add(&first);
+        second.add(&first);
+        assert_eq!(second.downloaded.get(), 200);
+        assert_eq!(second.peers_half_open.get(), 0);
+        assert_eq!(second.disk_write_latency.count(), 2);
+    }
+
+    #[test]
+    fn test_exposition_format() {
+        let histogram = Histogram::new(&[0.5]);
+        histogram.observe(Duration::from_millis(250));
+        let mut out = Exposition::default();
+        out.family("test_seconds", "histogram", "Test");
+        out.histogram("test_seconds", &[("name", "a \"b\"\n".to_string())], &histogram);
+        assert_eq!(
+            out.text,
+            "# HELP test_seconds Test\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{name=\"a \\\"b\\\"\\n\",le=\"0.5\"} 1\n\
+             test_seconds_bucket{name=\"a \\\"b\\\"\\n\",le=\"+Inf\"} 1\n\
+             test_seconds_sum{name=\"a \\\"b\\\"\\n\"} 0.25\n\
+             test_seconds_count{name=\"a \\\"b\\\"\\n\"} 1\n"
+        );
+    }
+}
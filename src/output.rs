@@ -0,0 +1,417 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use bittorrent_starter_rust::{
+    ipfilter::IpFilter,
+    metrics::METRICS_PATH,
+    peer_id::Client,
+    protocol::Handshake,
+    rpc::{SessionStatus, TorrentStatus},
+    tracker::ScrapeStats,
+    types::{Files, Torrent},
+    wire::{Recording, Replayed},
+};
+
+// Command results, printed as text or, with --json, as the documents
+// described in docs/json-output.md. Fields may be added but never renamed or
+// removed.
+pub fn print<T: Serialize + fmt::Display>(value: &T, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string(value)?);
+    } else {
+        print!("{}", value);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileEntry {
+    pub path: Vec<String>,
+    pub length: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InfoOutput {
+    pub name: String,
+    pub announce: String,
+    // The tiers we actually announce to, which is just the announce URL when
+    // there's no announce-list
+    pub tracker_tiers: Vec<Vec<String>>,
+    #[serde(skip)]
+    pub has_announce_list: bool,
+    pub info_hash: String,
+    pub length: usize,
+    pub piece_length: usize,
+    pub piece_hashes: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+impl InfoOutput {
+    pub fn new(torrent: &Torrent, info_hash: &[u8; 20]) -> Self {
+        let files = match &torrent.info.files {
+            Files::Single { length } => vec![FileEntry { path: vec![torrent.info.name.clone()], length: *length }],
+            Files::Multiple { files } => {
+                files.iter().map(|file| FileEntry { path: file.path.clone(), length: file.length }).collect()
+            }
+        };
+        InfoOutput {
+            name: torrent.info.name.clone(),
+            announce: torrent.announce.clone(),
+            tracker_tiers: torrent.announce_tiers(),
+            has_announce_list: torrent.announce_list.is_some(),
+            info_hash: hex::encode(info_hash),
+            length: torrent.info.files.length(),
+            piece_length: torrent.info.piece_length,
+            piece_hashes: torrent.info.pieces.0.iter().map(hex::encode).collect(),
+            files,
+        }
+    }
+}
+
+impl fmt::Display for InfoOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Tracker URL: {}", self.announce)?;
+        if self.has_announce_list {
+            writeln!(f, "Tracker Tiers:")?;
+            for (i, tier) in self.tracker_tiers.iter().enumerate() {
+                writeln!(f, "{}: {}", i, tier.join(" "))?;
+            }
+        }
+        writeln!(f, "Length: {}", self.length)?;
+        writeln!(f, "Info Hash: {}", self.info_hash)?;
+        writeln!(f, "Piece Length: {}", self.piece_length)?;
+        writeln!(f, "Piece Hashes:")?;
+        for hash in &self.piece_hashes {
+            writeln!(f, "{}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateOutput {
+    pub output: PathBuf,
+    #[serde(flatten)]
+    pub info: InfoOutput,
+}
+
+impl fmt::Display for CreateOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Created {}", self.output.display())?;
+        writeln!(f, "Info Hash: {}", self.info.info_hash)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerEntry {
+    pub ip: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeersOutput {
+    pub info_hash: String,
+    pub peers: Vec<PeerEntry>,
+}
+
+impl PeersOutput {
+    pub fn new(info_hash: &[u8; 20], peers: &[SocketAddr]) -> Self {
+        PeersOutput {
+            info_hash: hex::encode(info_hash),
+            peers: peers.iter().map(|peer| PeerEntry { ip: peer.ip().to_string(), port: peer.port() }).collect(),
+        }
+    }
+}
+
+impl fmt::Display for PeersOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for peer in &self.peers {
+            match peer.ip.contains(':') {
+                true => writeln!(f, "[{}]:{}", peer.ip, peer.port)?,
+                false => writeln!(f, "{}:{}", peer.ip, peer.port)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IpFilterOutput {
+    pub filter: PathBuf,
+    // After overlapping ones are merged
+    pub ranges: usize,
+    pub addresses: Vec<AddressEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AddressEntry {
+    pub address: IpAddr,
+    pub blocked: bool,
+}
+
+impl IpFilterOutput {
+    pub fn new(path: &Path, filter: &IpFilter, addresses: &[IpAddr]) -> Self {
+        IpFilterOutput {
+            filter: path.to_path_buf(),
+            ranges: filter.len(),
+            addresses: addresses.iter().map(|&address| AddressEntry { address, blocked: filter.is_blocked(address) }).collect(),
+        }
+    }
+}
+
+impl fmt::Display for IpFilterOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.addresses {
+            writeln!(f, "{}: {}", entry.address, if entry.blocked { "blocked" } else { "allowed" })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScrapeOutput {
+    pub tracker: String,
+    pub info_hash: String,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub completed: u32,
+}
+
+impl ScrapeOutput {
+    pub fn new(tracker: String, info_hash: &[u8; 20], stats: &ScrapeStats) -> Self {
+        ScrapeOutput {
+            tracker,
+            info_hash: hex::encode(info_hash),
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            completed: stats.completed,
+        }
+    }
+}
+
+impl fmt::Display for ScrapeOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Tracker URL: {}", self.tracker)?;
+        writeln!(f, "Seeders: {}", self.seeders)?;
+        writeln!(f, "Leechers: {}", self.leechers)?;
+        writeln!(f, "Completed: {}", self.completed)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Extensions {
+    pub dht: bool,
+    pub fast: bool,
+    pub extension_protocol: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HandshakeOutput {
+    pub peer: String,
+    pub peer_id: String,
+    // Who the peer id says made the client, like "qBittorrent 4.5.2"
+    pub client: String,
+    pub reserved: String,
+    pub extensions: Extensions,
+}
+
+impl HandshakeOutput {
+    pub fn new(peer: &str, handshake: &Handshake) -> Self {
+        let reserved = handshake.reserved;
+        HandshakeOutput {
+            peer: peer.to_string(),
+            peer_id: hex::encode(handshake.peer_id),
+            client: Client::from_peer_id(&handshake.peer_id).to_string(),
+            reserved: hex::encode(reserved),
+            extensions: Extensions {
+                // BEP 5, BEP 6 and BEP 10
+                dht: reserved[7] & 0x01 != 0,
+                fast: reserved[7] & 0x04 != 0,
+                extension_protocol: reserved[5] & 0x10 != 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for HandshakeOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Peer ID: {}", self.peer_id)?;
+        writeln!(f, "Client: {}", self.client)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PieceOutput {
+    pub output: PathBuf,
+    pub piece: u32,
+    pub length: usize,
+    pub hash: String,
+}
+
+// Nothing is printed for a successful download in text mode
+impl fmt::Display for PieceOutput {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadOutput {
+    pub output: PathBuf,
+    pub info_hash: String,
+    pub length: usize,
+    pub pieces: usize,
+    pub elapsed_secs: f64,
+}
+
+impl fmt::Display for DownloadOutput {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayOutput {
+    pub peer: SocketAddr,
+    // Pieces that verified, in the order they were asked for
+    pub pieces: Vec<u32>,
+    // What stopped the replay, if anything did
+    pub error: Option<String>,
+}
+
+impl ReplayOutput {
+    pub fn new(recording: &Recording, replayed: &Replayed) -> Self {
+        ReplayOutput {
+            peer: recording.peer,
+            pieces: replayed.pieces.clone(),
+            error: replayed.result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+impl fmt::Display for ReplayOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Replayed {}, verified {} pieces", self.peer, self.pieces.len())?;
+        for (position, piece) in self.pieces.iter().enumerate() {
+            write!(f, "{}{}", if position == 0 { ": " } else { " " }, piece)?;
+        }
+        writeln!(f)?;
+        match &self.error {
+            Some(error) => writeln!(f, "Failed: {}", error),
+            None => writeln!(f, "Completed"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackerOutput {
+    pub listen_addr: SocketAddr,
+}
+
+impl fmt::Display for TrackerOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Tracker listening on {}", self.listen_addr)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DaemonOutput {
+    pub rpc_addr: SocketAddr,
+    pub listen_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl fmt::Display for DaemonOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RPC listening on {}, peers on port {}", self.rpc_addr, self.listen_port)?;
+        if let Some(metrics_addr) = self.metrics_addr {
+            writeln!(f, "Metrics on http://{}{}", metrics_addr, METRICS_PATH)?;
+        }
+        Ok(())
+    }
+}
+
+// One torrent from `remote add` or `remote show`
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct RemoteTorrentOutput(pub TorrentStatus);
+
+impl fmt::Display for RemoteTorrentOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let torrent = &self.0;
+        writeln!(f, "Name: {}", torrent.name)?;
+        writeln!(f, "Info Hash: {}", torrent.info_hash)?;
+        writeln!(f, "Output: {}", torrent.output.display())?;
+        match &torrent.error {
+            Some(error) => writeln!(f, "State: {} ({})", torrent.state, error)?,
+            None => writeln!(f, "State: {}", torrent.state)?,
+        }
+        writeln!(f, "Pieces: {}/{}", torrent.pieces_done, torrent.num_pieces)?;
+        writeln!(f, "Downloaded: {} of {}", torrent.downloaded, torrent.total)?;
+        writeln!(f, "Uploaded: {}", torrent.uploaded)?;
+        writeln!(f, "Peers: {}", torrent.peers)?;
+        writeln!(f, "Download limit: {}", rate(torrent.download_rate))?;
+        writeln!(f, "Upload limit: {}", rate(torrent.upload_rate))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemoteListOutput {
+    pub torrents: Vec<TorrentStatus>,
+}
+
+impl fmt::Display for RemoteListOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for torrent in &self.torrents {
+            let percent = match torrent.total {
+                0 => 100.0,
+                total => torrent.downloaded as f64 * 100.0 / total as f64,
+            };
+            writeln!(f, "{} {:<11} {:5.1}% {}", torrent.info_hash, torrent.state, percent, torrent.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct RemoteLimitsOutput(pub SessionStatus);
+
+impl fmt::Display for RemoteLimitsOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Download limit: {}", rate(self.0.download_rate))?;
+        writeln!(f, "Upload limit: {}", rate(self.0.upload_rate))?;
+        writeln!(f, "Peer download limit: {}", rate(self.0.peer_download_rate))?;
+        writeln!(f, "Peer upload limit: {}", rate(self.0.peer_upload_rate))
+    }
+}
+
+fn rate(rate: Option<u64>) -> String {
+    rate.map_or("unlimited".to_string(), |rate| format!("{} B/s", rate))
+}
+
+// Pausing, resuming, removing and shutting down have nothing to report but
+// what was done
+#[derive(Debug, Serialize)]
+pub struct RemoteActionOutput {
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<String>,
+}
+
+impl fmt::Display for RemoteActionOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut action = self.action.chars();
+        let action: String = action.next().map(|first| first.to_ascii_uppercase()).into_iter().chain(action).collect();
+        match &self.info_hash {
+            Some(info_hash) => writeln!(f, "{} {}", action, info_hash),
+            None => writeln!(f, "{}", action),
+        }
+    }
+}
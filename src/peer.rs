@@ -0,0 +1,299 @@
+use anyhow::{anyhow, Result};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    protocol::{
+        download_piece, perform_handshake_with_peer, send_am_interested, send_keep_alive, wait_for_bitfield, wait_for_unchoke, Handshake,
+        PeerStream, CHUNK_LEN,
+    },
+    ratelimit::{RateLimits, Throttled},
+    wire::{self, Captured},
+};
+
+// Shortest read timeout we set; sockets take zero to mean none at all
+const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+// How long we wait on a peer before giving up on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    // For the handshake and bitfield once connected
+    pub handshake: Duration,
+    // For the peer to answer once we've asked it for something
+    pub request: Duration,
+    // We send a keep-alive when we've been quiet this long
+    pub keep_alive: Duration,
+    // A peer that sends nothing at all for this long is dropped
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            // Peers drop connections that are quiet for two minutes
+            keep_alive: Duration::from_secs(60),
+            idle: Duration::from_secs(120),
+        }
+    }
+}
+
+// A connection to a peer that has completed the handshake and sent us its
+// bitfield, ready to be asked for pieces once it unchokes us. Usually over
+// a socket, but it can be a recording being replayed too.
+pub struct PeerConnection<S = Throttled<Captured<TcpStream>>> {
+    pub addr: SocketAddr,
+    pub handshake: Handshake,
+    pub bitfield: Vec<u8>,
+    // Size of the blocks pieces are requested in
+    pub block_len: u32,
+    stream: KeepAlive<S>,
+    unchoked: bool,
+}
+
+impl PeerConnection {
+    // Connects to the peer, with everything sent and received counting
+    // against each of `limits`
+    pub fn connect(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+        num_pieces: usize,
+        limits: Vec<Arc<RateLimits>>,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, timeouts.connect)?;
+        stream.set_write_timeout(Some(timeouts.idle))?;
+        let stream = Throttled::new(wire::capture(stream, false), limits);
+        PeerConnection::from_stream(addr, stream, info_hash, peer_id, num_pieces, timeouts)
+    }
+}
+
+impl<S: PeerStream> PeerConnection<S> {
+    pub fn from_stream(addr: SocketAddr, stream: S, info_hash: &[u8; 20], peer_id: &[u8; 20], num_pieces: usize, timeouts: Timeouts) -> Result<Self> {
+        let mut stream = KeepAlive::new(stream, timeouts);
+        let handshake = perform_handshake_with_peer(&mut stream, info_hash, peer_id)?;
+
+        let bitfield = wait_for_bitfield(&mut stream)?;
+        let bitfield_bytes = num_pieces.div_ceil(8);
+        if bitfield_bytes != bitfield.len() {
+            return Err(anyhow!("Expected bitfield of length {}, got {}", bitfield_bytes, bitfield.len()));
+        }
+        stream.handshaken();
+        Ok(PeerConnection { addr, handshake, bitfield, block_len: CHUNK_LEN, stream, unchoked: false })
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        has_piece(&self.bitfield, index as usize)
+    }
+
+    // Tells the peer we're interested and waits to be unchoked, once
+    pub fn unchoke(&mut self) -> Result<()> {
+        if !self.unchoked {
+            send_am_interested(&mut self.stream)?;
+            self.stream.expect_reply(true);
+            let result = wait_for_unchoke(&mut self.stream);
+            self.stream.expect_reply(false);
+            result?;
+            self.unchoked = true;
+        }
+        Ok(())
+    }
+
+    // Downloads and verifies a single piece
+    pub fn download_piece(&mut self, index: u32, length: u32, hash: &[u8; 20]) -> Result<Vec<u8>> {
+        self.unchoke()?;
+        self.stream.expect_reply(true);
+        let result = download_piece(&mut self.stream, index, length, hash, self.block_len);
+        self.stream.expect_reply(false);
+        result
+    }
+
+    // Sends a keep-alive if we've been quiet long enough to need one, for
+    // callers that hold on to the connection without using it
+    pub fn keep_alive(&mut self) -> Result<()> {
+        Ok(self.stream.keep_alive()?)
+    }
+}
+
+// A peer stream that keeps the connection alive and notices when the peer
+// goes quiet. Reads wait in slices, sending a keep-alive whenever we've been
+// quiet for the keep-alive interval, and fail once the peer has sent nothing
+// for as long as it's allowed to: the handshake timeout until handshakes are
+// exchanged, the request timeout while we wait on an answer and the idle
+// timeout otherwise.
+pub struct KeepAlive<S> {
+    stream: S,
+    timeouts: Timeouts,
+    quiet_limit: Duration,
+    // Not until our handshake is out, a keep-alive can't come before it
+    keep_alives: bool,
+    // When we last heard from the peer, or started waiting on it
+    heard: Instant,
+    sent: Instant,
+}
+
+impl<S: PeerStream> KeepAlive<S> {
+    pub fn new(stream: S, timeouts: Timeouts) -> Self {
+        let now = Instant::now();
+        KeepAlive { stream, timeouts, quiet_limit: timeouts.handshake, keep_alives: false, heard: now, sent: now }
+    }
+
+    // Both handshakes are through, so keep-alives can start
+    pub fn handshaken(&mut self) {
+        self.keep_alives = true;
+        self.quiet_limit = self.timeouts.idle;
+    }
+
+    // Whether we're waiting on the peer to answer something we asked
+    pub fn expect_reply(&mut self, expecting: bool) {
+        self.quiet_limit = if expecting { self.timeouts.request } else { self.timeouts.idle };
+        self.heard = Instant::now();
+    }
+
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        if self.keep_alives && self.sent.elapsed() >= self.timeouts.keep_alive {
+            send_keep_alive(self).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S: PeerStream> Read for KeepAlive<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let quiet = self.heard.elapsed();
+            if quiet >= self.quiet_limit {
+                return Err(io::Error::new(ErrorKind::TimedOut, format!("Peer sent nothing for {:?}", self.quiet_limit)));
+            }
+            let mut wait = self.quiet_limit - quiet;
+            if self.keep_alives {
+                wait = wait.min(self.timeouts.keep_alive.saturating_sub(self.sent.elapsed()));
+            }
+            self.stream.set_read_timeout(Some(wait.max(MIN_READ_TIMEOUT)))?;
+            match self.stream.read(buf) {
+                Ok(read) => {
+                    if read > 0 {
+                        self.heard = Instant::now();
+                    }
+                    return Ok(read);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => self.keep_alive()?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S: PeerStream> Write for KeepAlive<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.sent = Instant::now();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: PeerStream> PeerStream for KeepAlive<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+pub fn has_piece(bitfield: &[u8], index: usize) -> bool {
+    bitfield.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+// Packs which pieces we have into a bitfield message payload
+pub fn to_bitfield(have: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; have.len().div_ceil(8)];
+    for (index, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    bitfield
+}
+
+pub fn from_bitfield(bitfield: &[u8], num_pieces: usize) -> Vec<bool> {
+    (0..num_pieces).map(|index| has_piece(bitfield, index)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (ours, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn test_keep_alives_until_idle() {
+        let (ours, mut theirs) = pair();
+        let timeouts = Timeouts { keep_alive: Duration::from_millis(40), idle: Duration::from_millis(200), ..Timeouts::default() };
+        let mut stream = KeepAlive::new(ours, timeouts);
+        stream.handshaken();
+        let started = Instant::now();
+        let error = stream.read(&mut [0u8; 4]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= timeouts.idle);
+
+        drop(stream);
+        let mut sent = Vec::new();
+        theirs.read_to_end(&mut sent).unwrap();
+        assert!(sent.len() >= 12 && sent.len() % 4 == 0, "{:?}", sent);
+        assert!(sent.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn test_request_timeout_while_waiting() {
+        let (ours, mut theirs) = pair();
+        let timeouts = Timeouts { request: Duration::from_millis(100), ..Timeouts::default() };
+        let mut stream = KeepAlive::new(ours, timeouts);
+        stream.handshaken();
+        theirs.write_all(&[1, 2]).unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+
+        stream.expect_reply(true);
+        let error = stream.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(error.to_string().contains("100ms"), "{}", error);
+    }
+
+    #[test]
+    fn test_no_keep_alive_before_handshake() {
+        let (ours, mut theirs) = pair();
+        let timeouts = Timeouts { handshake: Duration::from_millis(100), keep_alive: Duration::from_millis(10), ..Timeouts::default() };
+        let mut stream = KeepAlive::new(ours, timeouts);
+        assert_eq!(stream.read(&mut [0u8; 4]).unwrap_err().kind(), ErrorKind::TimedOut);
+        drop(stream);
+        let mut sent = Vec::new();
+        theirs.read_to_end(&mut sent).unwrap();
+        assert!(sent.is_empty(), "{:?}", sent);
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+
+use crate::random::random_u64;
+
+// What our peer ids start with, Azureus style: "-", a two letter client code,
+// four version digits and "-". Keep the digits in step with the crate
+// version.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-SR0100-";
+
+// Azureus style client codes we know the names of
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("A~", "Ares"),
+    ("AR", "Arctic"),
+    ("AT", "Artemis"),
+    ("AX", "BitPump"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BG", "BTG"),
+    ("BI", "BiglyBT"),
+    ("BL", "BitBlinder"),
+    ("BP", "BitTorrent Pro"),
+    ("BR", "BitRocket"),
+    ("BS", "BTSlave"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("CT", "CTorrent"),
+    ("DE", "Deluge"),
+    ("EB", "EBit"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("FT", "FoxTorrent"),
+    ("FX", "Freebox"),
+    ("HL", "Halite"),
+    ("HN", "Hydranode"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LC", "LeechCraft"),
+    ("LH", "LH-ABC"),
+    ("LP", "Lphant"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("LW", "LimeWire"),
+    ("MO", "MonoTorrent"),
+    ("MP", "MooPolice"),
+    ("MR", "Miro"),
+    ("MT", "MoonlightTorrent"),
+    ("NX", "Net Transport"),
+    ("OS", "OneSwarm"),
+    ("OT", "OmegaTorrent"),
+    ("PD", "Pando"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("QT", "Qt 4 Torrent example"),
+    ("RT", "Retriever"),
+    ("SB", "Swiftbit"),
+    ("SD", "Thunder"),
+    ("SM", "SoMud"),
+    ("SP", "BitSpirit"),
+    ("SR", "bittorrent-starter-rust"),
+    ("SS", "SwarmScope"),
+    ("ST", "SymTorrent"),
+    ("st", "sharktorrent"),
+    ("SZ", "Shareaza"),
+    ("TN", "TorrentDotNET"),
+    ("TR", "Transmission"),
+    ("TS", "Torrentstorm"),
+    ("TT", "TuoTu"),
+    ("UL", "uLeecher!"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WT", "BitLet"),
+    ("WW", "WebTorrent"),
+    ("WY", "FireTorrent"),
+    ("XF", "Xfplay"),
+    ("XL", "Xunlei"),
+    ("XT", "XanTorrent"),
+    ("XX", "Xtorrent"),
+    ("ZT", "ZipTorrent"),
+];
+
+// Shadow style clients, which put a single letter first
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Characters allowed in the random part of our peer ids
+const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// A fresh peer id, our prefix followed by random characters. Sessions make
+// one when they start and use it with every tracker and peer.
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for byte in &mut peer_id[8..] {
+        *byte = ALPHABET[(random_u64() % ALPHABET.len() as u64) as usize];
+    }
+    peer_id
+}
+
+// The client a peer id says it comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Client {
+    // Decodes Azureus style ids like "-qB4520-…" and Shadow style ones like
+    // "T03I-----…". Anything else is named after whatever printable prefix
+    // it has.
+    pub fn from_peer_id(peer_id: &[u8; 20]) -> Self {
+        azureus(peer_id).or_else(|| shadow(peer_id)).unwrap_or_else(|| {
+            let prefix: Vec<u8> = peer_id.iter().take_while(|byte| byte.is_ascii_graphic()).take(8).copied().collect();
+            let name = match prefix.is_empty() {
+                true => "unknown".to_string(),
+                false => String::from_utf8_lossy(&prefix).into_owned(),
+            };
+            Client { name, version: None }
+        })
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+fn azureus(peer_id: &[u8; 20]) -> Option<Client> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let digits = &peer_id[3..7];
+    if !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '~') || !digits.iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let name = AZUREUS_CLIENTS.iter().find(|(known, _)| *known == code).map_or(code, |(_, name)| name);
+    let digit = |byte: u8| (byte as char).to_digit(36);
+    let version = match code {
+        // "-TR2940-" is 2.94, with a Z or X at the end for builds in between
+        "TR" => format!(
+            "{}.{}{}{}",
+            digits[0] as char,
+            digits[1] as char,
+            digits[2] as char,
+            if matches!(digits[3], b'Z' | b'X') { "+" } else { "" }
+        ),
+        // "-UT355W-" is 3.5.5, the last letter says what kind of build
+        "UT" | "UM" | "UW" => format!("{}.{}.{}", digit(digits[0])?, digit(digits[1])?, digit(digits[2])?),
+        // Otherwise a number per character, "-qB4520-" for 4.5.2
+        _ => {
+            let mut parts = digits.iter().map(|byte| digit(*byte)).collect::<Option<Vec<u32>>>()?;
+            while parts.len() > 2 && parts.last() == Some(&0) {
+                parts.pop();
+            }
+            parts.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+        }
+    };
+    Some(Client { name: name.to_string(), version: Some(version) })
+}
+
+fn shadow(peer_id: &[u8; 20]) -> Option<Client> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(letter, _)| *letter == peer_id[0])?;
+    // Up to five version characters, then dashes
+    let end = peer_id[1..6].iter().position(|byte| *byte == b'-').map_or(6, |at| at + 1);
+    if end == 1 || peer_id.get(end..end + 2) != Some(b"--") {
+        return None;
+    }
+    let parts = peer_id[1..end]
+        .iter()
+        .map(|byte| match byte {
+            b'0'..=b'9' => Some((byte - b'0') as u32),
+            b'A'..=b'Z' => Some((byte - b'A') as u32 + 10),
+            b'a'..=b'z' => Some((byte - b'a') as u32 + 36),
+            b'.' => Some(62),
+            _ => None,
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    let version = parts.iter().map(u32::to_string).collect::<Vec<_>>().join(".");
+    Some(Client { name: name.to_string(), version: Some(version) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(peer_id: &[u8]) -> String {
+        let mut padded = [b'x'; 20];
+        padded[..peer_id.len()].copy_from_slice(peer_id);
+        Client::from_peer_id(&padded).to_string()
+    }
+
+    #[test]
+    fn test_generated_peer_ids() {
+        let (first, second) = (generate_peer_id(), generate_peer_id());
+        assert_ne!(first, second);
+        assert!(first.starts_with(PEER_ID_PREFIX));
+        assert!(first.iter().all(u8::is_ascii_graphic));
+        assert_eq!(Client::from_peer_id(&first).to_string(), "bittorrent-starter-rust 0.1");
+    }
+
+    #[test]
+    fn test_azureus_style() {
+        assert_eq!(client(b"-qB4520-"), "qBittorrent 4.5.2");
+        assert_eq!(client(b"-TR2940-"), "Transmission 2.94");
+        assert_eq!(client(b"-TR300Z-"), "Transmission 3.00+");
+        assert_eq!(client(b"-UT355W-"), "µTorrent 3.5.5");
+        assert_eq!(client(b"-DE13F0-"), "Deluge 1.3.15");
+        assert_eq!(client(b"-LT1020-"), "libtorrent 1.0.2");
+        assert_eq!(client(b"-lt0D60-"), "rTorrent 0.13.6");
+        // Codes we don't know keep the code as their name
+        assert_eq!(client(b"-MK0001-"), "MK 0.0.0.1");
+    }
+
+    #[test]
+    fn test_shadow_style() {
+        assert_eq!(client(b"S58B-----"), "Shadow 5.8.11");
+        assert_eq!(client(b"T03I-----"), "BitTornado 0.3.18");
+        assert_eq!(client(b"A310--"), "ABC 3.1.0");
+    }
+
+    #[test]
+    fn test_unknown_style() {
+        assert_eq!(client(b"M4-3-6--"), "M4-3-6--");
+        assert_eq!(Client::from_peer_id(&[0; 20]).to_string(), "unknown");
+        // A Shadow letter without the dashes isn't Shadow style
+        assert_eq!(client(b"Tixati12"), "Tixati12");
+    }
+}
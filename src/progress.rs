@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, IsTerminal},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tracing::{info, Level};
+
+use bittorrent_starter_rust::{log, ratemeter::RateMeter, Event, PeerInfo, TorrentHandle};
+
+// How often the display is redrawn on a terminal
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+// How often a progress line is logged when stderr isn't a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_PEER_LINES: usize = 10;
+// Lines above the peer list: name, piece map, transfer, tracker and peer count
+const HEADER_LINES: usize = 5;
+
+// Shows how a download is going on stderr: redrawn in place on a terminal,
+// a line every so often otherwise
+pub struct ProgressDisplay {
+    handle: TorrentHandle,
+    tty: bool,
+    // The torrent's (download, upload) rates and ETA as of the last sample
+    rates: (u64, u64),
+    eta: Option<Duration>,
+    // Download and upload rates of each peer
+    peers: HashMap<SocketAddr, (RateMeter, RateMeter)>,
+    tracker: String,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressDisplay {
+    pub fn new(handle: TorrentHandle) -> Self {
+        ProgressDisplay {
+            handle,
+            tty: io::stderr().is_terminal() && tracing::enabled!(Level::INFO),
+            rates: (0, 0),
+            eta: None,
+            peers: HashMap::new(),
+            tracker: "announcing".to_string(),
+            last_draw: None,
+        }
+    }
+
+    pub fn event(&mut self, event: &Event) {
+        match event {
+            Event::TrackerAnnounced { url, peers } => self.tracker = format!("{} peers from {}", peers, url),
+            Event::TrackerWarning { url, message } => self.tracker = format!("warning from {}: {}", url, message),
+            Event::TrackerError(error) => self.tracker = format!("error: {}", error),
+            _ => {}
+        }
+    }
+
+    // Samples the rates and redraws or logs, if it's time to
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        let interval = if self.tty { REDRAW_INTERVAL } else { LOG_INTERVAL };
+        if self.last_draw.is_some_and(|last| now.duration_since(last) < interval) {
+            return;
+        }
+        self.sample(now);
+        // The first sample has nothing to compare against
+        if self.last_draw.is_some() || self.tty {
+            match self.tty {
+                true => log::set_status(&self.render(terminal_size())),
+                false => info!("{}", self.summary()),
+            }
+        }
+        self.last_draw = Some(now);
+    }
+
+    // Takes the display down and logs where the download got to
+    pub fn finish(&mut self) {
+        self.sample(Instant::now());
+        if self.tty {
+            log::clear_status();
+        }
+        info!("{}", self.summary());
+    }
+
+    fn sample(&mut self, now: Instant) {
+        self.rates = self.handle.rates();
+        self.eta = self.handle.eta();
+        let peers = self.handle.peers();
+        self.peers.retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+        for peer in peers {
+            let (download, upload) = self.peers.entry(peer.addr).or_default();
+            download.record(now, peer.downloaded);
+            upload.record(now, peer.uploaded);
+        }
+    }
+
+    // One line for logs
+    fn summary(&self) -> String {
+        let progress = self.handle.progress();
+        format!(
+            "{}: {} ({}/{} pieces), {} of {}, {} down, {} up, {} peers, ETA {}",
+            self.handle.torrent().info.name,
+            format_percent(progress.downloaded, progress.total),
+            progress.pieces_done,
+            progress.num_pieces,
+            format_bytes(progress.downloaded),
+            format_bytes(progress.total),
+            format_rate(self.rates.0),
+            format_rate(self.rates.1),
+            progress.peers,
+            format_eta(self.eta),
+        )
+    }
+
+    // The whole display, cut to fit a terminal of `width` by `height`
+    fn render(&self, (width, height): (usize, usize)) -> String {
+        let progress = self.handle.progress();
+        let mut lines = vec![
+            format!(
+                "{}  {}  {}",
+                self.handle.torrent().info.name,
+                self.handle.state().name(),
+                format_percent(progress.downloaded, progress.total)
+            ),
+            format!("[{}]", piece_map(&self.handle.pieces(), width.saturating_sub(2))),
+            format!(
+                "{} of {}  {}/{} pieces  down {}  up {}  ETA {}",
+                format_bytes(progress.downloaded),
+                format_bytes(progress.total),
+                progress.pieces_done,
+                progress.num_pieces,
+                format_rate(self.rates.0),
+                format_rate(self.rates.1),
+                format_eta(self.eta),
+            ),
+            format!("Tracker: {}", self.tracker),
+            format!("Peers: {}", progress.peers),
+        ];
+
+        let mut peers: Vec<(PeerInfo, u64, u64)> = self
+            .handle
+            .peers()
+            .into_iter()
+            .map(|peer| {
+                let (download, upload) = self.peers.get(&peer.addr).map_or((0, 0), |(down, up)| (down.rate(), up.rate()));
+                (peer, download, upload)
+            })
+            .collect();
+        // Busiest first
+        peers.sort_by_key(|(peer, download, upload)| (std::cmp::Reverse(download + upload), peer.addr));
+        // Room for the list, less a line for saying how many didn't fit
+        let room = MAX_PEER_LINES.min(height.saturating_sub(HEADER_LINES + 1));
+        let shown = if peers.len() > room { room.saturating_sub(1) } else { peers.len() };
+        for (peer, download, upload) in &peers[..shown] {
+            lines.push(format!(
+                "  {:<21} {:<20} down {:>11}  up {:>11}{}",
+                peer.addr,
+                peer.client,
+                format_rate(*download),
+                format_rate(*upload),
+                if peer.incoming { "  incoming" } else { "" }
+            ));
+        }
+        if shown < peers.len() {
+            lines.push(format!("  and {} more", peers.len() - shown));
+        }
+
+        let mut text = String::new();
+        for line in lines {
+            let _ = writeln!(text, "{}", line.chars().take(width).collect::<String>());
+        }
+        text
+    }
+}
+
+fn terminal_size() -> (usize, usize) {
+    crossterm::terminal::size().map_or((80, 24), |(width, height)| (width as usize, height as usize))
+}
+
+// Squeezes which pieces we have into `width` cells: full when every piece
+// in the cell is done, shaded when some are
+pub fn piece_map(pieces: &[bool], width: usize) -> String {
+    if pieces.is_empty() {
+        return String::new();
+    }
+    let cells = width.min(pieces.len()).max(1);
+    (0..cells)
+        .map(|cell| {
+            let range = &pieces[cell * pieces.len() / cells..(cell + 1) * pieces.len() / cells];
+            match range.iter().filter(|have| **have).count() {
+                0 => '·',
+                done if done == range.len() => '█',
+                _ => '▒',
+            }
+        })
+        .collect()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
+
+pub fn format_percent(done: u64, total: u64) -> String {
+    match total {
+        0 => "100.0%".to_string(),
+        total => format!("{:.1}%", done as f64 * 100.0 / total as f64),
+    }
+}
+
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else { return "unknown".to_string() };
+    let secs = eta.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_map() {
+        let pieces = [true, true, true, false, false, false, true, false];
+        assert_eq!(piece_map(&pieces, 8), "███···█·");
+        assert_eq!(piece_map(&pieces, 4), "█▒·▒");
+        assert_eq!(piece_map(&pieces, 100), "███···█·");
+        assert_eq!(piece_map(&[], 10), "");
+    }
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(format_eta(None), "unknown");
+        assert_eq!(format_eta(Some(Duration::from_secs(75))), "1:15");
+        assert_eq!(format_eta(Some(Duration::from_secs(3725))), "1:02:05");
+    }
+}
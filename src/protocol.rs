@@ -1,150 +1,240 @@
 use anyhow::{anyhow, Result};
 use sha1::Digest;
-use std::{io::{Read, Write}, net::{SocketAddrV4, TcpStream}};
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{trace, Level};
 
-use crate::types::TrackerResponse;
+use crate::log::WIRE_TARGET;
 
+// The block size we ask for by default, and the largest we ask for or serve
 pub const CHUNK_LEN: u32 = 16_384;
+// Largest message we accept, a piece message carrying a full chunk
+pub const MAX_MESSAGE_LEN: usize = CHUNK_LEN as usize + 9;
 
-pub fn urlencode_u8_slice(slice: &[u8]) -> String {
-    let mut escaped_slice = String::with_capacity(slice.len() * 3);
-    for byte in slice {
-        escaped_slice.push_str(format!("%{:02X}", byte).as_str());
-    }
-    escaped_slice
+pub const MSG_CHOKE: u8 = 0;
+pub const MSG_UNCHOKE: u8 = 1;
+pub const MSG_INTERESTED: u8 = 2;
+pub const MSG_NOT_INTERESTED: u8 = 3;
+pub const MSG_HAVE: u8 = 4;
+pub const MSG_BITFIELD: u8 = 5;
+pub const MSG_REQUEST: u8 = 6;
+pub const MSG_PIECE: u8 = 7;
+pub const MSG_CANCEL: u8 = 8;
+
+// What peer messages are read from and written to: a socket, a socket
+// being captured or a recording being replayed
+pub trait PeerStream: Read + Write {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    // How long reads wait for the peer before failing, None for forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
 }
 
-pub fn get_peers_from_tracker(announce_url: String, info_hash: &[u8; 20], left: usize) -> Result<Vec<SocketAddrV4>> {
-    // Build url with query parameters
-    let mut url = announce_url;
-    if url.contains('?') {
-        url.push('&');
-    } else {
-        url.push('?');
+impl PeerStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
     }
-    url.push_str(format!("info_hash={}", urlencode_u8_slice(info_hash)).as_str());
-    url.push_str("&peer_id=00112233445566778899");
-    url.push_str("&port=6881");
-    url.push_str("&uploaded=0");
-    url.push_str("&downloaded=0");
-    url.push_str(format!("&left={}", left).as_str());
-    url.push_str("&compact=1");
-
-    let response = reqwest::blocking::get(&url)?.bytes()?;
-    let response: TrackerResponse = serde_bencode::from_bytes(&response)?;
-
-    Ok(response.peers.0)
-}
-
-pub fn perform_handshake_with_peer(stream: &mut TcpStream, info_hash: &[u8; 20]) -> Result<Vec<u8>> {
-    stream.write_all(&[19])?;
-    stream.write_all(b"BitTorrent protocol")?;
-    stream.write_all(&[0; 8])?;
-    stream.write_all(info_hash)?;
-    stream.write_all(b"01234567890123456789")?;
-    let mut handshake = [0; 68];
-    stream.read_exact(&mut handshake)?;
-    if info_hash != &handshake[28..48] {
-        return Err(anyhow!("Peer sent wrong info hash"));
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
     }
+}
 
-    Ok(handshake[48..68].to_vec())
+// What the remote side told us about itself in its handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub peer_id: [u8; 20],
 }
 
-pub fn wait_for_bitfield(stream: &mut TcpStream) -> Result<Vec<u8>> {
-    let mut buf = [0u8; 4];
-    stream.read_exact(&mut buf)?;
-    let len = u32::from_be_bytes(buf) as usize;
-    if len == 0 {
-        return Err(anyhow!("Peer closed connection"));
+// A piece whose data didn't match its hash, told apart from other download
+// errors so callers can count it against the peer. The data is kept to find
+// out which blocks were bad once a good copy turns up.
+#[derive(Debug, Error)]
+#[error("Piece hash mismatch")]
+pub struct HashMismatch {
+    pub data: Vec<u8>,
+}
+
+pub fn perform_handshake_with_peer(stream: &mut impl PeerStream, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<Handshake> {
+    send_handshake(stream, info_hash, peer_id)?;
+    let (their_info_hash, handshake) = read_handshake(stream)?;
+    if info_hash != &their_info_hash {
+        return Err(anyhow!("Peer sent wrong info hash"));
     }
-    let mut buf = vec!(0u8; len);
+    Ok(handshake)
+}
+
+pub fn send_handshake(stream: &mut impl PeerStream, info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Result<()> {
+    let mut buf = [0u8; 68];
+    buf[0] = 19;
+    buf[1..20].copy_from_slice(b"BitTorrent protocol");
+    buf[28..48].copy_from_slice(info_hash);
+    buf[48..68].copy_from_slice(peer_id);
+    stream.write_all(&buf)?;
+    trace_handshake(stream, "sent", info_hash, &buf[48..68]);
+    Ok(())
+}
+
+// Reads the other side's handshake, returning the info hash it's for
+pub fn read_handshake(stream: &mut impl PeerStream) -> Result<([u8; 20], Handshake)> {
+    let mut buf = [0; 68];
     stream.read_exact(&mut buf)?;
-    if buf[0] != 5 {
-        return Err(anyhow!("Expected bitfield, got message with id {}", buf[4]));
+    if buf[0] != 19 || &buf[1..20] != b"BitTorrent protocol" {
+        return Err(anyhow!("Peer does not speak the BitTorrent protocol"));
     }
-    Ok(buf[1..].to_vec())
+
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&buf[28..48]);
+    let mut handshake = Handshake { reserved: [0; 8], peer_id: [0; 20] };
+    handshake.reserved.copy_from_slice(&buf[20..28]);
+    handshake.peer_id.copy_from_slice(&buf[48..68]);
+    trace_handshake(stream, "received", &info_hash, &handshake.peer_id);
+    Ok((info_hash, handshake))
 }
 
-pub fn send_am_interested(stream: &mut TcpStream) -> Result<()> {
-    let mut buf = [0u8; 5];
-    buf[0..4].copy_from_slice(1u32.to_be_bytes().as_ref());
-    buf[4] = 2;
+pub fn send_message(stream: &mut impl PeerStream, id: u8, payload: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    buf.push(id);
+    buf.extend_from_slice(payload);
     stream.write_all(&buf)?;
+    trace_message(stream, "sent", Some(id), payload);
     Ok(())
 }
 
-pub fn wait_for_unchoke(stream: &mut TcpStream) -> Result<()> {
-    let mut buf = [0u8; 4];
-    stream.read_exact(&mut buf)?;
-    let len = u32::from_be_bytes(buf) as usize;
+// Reads one length-prefixed message into buf, returning its id, or None for
+// a keep-alive. The payload is left in buf[1..len].
+pub fn read_message(stream: &mut impl PeerStream, buf: &mut [u8]) -> Result<Option<(u8, usize)>> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
     if len == 0 {
-        return Err(anyhow!("Peer closed connection"));
+        trace_message(stream, "received", None, &[]);
+        return Ok(None);
     }
-    let mut buf = vec!(0u8; len);
-    stream.read_exact(&mut buf)?;
-    if buf[0] != 1 {
-        return Err(anyhow!("Expected unchoke, got message with id {}", buf[4]));
+    if len > buf.len() {
+        return Err(anyhow!("Peer sent message of {} bytes, max is {}", len, buf.len()));
     }
+    stream.read_exact(&mut buf[..len])?;
+    trace_message(stream, "received", Some(buf[0]), &buf[1..len]);
+    Ok(Some((buf[0], len)))
+}
+
+pub fn send_keep_alive(stream: &mut impl PeerStream) -> Result<()> {
+    stream.write_all(&[0; 4])?;
+    trace_message(stream, "sent", None, &[]);
     Ok(())
 }
 
-pub fn download_piece(stream: &mut TcpStream, piece_index: u32, piece_length: u32, piece_hash: &[u8; 20]) -> Result<Vec<u8>> {
-    let mut piece: Vec<u8> = vec![0u8; piece_length as usize];
-    let whole_chunks: u32 = piece_length / CHUNK_LEN;
-    let last_chunk_len: u32 = piece_length % CHUNK_LEN;
+pub fn wait_for_bitfield(stream: &mut impl PeerStream) -> Result<Vec<u8>> {
+    // A bitfield may be as large as the torrent has pieces
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        match read_message(stream, &mut buf)? {
+            None => continue,
+            Some((MSG_BITFIELD, len)) => return Ok(buf[1..len].to_vec()),
+            Some((id, _)) => return Err(anyhow!("Expected bitfield, got message with id {}", id)),
+        }
+    }
+}
+
+pub fn send_am_interested(stream: &mut impl PeerStream) -> Result<()> {
+    send_message(stream, MSG_INTERESTED, &[])
+}
 
+pub fn wait_for_unchoke(stream: &mut impl PeerStream) -> Result<()> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    loop {
+        match read_message(stream, &mut buf)? {
+            Some((MSG_UNCHOKE, _)) => return Ok(()),
+            // Keep-alives, haves and the like can arrive before we're unchoked
+            Some((MSG_CHOKE | MSG_INTERESTED | MSG_NOT_INTERESTED | MSG_HAVE | MSG_CANCEL, _)) | None => continue,
+            Some((id, _)) => return Err(anyhow!("Expected unchoke, got message with id {}", id)),
+        }
+    }
+}
+
+fn send_requests(stream: &mut impl PeerStream, piece_index: u32, piece_length: u32, block_len: u32, received: &[bool]) -> Result<()> {
     let mut buf = [0u8; 17];
     // Static portion of the request buffer
     buf[0..4].copy_from_slice(13u32.to_be_bytes().as_ref());
-    buf[4] = 6;
+    buf[4] = MSG_REQUEST;
     buf[5..9].copy_from_slice(piece_index.to_be_bytes().as_ref());
 
-    // Send chunk requests
-    if whole_chunks > 0 {
-        for i in 0..whole_chunks {
-            buf[9..13].copy_from_slice((i * CHUNK_LEN).to_be_bytes().as_ref());
-            buf[13..17].copy_from_slice(CHUNK_LEN.to_be_bytes().as_ref());
-            stream.write_all(&buf)?;
-        }
-    }
-    if last_chunk_len > 0 {
-        buf[9..13].copy_from_slice((whole_chunks * CHUNK_LEN).to_be_bytes().as_ref());
-        buf[13..17].copy_from_slice(last_chunk_len.to_be_bytes().as_ref());
+    for (i, _) in received.iter().enumerate().filter(|(_, received)| !**received) {
+        let begin = i as u32 * block_len;
+        let length = std::cmp::min(block_len, piece_length - begin);
+        buf[9..13].copy_from_slice(begin.to_be_bytes().as_ref());
+        buf[13..17].copy_from_slice(length.to_be_bytes().as_ref());
         stream.write_all(&buf)?;
+        trace_message(stream, "sent", Some(MSG_REQUEST), &buf[5..17]);
     }
+    Ok(())
+}
+
+// Downloads and verifies a piece, asking for it in blocks of `block_len`
+// bytes, which is at most CHUNK_LEN
+pub fn download_piece(
+    stream: &mut impl PeerStream,
+    piece_index: u32,
+    piece_length: u32,
+    piece_hash: &[u8; 20],
+    block_len: u32,
+) -> Result<Vec<u8>> {
+    let mut piece: Vec<u8> = vec![0u8; piece_length as usize];
+    let num_chunks = piece_length.div_ceil(block_len) as usize;
+    let mut received = vec![false; num_chunks];
+
+    // Send chunk requests
+    send_requests(stream, piece_index, piece_length, block_len, &received)?;
 
     // Receive chunks
-    let chunks_to_receive = if last_chunk_len > 0 { whole_chunks + 1 } else { whole_chunks };
-    let mut recv_buf: Vec<u8> = vec![0u8; (CHUNK_LEN + 9) as usize];
-    for _ in 0..chunks_to_receive {
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf)?;
-        let len = u32::from_be_bytes(buf);
-        if len == 0 {
-            return Err(anyhow!("Peer closed connection"));
-        }
-        stream.read_exact(&mut recv_buf[0..(len as usize)])?;
-        if recv_buf[0] != 7 {
-            return Err(anyhow!("Expected piece, got message with id {}", recv_buf[0]));
+    let mut chunks_to_receive = num_chunks;
+    let mut recv_buf: Vec<u8> = vec![0u8; MAX_MESSAGE_LEN];
+    while chunks_to_receive > 0 {
+        let len = match read_message(stream, &mut recv_buf)? {
+            Some((MSG_PIECE, len)) => len as u32,
+            Some((MSG_CHOKE, _)) => {
+                // The peer drops our outstanding requests when it chokes us,
+                // ask again for whatever is missing once we're unchoked
+                wait_for_unchoke(stream)?;
+                send_requests(stream, piece_index, piece_length, block_len, &received)?;
+                continue;
+            }
+            Some((MSG_UNCHOKE | MSG_INTERESTED | MSG_NOT_INTERESTED | MSG_HAVE | MSG_CANCEL, _)) | None => continue,
+            Some((id, _)) => return Err(anyhow!("Expected piece, got message with id {}", id)),
+        };
+        if len < 9 {
+            return Err(anyhow!("Piece message too short: {} bytes", len));
         }
-        if u32::from_be_bytes([recv_buf[1], recv_buf[2], recv_buf[3], recv_buf[4]]) != piece_index as u32 {
+        if u32::from_be_bytes([recv_buf[1], recv_buf[2], recv_buf[3], recv_buf[4]]) != piece_index {
             return Err(anyhow!("Expected piece with index {}, got {}", piece_index, u32::from_be_bytes([recv_buf[1], recv_buf[2], recv_buf[3], recv_buf[4]])));
         }
         let chunk_len = len - 9;
-        if chunk_len > CHUNK_LEN {
-            return Err(anyhow!("Received chunk with length {}, but max chunk length is {}", chunk_len, CHUNK_LEN));
+        if chunk_len > block_len {
+            return Err(anyhow!("Received chunk with length {}, but max chunk length is {}", chunk_len, block_len));
         }
         let chunk_index = u32::from_be_bytes([recv_buf[5], recv_buf[6], recv_buf[7], recv_buf[8]]);
-        if chunk_index % CHUNK_LEN != 0 {
-            return Err(anyhow!("Expected chunk with index {} to be a multiple of {}, but it's not", chunk_index, CHUNK_LEN));
+        if !chunk_index.is_multiple_of(block_len) {
+            return Err(anyhow!("Expected chunk with index {} to be a multiple of {}, but it's not", chunk_index, block_len));
         }
         if chunk_index + chunk_len > piece_length {
             return Err(anyhow!("Expected chunk with index {} and length {} to fit in piece of length {}", chunk_index, chunk_len, piece_length));
         }
-        if chunk_len < CHUNK_LEN && (chunk_index + chunk_len) != piece_length {
+        if chunk_len < block_len && (chunk_index + chunk_len) != piece_length {
             return Err(anyhow!("Received chunk with length {}, but it's not the last chunk", chunk_len));
         }
+        // A chunk we asked for twice around a choke may arrive twice
+        let chunk = (chunk_index / block_len) as usize;
+        if received[chunk] {
+            continue;
+        }
+        received[chunk] = true;
+        chunks_to_receive -= 1;
 
         let chunk_len = chunk_len as usize;
         let offset = chunk_index as usize;
@@ -156,8 +246,55 @@ pub fn download_piece(stream: &mut TcpStream, piece_index: u32, piece_length: u3
     hasher.update(&piece);
     let new_piece_hash = hasher.finalize();
     if new_piece_hash.as_slice() != piece_hash {
-        return Err(anyhow!("Piece hash mismatch"));
+        return Err(HashMismatch { data: piece }.into());
     }
 
     Ok(piece)
 }
+
+fn message_name(id: u8) -> &'static str {
+    match id {
+        MSG_CHOKE => "choke",
+        MSG_UNCHOKE => "unchoke",
+        MSG_INTERESTED => "interested",
+        MSG_NOT_INTERESTED => "not interested",
+        MSG_HAVE => "have",
+        MSG_BITFIELD => "bitfield",
+        MSG_REQUEST => "request",
+        MSG_PIECE => "piece",
+        MSG_CANCEL => "cancel",
+        _ => "unknown",
+    }
+}
+
+// Logs a peer message for the wire trace: its type and, for the ones about
+// pieces, which block of which piece. `id` is None for keep-alives.
+fn trace_message(stream: &impl PeerStream, direction: &str, id: Option<u8>, payload: &[u8]) {
+    if !tracing::enabled!(target: WIRE_TARGET, Level::TRACE) {
+        return;
+    }
+    let peer = stream.peer_addr().map_or("unknown".to_string(), |addr| addr.to_string());
+    let field = |at: usize| payload.get(at..at + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    match id {
+        None => trace!(target: WIRE_TARGET, peer, direction, "keep-alive"),
+        Some(id @ (MSG_REQUEST | MSG_CANCEL)) if payload.len() == 12 => {
+            let (index, begin, length) = (field(0), field(4), field(8));
+            trace!(target: WIRE_TARGET, peer, direction, index, begin, length, "{}", message_name(id))
+        }
+        Some(MSG_PIECE) if payload.len() >= 8 => {
+            let (index, begin, length) = (field(0), field(4), payload.len() - 8);
+            trace!(target: WIRE_TARGET, peer, direction, index, begin, length, "piece")
+        }
+        Some(MSG_HAVE) if payload.len() == 4 => trace!(target: WIRE_TARGET, peer, direction, index = field(0), "have"),
+        Some(id) => trace!(target: WIRE_TARGET, peer, direction, id, length = payload.len(), "{}", message_name(id)),
+    }
+}
+
+fn trace_handshake(stream: &impl PeerStream, direction: &str, info_hash: &[u8], peer_id: &[u8]) {
+    if !tracing::enabled!(target: WIRE_TARGET, Level::TRACE) {
+        return;
+    }
+    let peer = stream.peer_addr().map_or("unknown".to_string(), |addr| addr.to_string());
+    let (info_hash, peer_id) = (hex::encode(info_hash), String::from_utf8_lossy(peer_id));
+    trace!(target: WIRE_TARGET, peer, direction, info_hash, %peer_id, "handshake");
+}
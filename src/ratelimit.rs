@@ -0,0 +1,371 @@
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::protocol::PeerStream;
+
+// A token bucket shared by everything drawing from the same limit. Callers
+// take what they need up front and sleep off any debt, so a single transfer
+// larger than the burst still goes through at the configured rate.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Bytes per second, None for unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let tokens = rate.unwrap_or(0) as f64;
+        RateLimiter { bucket: Mutex::new(Bucket { rate, tokens, last: Instant::now() }) }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner()).rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or(0) as f64);
+        bucket.last = Instant::now();
+    }
+
+    // Blocks until `bytes` may be transferred
+    pub fn acquire(&self, bytes: usize) {
+        acquire_all([self], bytes);
+    }
+
+    // Takes `bytes` from the bucket, returning how long to wait for them
+    fn take(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(rate) = bucket.rate.filter(|&rate| rate > 0) else { return Duration::ZERO };
+        let now = Instant::now();
+        // At most a second's worth of burst
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate as f64).min(rate as f64);
+        bucket.last = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-bucket.tokens / rate as f64)
+    }
+}
+
+// Blocks until `bytes` may be transferred under every one of the limiters.
+// They refill at the same time, so the slowest one decides.
+pub fn acquire_all<'a>(limiters: impl IntoIterator<Item = &'a RateLimiter>, bytes: usize) {
+    let wait = limiters.into_iter().map(|limiter| limiter.take(bytes)).max().unwrap_or_default();
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+// A download and an upload limit at one level: the whole session, one
+// torrent or one peer connection
+#[derive(Debug)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        RateLimits { download: RateLimiter::new(download), upload: RateLimiter::new(upload) }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    // Current (download, upload) limits in bytes per second
+    pub fn rates(&self) -> (Option<u64>, Option<u64>) {
+        (self.download.rate(), self.upload.rate())
+    }
+
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+}
+
+// The limits every peer connection gets a bucket of its own for. Changing
+// them changes the buckets of connections that are already open too.
+#[derive(Debug)]
+pub struct PeerLimits {
+    rates: Mutex<(Option<u64>, Option<u64>)>,
+    peers: Mutex<Vec<Weak<RateLimits>>>,
+}
+
+impl PeerLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        PeerLimits { rates: Mutex::new((download, upload)), peers: Mutex::new(Vec::new()) }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn rates(&self) -> (Option<u64>, Option<u64>) {
+        *self.rates.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        *self.rates.lock().unwrap_or_else(|e| e.into_inner()) = (download, upload);
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.retain(|peer| peer.upgrade().inspect(|peer| peer.set(download, upload)).is_some());
+    }
+
+    // A new bucket for a connection, dropped along with it
+    pub fn for_peer(&self) -> Arc<RateLimits> {
+        let (download, upload) = self.rates();
+        let limits = Arc::new(RateLimits::new(download, upload));
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.retain(|peer| peer.strong_count() > 0);
+        peers.push(Arc::downgrade(&limits));
+        limits
+    }
+}
+
+// A peer stream whose reads draw from the download limit and writes from
+// the upload limit of every level it's under. Bytes are paid for once
+// they've gone through, so a stalled peer never holds up the others.
+pub struct Throttled<S> {
+    stream: S,
+    levels: Vec<Arc<RateLimits>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(stream: S, levels: Vec<Arc<RateLimits>>) -> Self {
+        Throttled { stream, levels }
+    }
+
+    // Puts the stream under another level of limits, like its torrent's once
+    // an incoming peer has said which torrent it wants
+    pub fn limit_by(&mut self, limits: Arc<RateLimits>) {
+        self.levels.push(limits);
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        acquire_all(self.levels.iter().map(|limits| &limits.download), read);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        acquire_all(self.levels.iter().map(|limits| &limits.upload), written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: PeerStream> PeerStream for Throttled<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+// A local time of day to the minute, written like "23:30"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn new(hour: u16, minute: u16) -> Self {
+        TimeOfDay(hour % 24 * 60 + minute % 60)
+    }
+
+    // The local time now, or UTC where we can't tell the time zone
+    pub fn now() -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        #[cfg(unix)]
+        {
+            let time = now as libc::time_t;
+            // SAFETY: localtime_r only writes the struct it's handed, and an
+            // all zero tm is a valid one
+            let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+            if !unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+                return TimeOfDay::new(tm.tm_hour as u16, tm.tm_min as u16);
+            }
+        }
+        TimeOfDay((now % 86_400 / 60) as u16)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let (hour, minute) = s.split_once(':').filter(|(hour, minute)| hour.len() <= 2 && minute.len() == 2)?;
+            let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+            (hour < 24 && minute < 60).then(|| TimeOfDay::new(hour, minute))
+        };
+        parse().ok_or_else(|| anyhow!("Expected a time of day like 23:30, got {:?}", s))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+// Session limits for part of every day. Rates are bytes per second, 0 for
+// unlimited; a rate left out stays as it is outside the rule.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleRule {
+    pub from: TimeOfDay,
+    // Exclusive, and before `from` for rules that run past midnight
+    pub to: TimeOfDay,
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+}
+
+impl ScheduleRule {
+    fn covers(&self, time: TimeOfDay) -> bool {
+        match self.from < self.to {
+            true => self.from <= time && time < self.to,
+            // A rule from a time to the same time covers the whole day
+            false => self.from <= time || time < self.to,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Schedule(pub Vec<ScheduleRule>);
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // The (download, upload) limits at `time` for a session whose limits are
+    // otherwise `base`. The first rule covering the time wins.
+    pub fn limits_at(&self, time: TimeOfDay, base: (Option<u64>, Option<u64>)) -> (Option<u64>, Option<u64>) {
+        let Some(rule) = self.0.iter().find(|rule| rule.covers(time)) else { return base };
+        let rate = |rate: Option<u64>, base| rate.map_or(base, |rate| Some(rate).filter(|&rate| rate > 0));
+        (rate(rule.download_rate, base.0), rate(rule.upload_rate, base.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_rate() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let started = Instant::now();
+        // The first second is burst, the rest has to wait
+        for _ in 0..15 {
+            limiter.acquire(10_000);
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        let limiter = RateLimiter::unlimited();
+        let started = Instant::now();
+        limiter.acquire(usize::MAX);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        limiter.set_rate(Some(1));
+        assert_eq!(limiter.rate(), Some(1));
+    }
+
+    #[test]
+    fn test_throttled_stream_obeys_the_slowest_level() {
+        let (session, torrent) = (Arc::new(RateLimits::unlimited()), Arc::new(RateLimits::new(Some(50_000), None)));
+        let peers = PeerLimits::unlimited();
+        let mut stream = Throttled::new(io::Cursor::new(vec![0u8; 100_000]), vec![session, torrent, peers.for_peer()]);
+        let started = Instant::now();
+        io::copy(&mut stream, &mut io::sink()).unwrap();
+        // A second of burst, then a second for the other half
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2000), "{:?}", elapsed);
+
+        // Writes only count against upload limits
+        let started = Instant::now();
+        stream.write_all(&[0; 100_000]).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_peer_limits_follow_changes() {
+        let peers = PeerLimits::new(Some(1000), None);
+        let first = peers.for_peer();
+        peers.set(None, Some(500));
+        assert_eq!(first.rates(), (None, Some(500)));
+        assert_eq!(peers.for_peer().rates(), (None, Some(500)));
+        drop(first);
+        peers.set(Some(1), Some(1));
+        assert!(peers.peers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let time = |s: &str| s.parse::<TimeOfDay>().unwrap();
+        let rule = |from: &str, to: &str, download_rate, upload_rate| ScheduleRule { from: time(from), to: time(to), download_rate, upload_rate };
+        // Unlimited downloads at night, slower uploads over lunch
+        let schedule = Schedule(vec![rule("23:00", "07:00", Some(0), None), rule("12:00", "13:30", None, Some(100))]);
+        let base = (Some(1000), Some(500));
+        assert_eq!(schedule.limits_at(time("23:00"), base), (None, Some(500)));
+        assert_eq!(schedule.limits_at(time("03:15"), base), (None, Some(500)));
+        assert_eq!(schedule.limits_at(time("07:00"), base), base);
+        assert_eq!(schedule.limits_at(time("13:29"), base), (Some(1000), Some(100)));
+        assert_eq!(schedule.limits_at(time("13:30"), base), base);
+
+        assert_eq!(time("7:05").to_string(), "07:05");
+        for bad in ["24:00", "12:60", "noon", "12:5:0", "12:005"] {
+            assert!(bad.parse::<TimeOfDay>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// Rates are averaged over this long
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// Bytes per second from samples of a growing byte count
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    pub fn record(&mut self, now: Instant, total: u64) {
+        // A count that went down started over, like a peer's does on a new
+        // connection, so nothing before it applies
+        if self.samples.back().is_some_and(|last| total < last.1) {
+            self.samples.clear();
+        }
+        self.samples.push_back((now, total));
+        // Keep one sample from before the window so it's always covered
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else { return 0 };
+        let elapsed = last.0.duration_since(first.0).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        ((last.1 - first.1) as f64 / elapsed) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let mut meter = RateMeter::default();
+        assert_eq!(meter.rate(), 0);
+        for secs in 0..=4 {
+            meter.record(start + Duration::from_secs(secs), secs * 1000);
+        }
+        assert_eq!(meter.rate(), 1000);
+        // Once nothing has arrived for a whole window the rate drops to 0
+        for secs in 5..=10 {
+            meter.record(start + Duration::from_secs(secs), 4000);
+        }
+        assert_eq!(meter.rate(), 0);
+        // The peer reconnected between samples and counts from zero again
+        meter.record(start + Duration::from_secs(11), 500);
+        assert_eq!(meter.rate(), 0);
+        meter.record(start + Duration::from_secs(12), 2500);
+        assert_eq!(meter.rate(), 2000);
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    net::{IpAddr, TcpListener},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
+
+mod transmission;
+
+pub use self::transmission::{TransmissionRpc, TRANSMISSION_PATH};
+
+use crate::{
+    http_server::{self, Request, Response},
+    session::{Session, TorrentHandle, TorrentState},
+    storage::Storage,
+    types::Torrent,
+};
+
+pub const RPC_PORT: u16 = 6882;
+pub const RPC_PATH: &str = "/rpc";
+
+// Error codes from the JSON-RPC 2.0 spec, plus one for everything the session
+// itself refuses
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SESSION_ERROR: i64 = -32000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[error("{message}")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError::new(SESSION_ERROR, format!("{:#}", e))
+    }
+}
+
+// A torrent as the API reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub output: PathBuf,
+    pub state: String,
+    // Why the torrent failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub pieces_done: usize,
+    pub num_pieces: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub total: u64,
+    pub peers: usize,
+    // The torrent's own limits in bytes per second, None for unlimited
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+}
+
+impl TorrentStatus {
+    pub fn new(handle: &TorrentHandle) -> Self {
+        let (state, progress) = (handle.state(), handle.progress());
+        let (download_rate, upload_rate) = handle.rate_limits();
+        TorrentStatus {
+            info_hash: hex::encode(handle.info_hash()),
+            name: handle.torrent().info.name.clone(),
+            output: handle.output().to_path_buf(),
+            state: state.name().to_string(),
+            error: match state {
+                TorrentState::Failed(error) => Some(error),
+                _ => None,
+            },
+            pieces_done: progress.pieces_done,
+            num_pieces: progress.num_pieces,
+            downloaded: progress.downloaded,
+            uploaded: progress.uploaded,
+            total: progress.total,
+            peers: progress.peers,
+            download_rate,
+            upload_rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub listen_port: u16,
+    // Bytes per second, None for unlimited
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    // For each peer connection on its own
+    pub peer_download_rate: Option<u64>,
+    pub peer_upload_rate: Option<u64>,
+    pub torrents: usize,
+    // Peers caught sending corrupt data
+    pub banned_peers: Vec<IpAddr>,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddParams {
+    // The .torrent file's contents, base64 encoded
+    metainfo: Option<String>,
+    // Or a .torrent file on the daemon's host
+    path: Option<PathBuf>,
+    // Where to download to, <output dir>/<name> by default
+    output: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemoveParams {
+    info_hash: String,
+    #[serde(default)]
+    delete_data: bool,
+}
+
+// Bytes per second, 0 for unlimited; limits left out stay as they are
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitParams {
+    download_rate: Option<u64>,
+    upload_rate: Option<u64>,
+    peer_download_rate: Option<u64>,
+    peer_upload_rate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TorrentLimitParams {
+    info_hash: String,
+    download_rate: Option<u64>,
+    upload_rate: Option<u64>,
+}
+
+// Serves JSON-RPC 2.0 over HTTP POSTs to /rpc, driving a session, and
+// optionally Transmission's protocol on /transmission/rpc. See docs/rpc.md
+// for the methods.
+pub struct RpcServer {
+    session: Arc<Session>,
+    output_dir: PathBuf,
+    transmission: Option<TransmissionRpc>,
+    stop: Arc<AtomicBool>,
+}
+
+impl RpcServer {
+    pub fn new(session: Arc<Session>, output_dir: PathBuf) -> Self {
+        RpcServer { session, output_dir, transmission: None, stop: Arc::new(AtomicBool::new(false)) }
+    }
+
+    // Also answers Transmission clients
+    pub fn with_transmission(mut self) -> Self {
+        self.transmission = Some(TransmissionRpc::new(self.session.clone(), self.output_dir.clone()));
+        self
+    }
+
+    // Answers requests until a client calls session.shutdown
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        let stop = self.stop.clone();
+        http_server::serve_until(listener, Arc::new(move |request| self.handle_http(request)), stop)
+    }
+
+    pub fn handle_http(&self, request: &Request) -> Response {
+        if let Some(transmission) = self.transmission.as_ref().filter(|_| request.path == TRANSMISSION_PATH) {
+            return transmission.handle_http(request);
+        }
+        if request.path != RPC_PATH {
+            return Response::not_found();
+        }
+        if request.method != "POST" {
+            return Response::new(405, "text/plain", b"Method Not Allowed".to_vec());
+        }
+        // Browsers can't send this from another site without a preflight,
+        // see "Browsers" in docs/rpc.md
+        let media_type = request.header("Content-Type").and_then(|value| value.split(';').next()).map(str::trim);
+        if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case("application/json")) {
+            return Response::new(415, "text/plain", b"Unsupported Media Type, expected application/json".to_vec());
+        }
+        let response = match serde_json::from_slice(&request.body) {
+            Ok(request) => self.handle(request),
+            Err(e) => error_response(Value::Null, RpcError::new(PARSE_ERROR, format!("Invalid JSON: {}", e))),
+        };
+        Response::new(200, "application/json", response.to_string().into_bytes())
+    }
+
+    // Answers one JSON-RPC request
+    pub fn handle(&self, request: Value) -> Value {
+        let request: RpcRequest = match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e))),
+        };
+        if request.jsonrpc != "2.0" {
+            return error_response(request.id, RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported"));
+        }
+        match self.call(&request.method, request.params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": request.id }),
+            Err(error) => error_response(request.id, error),
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let session = &self.session;
+        let result = match method {
+            "torrent.add" => to_value(self.add(parse_params(params)?)?),
+            "torrent.list" => to_value(session.torrents().iter().map(TorrentStatus::new).collect::<Vec<_>>()),
+            "torrent.get" => to_value(TorrentStatus::new(&self.find(parse_params(params)?)?)),
+            "torrent.pause" => {
+                let params: TorrentParams = parse_params(params)?;
+                session.pause(&parse_info_hash(&params.info_hash)?)?;
+                Value::Null
+            }
+            "torrent.resume" => {
+                let params: TorrentParams = parse_params(params)?;
+                session.resume(&parse_info_hash(&params.info_hash)?)?;
+                Value::Null
+            }
+            "torrent.set_limits" => {
+                let params: TorrentLimitParams = parse_params(params)?;
+                let handle = self.find(TorrentParams { info_hash: params.info_hash })?;
+                let (download, upload) = handle.rate_limits();
+                session.set_torrent_rate_limits(&handle.info_hash(), limit(params.download_rate, download), limit(params.upload_rate, upload))?;
+                to_value(TorrentStatus::new(&handle))
+            }
+            "torrent.remove" => {
+                let params: RemoveParams = parse_params(params)?;
+                session.remove(&parse_info_hash(&params.info_hash)?, params.delete_data)?;
+                Value::Null
+            }
+            "session.get" => to_value(self.status()),
+            "session.set_limits" => {
+                let params: LimitParams = parse_params(params)?;
+                let (download, upload) = session.rate_limits();
+                session.set_rate_limits(limit(params.download_rate, download), limit(params.upload_rate, upload));
+                let (download, upload) = session.peer_rate_limits();
+                session.set_peer_rate_limits(limit(params.peer_download_rate, download), limit(params.peer_upload_rate, upload));
+                to_value(self.status())
+            }
+            "session.shutdown" => {
+                self.stop.store(true, Ordering::Relaxed);
+                Value::Null
+            }
+            _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        };
+        Ok(result)
+    }
+
+    fn add(&self, params: AddParams) -> Result<TorrentStatus, RpcError> {
+        let torrent = match (params.metainfo, params.path) {
+            (Some(metainfo), None) => {
+                let bytes = BASE64.decode(metainfo).map_err(|e| RpcError::new(INVALID_PARAMS, format!("Bad metainfo: {}", e)))?;
+                Torrent::from_bytes(&bytes)?
+            }
+            (None, Some(path)) => Torrent::from_bytes(&fs::read(&path).map_err(|e| anyhow!("Can't read {}: {}", path.display(), e))?)?,
+            _ => return Err(RpcError::new(INVALID_PARAMS, "Expected exactly one of metainfo and path")),
+        };
+        let output = match params.output {
+            Some(output) => output,
+            None => Storage::default_path(&torrent.info, &self.output_dir)?,
+        };
+        let handle = match params.paused {
+            true => self.session.add_torrent_paused(torrent, &output)?,
+            false => self.session.add_torrent(torrent, &output)?,
+        };
+        Ok(TorrentStatus::new(&handle))
+    }
+
+    fn find(&self, params: TorrentParams) -> Result<TorrentHandle, RpcError> {
+        let info_hash = parse_info_hash(&params.info_hash)?;
+        self.session
+            .torrent(&info_hash)
+            .ok_or_else(|| RpcError::new(SESSION_ERROR, format!("Torrent {} is not in the session", params.info_hash)))
+    }
+
+    fn status(&self) -> SessionStatus {
+        let (download_rate, upload_rate) = self.session.rate_limits();
+        let (peer_download_rate, peer_upload_rate) = self.session.peer_rate_limits();
+        SessionStatus {
+            listen_port: self.session.listen_addr().port(),
+            download_rate,
+            upload_rate,
+            peer_download_rate,
+            peer_upload_rate,
+            torrents: self.session.torrents().len(),
+            banned_peers: self.session.banned_peers(),
+        }
+    }
+}
+
+// A limit from a request: 0 lifts it and leaving it out keeps `old`
+fn limit(new: Option<u64>, old: Option<u64>) -> Option<u64> {
+    new.map(|rate| Some(rate).filter(|&rate| rate > 0)).unwrap_or(old)
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", e)))
+}
+
+fn to_value<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+pub fn parse_info_hash(info_hash: &str) -> Result<[u8; 20], RpcError> {
+    hex::decode(info_hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Not an info hash: {}", info_hash)))
+}
+
+// Talks to a daemon's RPC endpoint
+pub struct RpcClient {
+    url: String,
+    client: reqwest::blocking::Client,
+    next_id: AtomicU64,
+}
+
+impl RpcClient {
+    pub fn new(url: &str) -> Self {
+        RpcClient { url: url.to_string(), client: reqwest::blocking::Client::new(), next_id: AtomicU64::new(1) }
+    }
+
+    pub fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id });
+        let response: Value = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .map_err(|e| anyhow!("Can't reach the daemon at {}: {}", self.url, e))?
+            .error_for_status()?
+            .json()?;
+        if let Some(error) = response.get("error") {
+            let error: RpcError = serde_json::from_value(error.clone())?;
+            return Err(error.into());
+        }
+        let result = response.get("result").cloned().ok_or_else(|| anyhow!("Daemon sent neither a result nor an error"))?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    pub fn add_torrent(&self, metainfo: &[u8], output: Option<PathBuf>) -> Result<TorrentStatus> {
+        self.call("torrent.add", json!({ "metainfo": BASE64.encode(metainfo), "output": output }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionConfig;
+
+    fn server() -> RpcServer {
+        let session = Session::new(SessionConfig { listen_port: 0, ..Default::default() }).unwrap();
+        RpcServer::new(Arc::new(session), PathBuf::from("."))
+    }
+
+    fn error_code(response: &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn test_rejects_bad_requests() {
+        let server = server();
+        assert_eq!(error_code(&server.handle(json!({ "method": "torrent.list" }))), INVALID_REQUEST);
+        assert_eq!(error_code(&server.handle(json!({ "jsonrpc": "1.0", "method": "torrent.list", "id": 1 }))), INVALID_REQUEST);
+        assert_eq!(error_code(&server.handle(json!({ "jsonrpc": "2.0", "method": "torrent.frob", "id": 1 }))), METHOD_NOT_FOUND);
+
+        let response = server.handle(json!({ "jsonrpc": "2.0", "method": "torrent.pause", "params": { "info_hash": "zz" }, "id": 7 }));
+        assert_eq!(error_code(&response), INVALID_PARAMS);
+        assert_eq!(response["id"], 7);
+        let response = server.handle(json!({ "jsonrpc": "2.0", "method": "torrent.add", "params": {}, "id": 8 }));
+        assert_eq!(error_code(&response), INVALID_PARAMS);
+        let response = server.handle(json!({ "jsonrpc": "2.0", "method": "torrent.add", "params": { "magnet": "magnet:?xt=urn:btih:00" }, "id": 9 }));
+        assert_eq!(error_code(&response), INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let server = server();
+        let request = |params: Value| json!({ "jsonrpc": "2.0", "method": "session.set_limits", "params": params, "id": 1 });
+        let response = server.handle(request(json!({ "download_rate": 1000 })));
+        assert_eq!(response["result"]["download_rate"], 1000);
+        assert_eq!(response["result"]["upload_rate"], Value::Null);
+
+        // Leaving a limit out keeps it, 0 lifts it
+        let response = server.handle(request(json!({ "upload_rate": 500 })));
+        assert_eq!((response["result"]["download_rate"].as_u64(), response["result"]["upload_rate"].as_u64()), (Some(1000), Some(500)));
+        let response = server.handle(request(json!({ "download_rate": 0 })));
+        assert_eq!(response["result"]["download_rate"], Value::Null);
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    http_server::{Request, Response},
+    random::random_u64,
+    session::{Session, TorrentHandle, TorrentState},
+    storage::Storage,
+    types::Torrent,
+};
+
+pub const TRANSMISSION_PATH: &str = "/transmission/rpc";
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+// What we claim to speak, from Transmission 3.00
+const RPC_VERSION: u32 = 16;
+const RPC_VERSION_MINIMUM: u32 = 1;
+// Transmission reports speeds in kB/s
+const SPEED_UNIT: u64 = 1000;
+
+// tr_torrent_activity
+const STATUS_STOPPED: u32 = 0;
+const STATUS_DOWNLOAD_WAIT: u32 = 3;
+const STATUS_DOWNLOAD: u32 = 4;
+const STATUS_SEED: u32 = 6;
+// tr_stat_errtype
+const ERROR_NONE: u32 = 0;
+const ERROR_LOCAL: u32 = 3;
+
+// The subset of Transmission's RPC protocol that tools like web UIs and the
+// *arr apps use, mapped onto our session. Torrents get the small integer ids
+// Transmission clients expect, in the order we first see them.
+pub struct TransmissionRpc {
+    session: Arc<Session>,
+    session_id: String,
+    state: Mutex<State>,
+}
+
+struct State {
+    ids: Vec<[u8; 20]>,
+    download_dir: PathBuf,
+    // Limits in kB/s, remembered while they're switched off like Transmission does
+    speed_limit_down: u64,
+    speed_limit_down_enabled: bool,
+    speed_limit_up: u64,
+    speed_limit_up_enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+    tag: Option<Value>,
+}
+
+impl TransmissionRpc {
+    pub fn new(session: Arc<Session>, download_dir: PathBuf) -> Self {
+        let (download, upload) = session.rate_limits();
+        let state = State {
+            ids: Vec::new(),
+            download_dir,
+            speed_limit_down: download.map_or(100, |rate| rate / SPEED_UNIT),
+            speed_limit_down_enabled: download.is_some(),
+            speed_limit_up: upload.map_or(100, |rate| rate / SPEED_UNIT),
+            speed_limit_up_enabled: upload.is_some(),
+        };
+        let session_id = format!("{:016x}{:016x}", random_u64(), random_u64());
+        TransmissionRpc { session, session_id, state: Mutex::new(state) }
+    }
+
+    // Clients have to echo the session id we hand out with a 409 before we
+    // answer them, as Transmission's own clients expect. See "Browsers" in
+    // docs/rpc.md for why.
+    pub fn handle_http(&self, request: &Request) -> Response {
+        if request.header(SESSION_ID_HEADER) != Some(self.session_id.as_str()) {
+            let mut response = Response::new(409, "text/html", b"<h1>409: Conflict</h1><p>Invalid session id</p>".to_vec());
+            response.headers.push((SESSION_ID_HEADER.to_string(), self.session_id.clone()));
+            return response;
+        }
+        if request.method != "POST" {
+            return Response::new(405, "text/plain", b"Method Not Allowed".to_vec());
+        }
+        let response = match serde_json::from_slice(&request.body) {
+            Ok(request) => self.handle(request),
+            Err(e) => json!({ "result": format!("Invalid JSON: {}", e), "arguments": {} }),
+        };
+        Response::new(200, "application/json", response.to_string().into_bytes())
+    }
+
+    // Answers one request body
+    pub fn handle(&self, request: Value) -> Value {
+        let request: RpcRequest = match serde_json::from_value(request) {
+            Ok(request) => request,
+            Err(e) => return json!({ "result": format!("Invalid request: {}", e), "arguments": {} }),
+        };
+        let (result, arguments) = match self.call(&request.method, &request.arguments) {
+            Ok(arguments) => ("success".to_string(), arguments),
+            Err(e) => (format!("{:#}", e), json!({})),
+        };
+        let mut response = json!({ "result": result, "arguments": arguments });
+        if let Some(tag) = request.tag {
+            response["tag"] = tag;
+        }
+        response
+    }
+
+    fn call(&self, method: &str, arguments: &Map<String, Value>) -> Result<Value> {
+        match method {
+            "torrent-add" => self.torrent_add(arguments),
+            "torrent-get" => self.torrent_get(arguments),
+            "torrent-start" | "torrent-start-now" => {
+                for handle in self.select(arguments)? {
+                    self.session.resume(&handle.info_hash())?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-stop" => {
+                for handle in self.select(arguments)? {
+                    self.session.pause(&handle.info_hash())?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-remove" => {
+                let delete_data = arguments.get("delete-local-data").and_then(Value::as_bool).unwrap_or(false);
+                for handle in self.select(arguments)? {
+                    self.session.remove(&handle.info_hash(), delete_data)?;
+                }
+                Ok(json!({}))
+            }
+            "session-get" => Ok(self.session_get()),
+            "session-set" => self.session_set(arguments),
+            _ => Err(anyhow!("method name not recognized")),
+        }
+    }
+
+    fn torrent_add(&self, arguments: &Map<String, Value>) -> Result<Value> {
+        let bytes = match (arguments.get("metainfo").and_then(Value::as_str), arguments.get("filename").and_then(Value::as_str)) {
+            (Some(metainfo), _) => BASE64.decode(metainfo).map_err(|e| anyhow!("invalid metainfo: {}", e))?,
+            (None, Some(filename)) if filename.starts_with("magnet:") => return Err(anyhow!("magnet links are not supported")),
+            (None, Some(filename)) => fs::read(filename).map_err(|e| anyhow!("can't read {}: {}", filename, e))?,
+            (None, None) => return Err(anyhow!("no filename or metainfo specified")),
+        };
+        let torrent = Torrent::from_bytes(&bytes).map_err(|_| anyhow!("invalid or corrupt torrent file"))?;
+        let info_hash = torrent.info.calculate_info_hash()?;
+        if let Some(handle) = self.session.torrent(&info_hash) {
+            return Ok(json!({ "torrent-duplicate": self.summary(&handle) }));
+        }
+
+        let download_dir = match arguments.get("download-dir").and_then(Value::as_str) {
+            Some(dir) => PathBuf::from(dir),
+            None => self.state().download_dir.clone(),
+        };
+        let output = Storage::default_path(&torrent.info, &download_dir)?;
+        let handle = match arguments.get("paused").and_then(Value::as_bool).unwrap_or(false) {
+            true => self.session.add_torrent_paused(torrent, &output)?,
+            false => self.session.add_torrent(torrent, &output)?,
+        };
+        Ok(json!({ "torrent-added": self.summary(&handle) }))
+    }
+
+    fn torrent_get(&self, arguments: &Map<String, Value>) -> Result<Value> {
+        let fields: Vec<&str> = arguments
+            .get("fields")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("no fields specified"))?
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        let queue = self.session.torrents();
+        let torrents: Vec<Value> = self
+            .select(arguments)?
+            .iter()
+            .map(|handle| {
+                let position = queue.iter().position(|other| other.info_hash() == handle.info_hash());
+                let fields = fields.iter().filter_map(|&field| Some((field.to_string(), self.field(handle, field, position)?)));
+                Value::Object(fields.collect())
+            })
+            .collect();
+        Ok(json!({ "torrents": torrents }))
+    }
+
+    // One torrent-get field, None for the ones we don't know
+    fn field(&self, handle: &TorrentHandle, field: &str, queue_position: Option<usize>) -> Option<Value> {
+        let (state, progress) = (handle.state(), handle.progress());
+        let info = &handle.torrent().info;
+        let value = match field {
+            "id" => json!(self.id(&handle.info_hash())),
+            "hashString" => json!(hex::encode(handle.info_hash())),
+            "name" => json!(info.name),
+            "status" => json!(match state {
+                TorrentState::Queued => STATUS_DOWNLOAD_WAIT,
+                TorrentState::Downloading => STATUS_DOWNLOAD,
+                TorrentState::Seeding => STATUS_SEED,
+                TorrentState::Completed | TorrentState::Paused | TorrentState::Failed(_) => STATUS_STOPPED,
+            }),
+            "error" => json!(if matches!(state, TorrentState::Failed(_)) { ERROR_LOCAL } else { ERROR_NONE }),
+            "errorString" => json!(match &state {
+                TorrentState::Failed(error) => error.as_str(),
+                _ => "",
+            }),
+            "totalSize" | "sizeWhenDone" => json!(progress.total),
+            "leftUntilDone" => json!(progress.total.saturating_sub(progress.downloaded)),
+            "percentDone" => json!(match progress.total {
+                0 => 1.0,
+                total => progress.downloaded as f64 / total as f64,
+            }),
+            "downloadedEver" => json!(progress.downloaded),
+            "uploadedEver" => json!(progress.uploaded),
+            "uploadRatio" => json!(match progress.downloaded {
+                0 => -1.0,
+                downloaded => progress.uploaded as f64 / downloaded as f64,
+            }),
+            "isFinished" => json!(progress.pieces_done == progress.num_pieces),
+            "downloadDir" => json!(handle.output().parent().unwrap_or(handle.output())),
+            "peersConnected" => json!(progress.peers),
+            "pieceCount" => json!(progress.num_pieces),
+            "pieceSize" => json!(info.piece_length),
+            "queuePosition" => json!(queue_position),
+            "rateDownload" => json!(handle.rates().0),
+            "rateUpload" => json!(handle.rates().1),
+            // Transmission uses -1 when there's no ETA
+            "eta" => json!(handle.eta().map_or(-1, |eta| eta.as_secs() as i64)),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // The torrents the request's `ids` pick, all of them if there are none
+    fn select(&self, arguments: &Map<String, Value>) -> Result<Vec<TorrentHandle>> {
+        let torrents = self.session.torrents();
+        let ids = match arguments.get("ids") {
+            None => return Ok(torrents),
+            // We don't track activity, so everything counts as recent
+            Some(Value::String(ids)) if ids == "recently-active" => return Ok(torrents),
+            Some(Value::Array(ids)) => ids.clone(),
+            Some(id) => vec![id.clone()],
+        };
+
+        // Torrents restored from the state dir or added over the other RPC
+        // haven't been given an id yet, number them in queue order first
+        for handle in &torrents {
+            self.id(&handle.info_hash());
+        }
+        let mut selected = Vec::new();
+        for id in ids {
+            let handle = match &id {
+                Value::Number(number) => {
+                    let info_hash = number.as_u64().and_then(|id| self.state().ids.get((id as usize).checked_sub(1)?).copied());
+                    info_hash.and_then(|info_hash| self.session.torrent(&info_hash))
+                }
+                Value::String(hash) => hex::decode(hash).ok().and_then(|hash| self.session.torrent(&hash.try_into().ok()?)),
+                _ => return Err(anyhow!("invalid id {}", id)),
+            };
+            // Transmission skips ids it doesn't know about
+            selected.extend(handle);
+        }
+        Ok(selected)
+    }
+
+    fn summary(&self, handle: &TorrentHandle) -> Value {
+        json!({
+            "id": self.id(&handle.info_hash()),
+            "name": handle.torrent().info.name,
+            "hashString": hex::encode(handle.info_hash()),
+        })
+    }
+
+    fn id(&self, info_hash: &[u8; 20]) -> usize {
+        let mut state = self.state();
+        match state.ids.iter().position(|other| other == info_hash) {
+            Some(index) => index + 1,
+            None => {
+                state.ids.push(*info_hash);
+                state.ids.len()
+            }
+        }
+    }
+
+    fn session_get(&self) -> Value {
+        let config = self.session.config();
+        let state = self.state();
+        json!({
+            "version": format!("3.00 ({} {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "download-dir": state.download_dir,
+            "peer-port": self.session.listen_addr().port(),
+            "speed-limit-down": state.speed_limit_down,
+            "speed-limit-down-enabled": state.speed_limit_down_enabled,
+            "speed-limit-up": state.speed_limit_up,
+            "speed-limit-up-enabled": state.speed_limit_up_enabled,
+            "download-queue-enabled": true,
+            "download-queue-size": config.max_active_downloads,
+            "seed-queue-enabled": true,
+            "seed-queue-size": config.max_active_seeds,
+            "units": {
+                "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+                "speed-bytes": SPEED_UNIT,
+                "size-units": ["kB", "MB", "GB", "TB"],
+                "size-bytes": 1000,
+                "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+                "memory-bytes": 1024,
+            },
+        })
+    }
+
+    // Settings we don't have are ignored, like Transmission does for keys it
+    // doesn't know
+    fn session_set(&self, arguments: &Map<String, Value>) -> Result<Value> {
+        let mut state = self.state();
+        if let Some(dir) = arguments.get("download-dir") {
+            state.download_dir = PathBuf::from(dir.as_str().ok_or_else(|| anyhow!("download-dir must be a string"))?);
+        }
+        let number = |key: &str| arguments.get(key).map(|value| value.as_u64().ok_or_else(|| anyhow!("{} must be a number", key))).transpose();
+        // In kB/s, which has to fit in bytes per second
+        let speed = |key: &str| match number(key)? {
+            Some(limit) if limit.checked_mul(SPEED_UNIT).is_none() => Err(anyhow!("{} is too large", key)),
+            limit => Ok(limit),
+        };
+        let flag = |key: &str| arguments.get(key).map(|value| value.as_bool().ok_or_else(|| anyhow!("{} must be a boolean", key))).transpose();
+        state.speed_limit_down = speed("speed-limit-down")?.unwrap_or(state.speed_limit_down);
+        state.speed_limit_down_enabled = flag("speed-limit-down-enabled")?.unwrap_or(state.speed_limit_down_enabled);
+        state.speed_limit_up = speed("speed-limit-up")?.unwrap_or(state.speed_limit_up);
+        state.speed_limit_up_enabled = flag("speed-limit-up-enabled")?.unwrap_or(state.speed_limit_up_enabled);
+
+        let rate = |limit: u64, enabled: bool| enabled.then_some(limit * SPEED_UNIT);
+        self.session.set_rate_limits(
+            rate(state.speed_limit_down, state.speed_limit_down_enabled),
+            rate(state.speed_limit_up, state.speed_limit_up_enabled),
+        );
+        Ok(json!({}))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,461 @@
+use anyhow::{anyhow, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+mod state;
+mod torrent;
+mod upload;
+
+pub use self::torrent::{Event, PeerInfo, Progress, TorrentHandle, TorrentState};
+
+use self::state::{SavedTorrent, StateDir};
+use crate::{
+    download::{ConnectionBudget, ReconnectPolicy, MAX_PEERS},
+    ipfilter::IpFilter,
+    metrics::TorrentMetrics,
+    peer::Timeouts,
+    peer_id::generate_peer_id,
+    protocol::CHUNK_LEN,
+    ratelimit::{PeerLimits, RateLimits, Schedule, TimeOfDay},
+    smartban::BanList,
+    tracker::{DEFAULT_NUMWANT, LISTEN_PORT},
+    types::Torrent,
+};
+
+// How often the schedule is checked for limits to change
+const SCHEDULE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // Ours, for every tracker and peer; a fresh one by default
+    pub peer_id: [u8; 20],
+    // Port we accept peer connections on, 0 for any free one. If it's taken
+    // we fall back to any free one.
+    pub listen_port: u16,
+    // Where the session keeps its torrents and their progress across
+    // restarts, None to not keep anything
+    pub state_dir: Option<PathBuf>,
+    pub max_active_downloads: usize,
+    // 0 to stop torrents as soon as they complete
+    pub max_active_seeds: usize,
+    // Peer connections across all torrents, incoming and outgoing
+    pub max_connections: usize,
+    // Peers each torrent downloads from at the same time
+    pub max_peers_per_torrent: usize,
+    // Size of the blocks pieces are requested in, at most CHUNK_LEN
+    pub block_len: u32,
+    // Peers asked of trackers in each announce
+    pub numwant: u32,
+    // Bytes per second across all torrents, None for unlimited
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    // Bytes per second for each peer connection on its own
+    pub peer_download_rate: Option<u64>,
+    pub peer_upload_rate: Option<u64>,
+    // Times of day the session's limits are different
+    pub schedule: Schedule,
+    // How long peers get to answer, for both our connections and theirs
+    pub timeouts: Timeouts,
+    pub reconnect: ReconnectPolicy,
+    // Addresses we neither connect to nor accept connections from
+    pub ip_filter: Arc<IpFilter>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            peer_id: generate_peer_id(),
+            listen_port: LISTEN_PORT,
+            state_dir: None,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            max_connections: 100,
+            max_peers_per_torrent: MAX_PEERS,
+            block_len: CHUNK_LEN,
+            numwant: DEFAULT_NUMWANT,
+            download_rate: None,
+            upload_rate: None,
+            peer_download_rate: None,
+            peer_upload_rate: None,
+            schedule: Schedule::default(),
+            timeouts: Timeouts::default(),
+            reconnect: ReconnectPolicy::default(),
+            ip_filter: Arc::new(IpFilter::default()),
+        }
+    }
+}
+
+// Runs any number of torrents side by side. The session owns what they share:
+// the listen socket incoming peers arrive on, the connection budget, the
+// bandwidth limits and the queue deciding which torrents are active. Each
+// active torrent runs on its own thread and is watched through its
+// TorrentHandle. Dropping the session shuts it down.
+pub struct Session {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: SessionConfig,
+    listen_addr: SocketAddr,
+    budget: Arc<ConnectionBudget>,
+    // What's enforced across all torrents, and what that is outside the
+    // schedule's rules
+    limits: Arc<RateLimits>,
+    base_limits: Mutex<(Option<u64>, Option<u64>)>,
+    peer_limits: Arc<PeerLimits>,
+    // Peers caught sending corrupt data, kept across runs
+    bans: Arc<BanList>,
+    state: Option<StateDir>,
+    // In the order they were added, which is also the queue order
+    torrents: Mutex<Vec<TorrentHandle>>,
+    // What torrents that have since been removed did, so totals don't go
+    // backwards
+    removed: TorrentMetrics,
+    shutdown: AtomicBool,
+}
+
+impl Session {
+    // Starts listening for peers and picks up whatever torrents the state
+    // directory holds from the last run
+    pub fn new(config: SessionConfig) -> Result<Self> {
+        let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.listen_port)) {
+            Ok(listener) => listener,
+            Err(e) if config.listen_port != 0 => {
+                warn!("Can't listen on port {}, using a free port instead: {}", config.listen_port, e);
+                TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let state = config.state_dir.as_deref().map(StateDir::open).transpose()?;
+        let bans = state.as_ref().map(StateDir::load_bans).transpose()?.unwrap_or_default();
+        let inner = Arc::new(Inner {
+            listen_addr: listener.local_addr()?,
+            budget: ConnectionBudget::new(config.max_connections),
+            limits: Arc::new(RateLimits::unlimited()),
+            base_limits: Mutex::new((config.download_rate, config.upload_rate)),
+            peer_limits: Arc::new(PeerLimits::new(config.peer_download_rate, config.peer_upload_rate)),
+            bans: Arc::new(BanList::new(bans)),
+            state,
+            torrents: Mutex::new(Vec::new()),
+            removed: TorrentMetrics::default(),
+            shutdown: AtomicBool::new(false),
+            config,
+        });
+
+        if let Some(state) = &inner.state {
+            let mut torrents = inner.torrents();
+            for (torrent, saved) in state.load()? {
+                match TorrentHandle::new(torrent, &saved.output, Some(&saved)) {
+                    Ok(handle) => torrents.push(handle),
+                    Err(e) => warn!("Can't restore torrent {}: {:#}", saved.info_hash, e),
+                }
+            }
+        }
+
+        inner.apply_schedule();
+        if !inner.config.schedule.is_empty() {
+            let scheduling = inner.clone();
+            thread::spawn(move || {
+                while !scheduling.shutdown.load(Ordering::Relaxed) {
+                    thread::sleep(SCHEDULE_POLL);
+                    scheduling.apply_schedule();
+                }
+            });
+        }
+        let accepting = inner.clone();
+        thread::spawn(move || upload::accept_loop(&accepting, listener));
+        inner.schedule(&inner.torrents());
+        Ok(Session { inner })
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.inner.listen_addr
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.inner.config
+    }
+
+    // Queues the torrent for download to `output`, a file for single-file
+    // torrents and a directory otherwise. Whatever is already there is
+    // checked first, so pointing it at complete data seeds it.
+    pub fn add_torrent(&self, torrent: Torrent, output: &Path) -> Result<TorrentHandle> {
+        self.add(torrent, output, false)
+    }
+
+    // Adds the torrent without starting it, until it's resumed
+    pub fn add_torrent_paused(&self, torrent: Torrent, output: &Path) -> Result<TorrentHandle> {
+        self.add(torrent, output, true)
+    }
+
+    fn add(&self, torrent: Torrent, output: &Path, paused: bool) -> Result<TorrentHandle> {
+        let info_hash = torrent.info.calculate_info_hash()?;
+        let mut torrents = self.inner.torrents();
+        if self.inner.shutdown.load(Ordering::Relaxed) {
+            return Err(anyhow!("Session is shutting down"));
+        }
+        if torrents.iter().any(|handle| handle.info_hash() == info_hash) {
+            return Err(anyhow!("Torrent {} is already in the session", hex::encode(info_hash)));
+        }
+
+        let handle = TorrentHandle::new(torrent, output, None)?;
+        if paused {
+            handle.pause();
+        }
+        if let Some(state) = &self.inner.state {
+            state.save_torrent(handle.torrent(), &info_hash)?;
+        }
+        torrents.push(handle.clone());
+        self.inner.schedule(&torrents);
+        self.inner.save(&torrents);
+        Ok(handle)
+    }
+
+    // Stops the torrent until it's resumed, closing its peer connections
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let torrents = self.inner.torrents();
+        find(&torrents, info_hash)?.pause();
+        self.inner.schedule(&torrents);
+        self.inner.save(&torrents);
+        Ok(())
+    }
+
+    // Puts a paused or failed torrent back in the queue
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let torrents = self.inner.torrents();
+        find(&torrents, info_hash)?.resume();
+        self.inner.schedule(&torrents);
+        self.inner.save(&torrents);
+        Ok(())
+    }
+
+    // Stops the torrent and forgets about it, deleting what it downloaded too
+    // if asked to
+    pub fn remove(&self, info_hash: &[u8; 20], delete_data: bool) -> Result<()> {
+        let handle = {
+            let mut torrents = self.inner.torrents();
+            let handle = find(&torrents, info_hash)?.clone();
+            torrents.retain(|other| other.info_hash() != *info_hash);
+            handle.close();
+            self.inner.schedule(&torrents);
+            self.inner.save(&torrents);
+            handle
+        };
+
+        handle.wait_stopped();
+        self.inner.removed.add(handle.metrics());
+        if let Some(state) = &self.inner.state {
+            state.remove_torrent(info_hash)?;
+        }
+        if delete_data {
+            handle.storage().remove()?;
+        }
+        Ok(())
+    }
+
+    // Moves the torrent to `position` in the queue, or to the end if that's
+    // past it. Torrents earlier in the queue get free slots first.
+    pub fn move_torrent(&self, info_hash: &[u8; 20], position: usize) -> Result<()> {
+        let mut torrents = self.inner.torrents();
+        let index = torrents
+            .iter()
+            .position(|handle| handle.info_hash() == *info_hash)
+            .ok_or_else(|| anyhow!("Torrent {} is not in the session", hex::encode(info_hash)))?;
+        let handle = torrents.remove(index);
+        let position = position.min(torrents.len());
+        torrents.insert(position, handle);
+        self.inner.schedule(&torrents);
+        self.inner.save(&torrents);
+        Ok(())
+    }
+
+    // Sets the limits across all torrents. While a rule of the schedule
+    // applies, the rates it sets win over these.
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        *self.inner.base_limits.lock().unwrap_or_else(|e| e.into_inner()) = (download, upload);
+        self.inner.apply_schedule();
+    }
+
+    // Current (download, upload) limits in bytes per second
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.inner.limits.rates()
+    }
+
+    // Sets the limits each peer connection gets on its own, open ones included
+    pub fn set_peer_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.inner.peer_limits.set(download, upload);
+    }
+
+    pub fn peer_rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.inner.peer_limits.rates()
+    }
+
+    // Sets the limits of one torrent, on top of the session's
+    pub fn set_torrent_rate_limits(&self, info_hash: &[u8; 20], download: Option<u64>, upload: Option<u64>) -> Result<()> {
+        let torrents = self.inner.torrents();
+        find(&torrents, info_hash)?.limits().set(download, upload);
+        self.inner.save(&torrents);
+        Ok(())
+    }
+
+    // Addresses of peers that sent corrupt data, which we no longer talk to
+    pub fn banned_peers(&self) -> Vec<IpAddr> {
+        self.inner.bans.list()
+    }
+
+    // Peer connections open across all torrents, incoming and outgoing
+    pub fn connections(&self) -> usize {
+        self.inner.budget.used()
+    }
+
+    // Counters and timings of the torrents that were removed from the session
+    pub fn removed_metrics(&self) -> &TorrentMetrics {
+        &self.inner.removed
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.inner.torrent(info_hash)
+    }
+
+    // Every torrent in the session, in queue order
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.inner.torrents().clone()
+    }
+
+    // Stops every torrent and saves where they got to. Torrents that were
+    // running start again when the session is next created.
+    pub fn shutdown(&self) {
+        if self.inner.shutdown.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let torrents = self.inner.torrents().clone();
+        for handle in &torrents {
+            handle.stop();
+        }
+        for handle in &torrents {
+            handle.wait_stopped();
+        }
+        self.inner.save(&torrents);
+        for handle in &torrents {
+            handle.close();
+        }
+        // Wakes the listener up so it sees we're done
+        let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.inner.listen_addr.port()));
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn find<'a>(torrents: &'a [TorrentHandle], info_hash: &[u8; 20]) -> Result<&'a TorrentHandle> {
+    torrents
+        .iter()
+        .find(|handle| handle.info_hash() == *info_hash)
+        .ok_or_else(|| anyhow!("Torrent {} is not in the session", hex::encode(info_hash)))
+}
+
+impl Inner {
+    fn torrents(&self) -> MutexGuard<'_, Vec<TorrentHandle>> {
+        self.torrents.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents().iter().find(|handle| handle.info_hash() == *info_hash).cloned()
+    }
+
+    // Starts queued torrents while there are download slots, and completed
+    // ones while there are seed slots, first come first served
+    fn schedule(self: &Arc<Self>, torrents: &[TorrentHandle]) {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        let count = |state: TorrentState| torrents.iter().filter(|handle| handle.state() == state).count();
+        let mut downloads = count(TorrentState::Downloading);
+        let mut seeds = count(TorrentState::Seeding);
+        for handle in torrents.iter().filter(|handle| !handle.running()) {
+            match handle.state() {
+                TorrentState::Queued if downloads < self.config.max_active_downloads => {
+                    downloads += 1;
+                    handle.start(self);
+                }
+                TorrentState::Completed if seeds < self.config.max_active_seeds => {
+                    seeds += 1;
+                    handle.start(self);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Called by a torrent that just finished downloading. Returns whether it
+    // should go on to seed.
+    fn download_finished(self: &Arc<Self>, handle: &TorrentHandle) -> bool {
+        let torrents = self.torrents();
+        // Paused or removed while finishing up
+        if handle.cancelled() {
+            return false;
+        }
+        let seeds = torrents.iter().filter(|handle| handle.state() == TorrentState::Seeding).count();
+        let seed = seeds < self.config.max_active_seeds;
+        handle.set_state(if seed { TorrentState::Seeding } else { TorrentState::Completed });
+        self.schedule(&torrents);
+        self.save(&torrents);
+        seed
+    }
+
+    // Called once a torrent's thread is done, to hand its slot to the next one
+    fn torrent_stopped(self: &Arc<Self>) {
+        let torrents = self.torrents();
+        self.schedule(&torrents);
+        self.save(&torrents);
+    }
+
+    // Brings the session's limits in line with the schedule
+    fn apply_schedule(&self) {
+        let base = *self.base_limits.lock().unwrap_or_else(|e| e.into_inner());
+        let (download, upload) = self.config.schedule.limits_at(TimeOfDay::now(), base);
+        if self.limits.rates() != (download, upload) {
+            if !self.config.schedule.is_empty() {
+                let rate = |rate: Option<u64>| rate.map_or("unlimited".to_string(), |rate| format!("{} B/s", rate));
+                info!("Rate limits now {} down, {} up", rate(download), rate(upload));
+            }
+            self.limits.set(download, upload);
+        }
+    }
+
+    fn save_all(&self) {
+        self.save(&self.torrents());
+    }
+
+    fn save(&self, torrents: &[TorrentHandle]) {
+        let Some(state) = &self.state else { return };
+        let saved: Vec<SavedTorrent> = torrents.iter().map(SavedTorrent::from_handle).collect();
+        if let Err(e) = state.save(&saved) {
+            warn!("Failed to save session state: {:#}", e);
+        }
+    }
+
+    // Drops the newly banned peer's connections to us and remembers the ban
+    // for the next run
+    fn peer_banned(&self, ip: IpAddr) {
+        for handle in self.torrents().iter() {
+            handle.close_incoming_from(ip);
+        }
+        let Some(state) = &self.state else { return };
+        if let Err(e) = state.save_bans(&self.bans.list()) {
+            warn!("Failed to save ban list: {:#}", e);
+        }
+    }
+}
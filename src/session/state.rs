@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use super::{TorrentHandle, TorrentState};
+use crate::{peer::to_bitfield, types::Torrent};
+
+const STATE_FILE: &str = "session.json";
+// Addresses of peers caught sending corrupt data
+const BANS_FILE: &str = "banned.json";
+
+// What we remember about a torrent between runs. The torrent itself is kept
+// next to the state file as <info hash>.torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct SavedTorrent {
+    pub info_hash: String,
+    pub output: PathBuf,
+    pub paused: bool,
+    // Bitfield of the pieces we had, in hex. They're checked again before
+    // they're trusted.
+    pub have: String,
+    pub uploaded: u64,
+    // The torrent's own limits in bytes per second, if it has any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_rate: Option<u64>,
+}
+
+impl SavedTorrent {
+    pub fn from_handle(handle: &TorrentHandle) -> Self {
+        let (download_rate, upload_rate) = handle.rate_limits();
+        SavedTorrent {
+            info_hash: hex::encode(handle.info_hash()),
+            output: handle.output().to_path_buf(),
+            paused: handle.state() == TorrentState::Paused,
+            have: hex::encode(to_bitfield(&handle.have())),
+            uploaded: handle.progress().uploaded,
+            download_rate,
+            upload_rate,
+        }
+    }
+}
+
+pub(super) struct StateDir {
+    dir: PathBuf,
+}
+
+impl StateDir {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Can't create state directory {}", dir.display()))?;
+        Ok(StateDir { dir: dir.to_path_buf() })
+    }
+
+    fn torrent_path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        self.dir.join(format!("{}.torrent", hex::encode(info_hash)))
+    }
+
+    pub fn save_torrent(&self, torrent: &Torrent, info_hash: &[u8; 20]) -> Result<()> {
+        fs::write(self.torrent_path(info_hash), torrent.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> Result<()> {
+        match fs::remove_file(self.torrent_path(info_hash)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Torrents from the last run, skipping any whose .torrent went missing
+    pub fn load(&self) -> Result<Vec<(Torrent, SavedTorrent)>> {
+        let path = self.dir.join(STATE_FILE);
+        let saved: Vec<SavedTorrent> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("Corrupt session state in {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut torrents = Vec::new();
+        for saved in saved {
+            let result = hex::decode(&saved.info_hash)
+                .ok()
+                .and_then(|hash| <[u8; 20]>::try_from(hash).ok())
+                .ok_or_else(|| anyhow!("Bad info hash"))
+                .and_then(|info_hash| Torrent::read(&self.torrent_path(&info_hash)));
+            match result {
+                Ok(torrent) => torrents.push((torrent, saved)),
+                Err(e) => warn!("Can't restore torrent {}: {:#}", saved.info_hash, e),
+            }
+        }
+        Ok(torrents)
+    }
+
+    pub fn save(&self, torrents: &[SavedTorrent]) -> Result<()> {
+        self.write(STATE_FILE, &serde_json::to_vec_pretty(torrents)?)
+    }
+
+    pub fn load_bans(&self) -> Result<Vec<IpAddr>> {
+        let path = self.dir.join(BANS_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("Corrupt ban list in {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_bans(&self, bans: &[IpAddr]) -> Result<()> {
+        self.write(BANS_FILE, &serde_json::to_vec_pretty(bans)?)
+    }
+
+    // Written to the side and renamed into place, so a crash mid-write leaves
+    // the previous contents intact
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let temp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, self.dir.join(name))?;
+        Ok(())
+    }
+}
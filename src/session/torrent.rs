@@ -0,0 +1,701 @@
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{info_span, warn, Span};
+
+use super::{state::SavedTorrent, Inner};
+use crate::{
+    download::{download_torrent, piece_length, DownloadEvent, DownloadOptions},
+    metrics::TorrentMetrics,
+    peer::{from_bitfield, to_bitfield},
+    peer_id::Client,
+    ratelimit::RateLimits,
+    ratemeter::RateMeter,
+    storage::Storage,
+    tracker::{TrackerEvent, TrackerSession, TrackerTiers},
+    types::Torrent,
+};
+
+// Events kept for late subscribers; older ones are dropped
+const MAX_HISTORY: usize = 1024;
+// How often a seeding torrent checks whether it's time to re-announce
+const SEED_POLL: Duration = Duration::from_millis(100);
+// Saving the session state on every piece would be a lot of writes
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub pieces_done: usize,
+    pub num_pieces: usize,
+    // Bytes of verified pieces
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub total: u64,
+    pub peers: usize,
+}
+
+// A peer we're connected to for a torrent, whichever side connected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    // What its peer id says about the client it runs
+    pub client: String,
+    pub incoming: bool,
+    // Bytes of verified pieces it sent us
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    // Waiting for a download slot
+    Queued,
+    Downloading,
+    Seeding,
+    // Has every piece but isn't seeding, or is waiting for a seed slot
+    Completed,
+    Paused,
+    Failed(String),
+}
+
+impl TorrentState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TorrentState::Queued => "queued",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Completed => "completed",
+            TorrentState::Paused => "paused",
+            TorrentState::Failed(_) => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    StateChanged(TorrentState),
+    TrackerAnnounced { url: String, peers: usize },
+    TrackerWarning { url: String, message: String },
+    // An announce failed; the torrent carries on with the peers it has
+    TrackerError(String),
+    PeerConnected(SocketAddr),
+    PeerDisconnected { peer: SocketAddr, error: Option<String> },
+    // Sent corrupt data, so the session won't talk to it again
+    PeerBanned(IpAddr),
+    PieceVerified { index: u32, progress: Progress },
+    Completed,
+    Failed(String),
+}
+
+struct Status {
+    state: TorrentState,
+    progress: Progress,
+    // Recent events, so late subscribers don't miss them
+    history: VecDeque<Event>,
+    subscribers: Vec<mpsc::Sender<Event>>,
+    // Pieces we have. Until `checked`, the ones we might have and still have
+    // to verify.
+    have: Vec<bool>,
+    checked: bool,
+    // Whether the torrent's thread is running, and the flag that stops it
+    running: bool,
+    cancel: Arc<AtomicBool>,
+    // Peers that connected to us for this torrent
+    incoming: HashMap<SocketAddr, TcpStream>,
+    // Everyone we're connected to, both ways
+    peers: HashMap<SocketAddr, PeerInfo>,
+    // Removed from the session, or the session shut down
+    closed: bool,
+    // Sampled whenever someone asks for the rates
+    download_rate: RateMeter,
+    upload_rate: RateMeter,
+}
+
+impl Status {
+    fn emit(&mut self, event: Event) {
+        self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+    }
+
+    fn set_state(&mut self, state: TorrentState) {
+        if self.state != state {
+            self.state = state.clone();
+            self.emit(Event::StateChanged(state));
+        }
+    }
+
+    fn add_peer(&mut self, addr: SocketAddr, peer_id: &[u8; 20], incoming: bool) {
+        let client = Client::from_peer_id(peer_id).to_string();
+        self.peers.insert(addr, PeerInfo { addr, client, incoming, downloaded: 0, uploaded: 0 });
+        self.progress.peers = self.peers.len();
+    }
+
+    fn remove_peers(&mut self, f: impl Fn(&PeerInfo) -> bool) {
+        self.peers.retain(|_, peer| !f(peer));
+        self.progress.peers = self.peers.len();
+    }
+
+    fn close_incoming(&mut self) {
+        for (_, stream) in self.incoming.drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+struct Shared {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    output: PathBuf,
+    storage: Storage,
+    status: Mutex<Status>,
+    changed: Condvar,
+    metrics: Arc<TorrentMetrics>,
+    // The torrent's own limits, on top of the session's
+    limits: Arc<RateLimits>,
+    // Everything logged about the torrent happens in here
+    span: Span,
+}
+
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<Shared>,
+}
+
+impl TorrentHandle {
+    // A new torrent if `saved` is None, otherwise one restored from the last run
+    pub(super) fn new(torrent: Torrent, output: &Path, saved: Option<&SavedTorrent>) -> Result<Self> {
+        let info_hash = torrent.info.calculate_info_hash()?;
+        let storage = Storage::new(&torrent.info, output)?;
+        let torrent_name = torrent.info.name.clone();
+        let num_pieces = torrent.info.pieces.0.len();
+        let mut progress = Progress { num_pieces, total: torrent.info.files.length() as u64, ..Default::default() };
+
+        let (state, have) = match saved {
+            // Nothing's known about a new torrent's data, so all of it is checked
+            None => (TorrentState::Queued, vec![true; num_pieces]),
+            Some(saved) => {
+                let bitfield = hex::decode(&saved.have).map_err(|e| anyhow!("Bad bitfield in session state: {}", e))?;
+                let have = from_bitfield(&bitfield, num_pieces);
+                progress.uploaded = saved.uploaded;
+                update_done(&mut progress, &torrent, &have);
+                let state = match (saved.paused, have.iter().all(|have| *have)) {
+                    (true, _) => TorrentState::Paused,
+                    (false, true) => TorrentState::Completed,
+                    (false, false) => TorrentState::Queued,
+                };
+                (state, have)
+            }
+        };
+
+        Ok(TorrentHandle {
+            shared: Arc::new(Shared {
+                torrent,
+                info_hash,
+                output: output.to_path_buf(),
+                storage,
+                status: Mutex::new(Status {
+                    state,
+                    progress,
+                    history: VecDeque::new(),
+                    subscribers: Vec::new(),
+                    have,
+                    checked: false,
+                    running: false,
+                    cancel: Arc::new(AtomicBool::new(false)),
+                    incoming: HashMap::new(),
+                    peers: HashMap::new(),
+                    closed: false,
+                    download_rate: RateMeter::default(),
+                    upload_rate: RateMeter::default(),
+                }),
+                changed: Condvar::new(),
+                metrics: Arc::new(TorrentMetrics::default()),
+                limits: Arc::new(saved.map_or_else(RateLimits::unlimited, |saved| RateLimits::new(saved.download_rate, saved.upload_rate))),
+                span: info_span!(parent: None, "torrent", name = %torrent_name),
+            }),
+        })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.shared.torrent
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.shared.output
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.status().state.clone()
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.status().progress
+    }
+
+    // (download, upload) in bytes per second over the last few seconds
+    pub fn rates(&self) -> (u64, u64) {
+        let mut status = self.status();
+        let (now, progress) = (Instant::now(), status.progress);
+        status.download_rate.record(now, progress.downloaded);
+        status.upload_rate.record(now, progress.uploaded);
+        (status.download_rate.rate(), status.upload_rate.rate())
+    }
+
+    // How long the rest of the download takes at the current rate, None while
+    // nothing is arriving
+    pub fn eta(&self) -> Option<Duration> {
+        let progress = self.progress();
+        match self.rates().0 {
+            0 => None,
+            rate => Some(Duration::from_secs(progress.total.saturating_sub(progress.downloaded).div_ceil(rate))),
+        }
+    }
+
+    // Which pieces we have, by index. None until what's on disk is checked.
+    pub fn pieces(&self) -> Vec<bool> {
+        let status = self.status();
+        match status.checked {
+            true => status.have.clone(),
+            false => vec![false; status.have.len()],
+        }
+    }
+
+    // Everyone we're connected to, by address
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.status().peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.addr);
+        peers
+    }
+
+    // Counters and timings since the torrent was added or restored
+    pub fn metrics(&self) -> &TorrentMetrics {
+        &self.shared.metrics
+    }
+
+    // The torrent's own (download, upload) limits in bytes per second
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.shared.limits.rates()
+    }
+
+    // Recent events, then new ones as they happen. The receiver ends once the
+    // torrent is removed or the session shuts down.
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        let mut status = self.status();
+        for event in &status.history {
+            let _ = tx.send(event.clone());
+        }
+        if !status.closed {
+            status.subscribers.push(tx);
+        }
+        rx
+    }
+
+    // Blocks until the torrent has every piece, or can't get them
+    pub fn wait(&self) -> Result<()> {
+        let mut status = self.status();
+        loop {
+            match &status.state {
+                TorrentState::Seeding | TorrentState::Completed => return Ok(()),
+                TorrentState::Failed(error) => return Err(anyhow!("{}", error)),
+                TorrentState::Paused => return Err(anyhow!("Torrent is paused")),
+                _ if status.closed => return Err(anyhow!("Torrent is no longer in the session")),
+                _ => status = self.shared.changed.wait(status).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+
+    fn status(&self) -> MutexGuard<'_, Status> {
+        self.shared.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(super) fn span(&self) -> &Span {
+        &self.shared.span
+    }
+
+    pub(super) fn limits(&self) -> &Arc<RateLimits> {
+        &self.shared.limits
+    }
+
+    pub(super) fn storage(&self) -> &Storage {
+        &self.shared.storage
+    }
+
+    pub(super) fn have(&self) -> Vec<bool> {
+        self.status().have.clone()
+    }
+
+    pub(super) fn has_piece(&self, index: u32) -> bool {
+        let status = self.status();
+        status.checked && status.have.get(index as usize).copied().unwrap_or(false)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.status().have.iter().all(|have| *have)
+    }
+
+    pub(super) fn running(&self) -> bool {
+        self.status().running
+    }
+
+    pub(super) fn cancelled(&self) -> bool {
+        self.status().cancel.load(Ordering::Relaxed)
+    }
+
+    fn emit(&self, event: Event) {
+        self.status().emit(event);
+    }
+
+    pub(super) fn set_state(&self, state: TorrentState) {
+        self.status().set_state(state);
+        self.shared.changed.notify_all();
+    }
+
+    // Starts the torrent's thread, downloading or seeding depending on what
+    // it has
+    pub(super) fn start(&self, inner: &Arc<Inner>) {
+        let cancel = {
+            let mut status = self.status();
+            let state = match status.state {
+                TorrentState::Completed => TorrentState::Seeding,
+                _ => TorrentState::Downloading,
+            };
+            status.set_state(state);
+            status.running = true;
+            status.cancel = Arc::new(AtomicBool::new(false));
+            status.cancel.clone()
+        };
+        self.shared.changed.notify_all();
+
+        let (handle, inner) = (self.clone(), inner.clone());
+        thread::spawn(move || {
+            let _span = handle.span().enter();
+            let result = run(&handle, &inner, &cancel);
+            handle.stopped(result, &cancel);
+            inner.torrent_stopped();
+        });
+    }
+
+    fn stopped(&self, result: Result<()>, cancel: &AtomicBool) {
+        let mut status = self.status();
+        status.running = false;
+        status.close_incoming();
+        if let Err(e) = result {
+            // Errors from tearing down a paused torrent aren't failures
+            if !cancel.load(Ordering::Relaxed) {
+                let error = format!("{:#}", e);
+                status.emit(Event::Failed(error.clone()));
+                status.set_state(TorrentState::Failed(error));
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+
+    pub(super) fn pause(&self) {
+        let mut status = self.status();
+        status.cancel.store(true, Ordering::Relaxed);
+        status.close_incoming();
+        status.set_state(TorrentState::Paused);
+        self.shared.changed.notify_all();
+    }
+
+    pub(super) fn resume(&self) {
+        let mut status = self.status();
+        if matches!(status.state, TorrentState::Paused | TorrentState::Failed(_)) {
+            // Unchecked pieces are only candidates
+            let complete = status.checked && status.have.iter().all(|have| *have);
+            status.set_state(if complete { TorrentState::Completed } else { TorrentState::Queued });
+        }
+        self.shared.changed.notify_all();
+    }
+
+    // Stops the thread without changing state, for shutting down
+    pub(super) fn stop(&self) {
+        let mut status = self.status();
+        status.cancel.store(true, Ordering::Relaxed);
+        status.close_incoming();
+    }
+
+    // Stops the thread and ends every subscription
+    pub(super) fn close(&self) {
+        self.stop();
+        let mut status = self.status();
+        status.closed = true;
+        status.subscribers.clear();
+        self.shared.changed.notify_all();
+    }
+
+    pub(super) fn wait_stopped(&self) {
+        let mut status = self.status();
+        while status.running {
+            status = self.shared.changed.wait(status).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    // Registers a peer that connected to us, returning the bitfield to send
+    // it. Fails unless we're running and know what we have.
+    pub(super) fn add_incoming(&self, peer: SocketAddr, peer_id: &[u8; 20], stream: TcpStream) -> Result<Vec<u8>> {
+        let mut status = self.status();
+        if !status.running || !status.checked || !matches!(status.state, TorrentState::Downloading | TorrentState::Seeding) {
+            return Err(anyhow!("Torrent {} isn't active", hex::encode(self.info_hash())));
+        }
+        status.incoming.insert(peer, stream);
+        status.add_peer(peer, peer_id, true);
+        Ok(to_bitfield(&status.have))
+    }
+
+    pub(super) fn remove_incoming(&self, peer: SocketAddr) {
+        let mut status = self.status();
+        status.incoming.remove(&peer);
+        status.remove_peers(|other| other.addr == peer);
+    }
+
+    // Hangs up on whatever connections the address has to us; their threads
+    // clean up after them
+    pub(super) fn close_incoming_from(&self, ip: IpAddr) {
+        for (_, stream) in self.status().incoming.iter().filter(|(peer, _)| peer.ip() == ip) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub(super) fn add_uploaded(&self, peer: SocketAddr, bytes: u64) {
+        self.metrics().uploaded.add(bytes);
+        let mut status = self.status();
+        status.progress.uploaded += bytes;
+        if let Some(peer) = status.peers.get_mut(&peer) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    fn tracker_failed(&self, error: &anyhow::Error) {
+        warn!("Failed to announce to tracker: {:#}", error);
+        self.emit(Event::TrackerError(format!("{:#}", error)));
+    }
+
+    fn report_tracker_events(&self, tracker: &mut TrackerSession) {
+        let metrics = self.metrics();
+        for event in tracker.take_events() {
+            match event {
+                TrackerEvent::Announced { url, peers, latency, .. } => {
+                    metrics.announces_succeeded.inc();
+                    metrics.announce_latency.observe(latency);
+                    self.emit(Event::TrackerAnnounced { url, peers });
+                }
+                TrackerEvent::Warning { url, message } => self.emit(Event::TrackerWarning { url, message }),
+                TrackerEvent::Failed { latency, .. } => {
+                    metrics.announces_failed.inc();
+                    metrics.announce_latency.observe(latency);
+                }
+            }
+        }
+    }
+
+    // Verifies the pieces we might have against what's on disk, once
+    fn check(&self, cancel: &AtomicBool) {
+        let candidates = {
+            let status = self.status();
+            if status.checked {
+                return;
+            }
+            status.have.clone()
+        };
+
+        let info = &self.torrent().info;
+        let mut have = vec![false; candidates.len()];
+        for (index, _) in candidates.iter().enumerate().filter(|(_, candidate)| **candidate) {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let length = piece_length(info, index as u32) as usize;
+            // Missing or short files just mean we don't have the piece yet
+            if let Ok(data) = self.storage().read_piece(index as u32, length) {
+                have[index] = <[u8; 20]>::from(Sha1::digest(&data)) == info.pieces.0[index];
+            }
+        }
+
+        let mut status = self.status();
+        update_done(&mut status.progress, self.torrent(), &have);
+        status.have = have;
+        status.checked = true;
+    }
+
+    fn piece_verified(&self, peer: SocketAddr, index: u32) {
+        let mut status = self.status();
+        let length = piece_length(&self.torrent().info, index) as u64;
+        status.have[index as usize] = true;
+        status.progress.pieces_done += 1;
+        status.progress.downloaded += length;
+        if let Some(peer) = status.peers.get_mut(&peer) {
+            peer.downloaded += length;
+        }
+        let progress = status.progress;
+        status.emit(Event::PieceVerified { index, progress });
+    }
+}
+
+fn update_done(progress: &mut Progress, torrent: &Torrent, have: &[bool]) {
+    let pieces = have.iter().enumerate().filter(|(_, have)| **have);
+    progress.pieces_done = pieces.clone().count();
+    progress.downloaded = pieces.map(|(index, _)| piece_length(&torrent.info, index as u32) as u64).sum();
+}
+
+// The torrent's thread: checks what's on disk, downloads what's missing and
+// then seeds until it's stopped, if the session has room for another seed
+fn run(handle: &TorrentHandle, inner: &Arc<Inner>, cancel: &Arc<AtomicBool>) -> Result<()> {
+    let torrent = handle.torrent();
+    let info_hash = handle.info_hash();
+    let storage = handle.storage();
+    handle.check(cancel);
+    if cancel.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    storage.allocate()?;
+
+    let progress = handle.progress();
+    let mut tracker = TrackerSession::new(info_hash, inner.config.peer_id, TrackerTiers::from_torrent(torrent), progress.total.saturating_sub(progress.downloaded));
+    tracker.set_port(inner.listen_addr.port());
+    tracker.set_numwant(inner.config.numwant);
+    tracker.set_ip_filter(inner.config.ip_filter.clone());
+    // Without the tracker a complete torrent can still seed to peers that
+    // find us, and one that isn't waits for the tracker to come back
+    let peers = tracker.start();
+    handle.report_tracker_events(&mut tracker);
+    let mut peers = peers.map_err(|e| handle.tracker_failed(&e)).ok();
+
+    // Restored torrents may turn out to be missing pieces they claimed to have
+    if !handle.is_complete() && handle.state() == TorrentState::Seeding {
+        handle.set_state(TorrentState::Downloading);
+    }
+    if handle.state() == TorrentState::Downloading {
+        if !handle.is_complete() {
+            let peers = loop {
+                if let Some(peers) = peers.take() {
+                    break peers;
+                }
+                if cancel.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                thread::sleep(SEED_POLL);
+                match tracker.announce_if_due() {
+                    Ok(found) => peers = found,
+                    Err(e) => handle.tracker_failed(&e),
+                }
+                handle.report_tracker_events(&mut tracker);
+            };
+            download(handle, inner, cancel, &mut tracker, &peers)?;
+            if cancel.load(Ordering::Relaxed) {
+                stop_tracker(handle, &mut tracker);
+                return Ok(());
+            }
+            if let Err(e) = tracker.complete() {
+                warn!("Failed to send completed event to tracker: {}", e);
+            }
+            handle.report_tracker_events(&mut tracker);
+        }
+        handle.emit(Event::Completed);
+        if !inner.download_finished(handle) {
+            stop_tracker(handle, &mut tracker);
+            return Ok(());
+        }
+    }
+
+    let mut reported = handle.progress().uploaded;
+    while !cancel.load(Ordering::Relaxed) {
+        thread::sleep(SEED_POLL);
+        let uploaded = handle.progress().uploaded;
+        tracker.add_uploaded(uploaded - reported);
+        reported = uploaded;
+        if let Err(e) = tracker.announce_if_due() {
+            handle.tracker_failed(&e);
+        }
+        handle.report_tracker_events(&mut tracker);
+    }
+    stop_tracker(handle, &mut tracker);
+    Ok(())
+}
+
+fn download(
+    handle: &TorrentHandle,
+    inner: &Arc<Inner>,
+    cancel: &Arc<AtomicBool>,
+    tracker: &mut TrackerSession,
+    peers: &[SocketAddr],
+) -> Result<()> {
+    let torrent = handle.torrent();
+    let options = DownloadOptions {
+        max_peers: inner.config.max_peers_per_torrent,
+        peer_id: inner.config.peer_id,
+        block_len: inner.config.block_len,
+        timeouts: inner.config.timeouts,
+        reconnect: inner.config.reconnect,
+        budget: inner.budget.clone(),
+        limits: vec![inner.limits.clone(), handle.limits().clone()],
+        peer_limits: inner.peer_limits.clone(),
+        bans: inner.bans.clone(),
+        cancel: cancel.clone(),
+        have: handle.have(),
+        metrics: handle.shared.metrics.clone(),
+    };
+
+    let mut last_save = Instant::now();
+    let result = download_torrent(&torrent.info, &handle.info_hash(), peers, &options, |event| {
+        match event {
+            DownloadEvent::PeerConnected(peer, peer_id) => {
+                handle.status().add_peer(peer, &peer_id, false);
+                handle.emit(Event::PeerConnected(peer));
+            }
+            DownloadEvent::PeerDisconnected(peer, error) => {
+                handle.status().remove_peers(|other| other.addr == peer && !other.incoming);
+                handle.emit(Event::PeerDisconnected { peer, error });
+            }
+            DownloadEvent::PeerBanned(ip) => {
+                inner.peer_banned(ip);
+                handle.emit(Event::PeerBanned(ip));
+            }
+            DownloadEvent::Piece(peer, index, data) => {
+                let started = Instant::now();
+                handle.storage().write_piece(index, &data)?;
+                handle.metrics().disk_write_latency.observe(started.elapsed());
+                handle.piece_verified(peer, index);
+                tracker.add_downloaded(data.len() as u64);
+                if let Err(e) = tracker.announce_if_due() {
+                    handle.tracker_failed(&e);
+                }
+                handle.report_tracker_events(tracker);
+                if last_save.elapsed() >= SAVE_INTERVAL {
+                    inner.save_all();
+                    last_save = Instant::now();
+                }
+            }
+        }
+        Ok(())
+    });
+    // Workers still winding down when the download ends don't report back
+    handle.status().remove_peers(|peer| !peer.incoming);
+    result?;
+    handle.storage().sync()
+}
+
+fn stop_tracker(handle: &TorrentHandle, tracker: &mut TrackerSession) {
+    if let Err(e) = tracker.stop() {
+        warn!("Failed to send stopped event to tracker: {}", e);
+    }
+    handle.report_tracker_events(tracker);
+}
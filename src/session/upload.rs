@@ -0,0 +1,110 @@
+use anyhow::{anyhow, Result};
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    thread,
+};
+use tracing::{debug, info_span};
+
+use super::{Inner, TorrentHandle};
+use crate::{
+    download::piece_length,
+    peer::KeepAlive,
+    protocol::{
+        read_handshake, read_message, send_handshake, send_message, CHUNK_LEN, MAX_MESSAGE_LEN, MSG_BITFIELD,
+        MSG_INTERESTED, MSG_PIECE, MSG_REQUEST, MSG_UNCHOKE, PeerStream,
+    },
+    ratelimit::Throttled,
+    wire,
+};
+
+// Hands every peer that connects to us to its own thread, as long as the
+// connection budget allows
+pub(super) fn accept_loop(inner: &Arc<Inner>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        if inner.shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        let Ok(stream) = stream else { continue };
+        if stream.peer_addr().is_ok_and(|peer| inner.bans.contains(peer.ip()) || inner.config.ip_filter.is_blocked(peer.ip())) {
+            debug!("Turning away banned or filtered peer {:?}", stream.peer_addr());
+            continue;
+        }
+        let Some(permit) = inner.budget.try_acquire() else {
+            debug!("Out of connections, turning away {:?}", stream.peer_addr());
+            continue;
+        };
+        let inner = inner.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = serve_peer(&inner, stream) {
+                debug!("Incoming peer {:?} disconnected: {:#}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+fn serve_peer(inner: &Inner, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let timeouts = inner.config.timeouts;
+    stream.set_write_timeout(Some(timeouts.idle))?;
+    let shared = stream.try_clone()?;
+    let stream = Throttled::new(wire::capture(stream, true), vec![inner.limits.clone(), inner.peer_limits.for_peer()]);
+    let mut stream = KeepAlive::new(stream, timeouts);
+    let (info_hash, handshake) = read_handshake(&mut stream)?;
+    let handle = inner
+        .torrent(&info_hash)
+        .ok_or_else(|| anyhow!("Peer asked for torrent {} which we don't have", hex::encode(info_hash)))?;
+    stream.get_mut().limit_by(handle.limits().clone());
+    let _span = info_span!(parent: handle.span(), "peer", addr = %peer, incoming = true).entered();
+    let bitfield = handle.add_incoming(peer, &handshake.peer_id, shared)?;
+
+    let result = (|| {
+        send_handshake(&mut stream, &info_hash, &inner.config.peer_id)?;
+        send_message(&mut stream, MSG_BITFIELD, &bitfield)?;
+        stream.handshaken();
+        serve_requests(&handle, &mut stream, peer, bitfield.len())
+    })();
+    handle.remove_incoming(peer);
+    result
+}
+
+// Unchokes the peer as soon as it's interested and answers its requests for
+// pieces we have, until it goes away or the torrent stops
+fn serve_requests(
+    handle: &TorrentHandle,
+    stream: &mut impl PeerStream,
+    peer: SocketAddr,
+    bitfield_len: usize,
+) -> Result<()> {
+    let info = &handle.torrent().info;
+    // The peer's bitfield is as long as ours
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN.max(bitfield_len + 1)];
+    let mut choked = true;
+    loop {
+        match read_message(stream, &mut buf)? {
+            Some((MSG_INTERESTED, _)) if choked => {
+                send_message(stream, MSG_UNCHOKE, &[])?;
+                choked = false;
+            }
+            Some((MSG_REQUEST, 13)) if !choked => {
+                let field = |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+                let (index, begin, length) = (field(1), field(5), field(9));
+                if !handle.has_piece(index) || length > CHUNK_LEN || begin as u64 + length as u64 > piece_length(info, index) as u64 {
+                    return Err(anyhow!("Peer requested {} bytes at offset {} of piece {}, which we can't serve", length, begin, index));
+                }
+
+                let block = handle.storage().read_block(index, begin, length as usize)?;
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(&block);
+                send_message(stream, MSG_PIECE, &payload)?;
+                handle.add_uploaded(peer, length as u64);
+            }
+            // Nothing else needs an answer from us
+            _ => continue,
+        }
+    }
+}
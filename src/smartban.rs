@@ -0,0 +1,122 @@
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+// Works out who sent the bad data when a piece fails its hash check. Every
+// failed attempt at a piece is remembered as the hashes of its blocks and
+// the peer they came from. Once the piece passes, the blocks of the failed
+// attempts are compared with the good copy and whoever sent one that
+// differs is to blame. A peer whose blocks all match wasn't at fault, the
+// piece went bad some other way.
+#[derive(Debug)]
+pub struct SmartBan {
+    block_len: usize,
+    failed: HashMap<u32, Vec<FailedBlock>>,
+}
+
+#[derive(Debug)]
+struct FailedBlock {
+    peer: IpAddr,
+    offset: usize,
+    hash: [u8; 20],
+}
+
+impl SmartBan {
+    pub fn new(block_len: u32) -> Self {
+        SmartBan { block_len: block_len as usize, failed: HashMap::new() }
+    }
+
+    // A piece that failed its hash check and the peer that sent it
+    pub fn failed(&mut self, index: u32, peer: IpAddr, data: &[u8]) {
+        let blocks = self.failed.entry(index).or_default();
+        blocks.extend(block_hashes(data, self.block_len).map(|(offset, hash)| FailedBlock { peer, offset, hash }));
+    }
+
+    // A piece that passed, and the peers that sent bad blocks of it before
+    pub fn verified(&mut self, index: u32, data: &[u8]) -> Vec<IpAddr> {
+        let Some(failed) = self.failed.remove(&index) else { return Vec::new() };
+        let good: HashMap<usize, [u8; 20]> = block_hashes(data, self.block_len).collect();
+        let culprits: BTreeSet<IpAddr> =
+            failed.iter().filter(|block| good.get(&block.offset) != Some(&block.hash)).map(|block| block.peer).collect();
+        culprits.into_iter().collect()
+    }
+}
+
+fn block_hashes(data: &[u8], block_len: usize) -> impl Iterator<Item = (usize, [u8; 20])> + '_ {
+    data.chunks(block_len).enumerate().map(move |(i, block)| (i * block_len, Sha1::digest(block).into()))
+}
+
+// Addresses we won't connect to or accept connections from, shared by every
+// torrent in a session
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: Mutex<BTreeSet<IpAddr>>,
+}
+
+impl BanList {
+    pub fn new(banned: impl IntoIterator<Item = IpAddr>) -> Self {
+        BanList { banned: Mutex::new(banned.into_iter().collect()) }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap_or_else(|e| e.into_inner()).contains(&ip)
+    }
+
+    // Whether it's newly banned
+    pub fn ban(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap_or_else(|e| e.into_inner()).insert(ip)
+    }
+
+    pub fn list(&self) -> Vec<IpAddr> {
+        self.banned.lock().unwrap_or_else(|e| e.into_inner()).iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn test_blames_the_peer_with_the_bad_block() {
+        // A short last block too
+        let good: Vec<u8> = (0..40u8).collect();
+        let mut bad = good.clone();
+        bad[35] ^= 0xff;
+
+        let mut smart_ban = SmartBan::new(16);
+        smart_ban.failed(3, ip(1), &bad);
+        smart_ban.failed(3, ip(2), &good);
+        assert!(smart_ban.verified(2, &good).is_empty());
+        assert_eq!(smart_ban.verified(3, &good), vec![ip(1)]);
+        // Only once
+        assert!(smart_ban.verified(3, &good).is_empty());
+    }
+
+    #[test]
+    fn test_blames_every_peer_that_sent_bad_blocks() {
+        let good = vec![7u8; 32];
+        let mut smart_ban = SmartBan::new(16);
+        for (peer, at) in [(ip(3), 31), (ip(1), 0), (ip(3), 2)] {
+            let mut bad = good.clone();
+            bad[at] = 0;
+            smart_ban.failed(0, peer, &bad);
+        }
+        assert_eq!(smart_ban.verified(0, &good), vec![ip(1), ip(3)]);
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let bans = BanList::new([ip(2)]);
+        assert!(bans.ban(ip(1)));
+        assert!(!bans.ban(ip(2)));
+        assert!(bans.contains(ip(1)) && !bans.contains(ip(3)));
+        assert_eq!(bans.list(), vec![ip(1), ip(2)]);
+    }
+}
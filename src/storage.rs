@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::types::{Files, Info};
+
+struct FileSpan {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+// Maps the torrent's byte stream onto files on disk. A single-file torrent is
+// written to `root` itself, a multi-file one to files below the `root`
+// directory.
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<FileSpan>,
+    piece_length: u64,
+}
+
+// Torrents come from strangers, so a name or path part they pick has to be a
+// single plain file name or it could write outside the directory it's put in
+pub fn file_name(name: &str) -> Result<&str> {
+    match Path::new(name).components().collect::<Vec<_>>()[..] {
+        [Component::Normal(part)] if part == name => Ok(name),
+        _ => Err(anyhow!("Unsafe path component in torrent: {:?}", name)),
+    }
+}
+
+impl Storage {
+    pub fn new(info: &Info, root: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        match &info.files {
+            Files::Single { length } => files.push(FileSpan { path: root.to_path_buf(), offset: 0, length: *length as u64 }),
+            Files::Multiple { files: entries } => {
+                let mut offset = 0;
+                for entry in entries {
+                    let mut path = root.to_path_buf();
+                    for part in &entry.path {
+                        path.push(file_name(part)?);
+                    }
+                    files.push(FileSpan { path, offset, length: entry.length as u64 });
+                    offset += entry.length as u64;
+                }
+            }
+        }
+        Ok(Storage { root: root.to_path_buf(), files, piece_length: info.piece_length as u64 })
+    }
+
+    // Where a torrent goes in `dir` when no output path was given
+    pub fn default_path(info: &Info, dir: &Path) -> Result<PathBuf> {
+        Ok(dir.join(file_name(&info.name)?))
+    }
+
+    // Creates every file at its full length, keeping whatever is already there
+    pub fn allocate(&self) -> Result<()> {
+        for file in &self.files {
+            if let Some(parent) = file.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&file.path)?;
+            if handle.metadata()?.len() != file.length {
+                handle.set_len(file.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        self.for_each_span(index as u64 * self.piece_length, data.len(), |file, file_offset, range| {
+            let mut handle = fs::OpenOptions::new().write(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.write_all(&data[range])?;
+            Ok(())
+        })
+    }
+
+    pub fn read_piece(&self, index: u32, length: usize) -> Result<Vec<u8>> {
+        self.read_block(index, 0, length)
+    }
+
+    // Reads `length` bytes starting `begin` bytes into the piece
+    pub fn read_block(&self, index: u32, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        self.for_each_span(index as u64 * self.piece_length + begin as u64, length, |file, file_offset, range| {
+            let mut handle = fs::File::open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.read_exact(&mut data[range])?;
+            Ok(())
+        })?;
+        Ok(data)
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        for file in &self.files {
+            fs::OpenOptions::new().write(true).open(&file.path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Deletes every file, then whatever directories that leaves empty below
+    // the root
+    pub fn remove(&self) -> Result<()> {
+        for file in &self.files {
+            match fs::remove_file(&file.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let mut dir = file.path.parent();
+            while let Some(parent) = dir.filter(|dir| dir.starts_with(&self.root)) {
+                if fs::remove_dir(parent).is_err() {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+        Ok(())
+    }
+
+    // Calls f with each file the byte range starting at `start` overlaps, the
+    // offset into that file and the part of the range that goes there
+    fn for_each_span<F>(&self, start: u64, length: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(&FileSpan, u64, std::ops::Range<usize>) -> Result<()>,
+    {
+        let end = start + length as u64;
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let from = start.max(file.offset);
+            let to = end.min(file_end);
+            f(file, from - file.offset, (from - start) as usize..(to - start) as usize)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{File, Hashes};
+
+    fn info(files: Files) -> Info {
+        Info { name: "test".to_string(), piece_length: 4, pieces: Hashes(Vec::new()), files }
+    }
+
+    #[test]
+    fn test_pieces_span_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = vec![
+            File { length: 3, path: vec!["a".to_string()] },
+            File { length: 6, path: vec!["sub".to_string(), "b".to_string()] },
+        ];
+        let storage = Storage::new(&info(Files::Multiple { files }), dir.path()).unwrap();
+        storage.allocate().unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(1, b"4567").unwrap();
+        storage.write_piece(2, b"8").unwrap();
+        assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"012");
+        assert_eq!(fs::read(dir.path().join("sub/b")).unwrap(), b"345678");
+        assert_eq!(storage.read_piece(1, 4).unwrap(), b"4567");
+        assert_eq!(storage.read_block(0, 2, 3).unwrap(), b"234");
+
+        storage.remove().unwrap();
+        // The root was only holding the torrent's files
+        assert!(!dir.path().exists());
+    }
+
+    #[test]
+    fn test_rejects_unsafe_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for part in ["..", "/etc", "a/b", "", ".", "a/", "./a"] {
+            let files = vec![File { length: 1, path: vec![part.to_string()] }];
+            assert!(Storage::new(&info(Files::Multiple { files }), dir.path()).is_err(), "{:?}", part);
+            let mut single = info(Files::Single { length: 1 });
+            single.name = part.to_string();
+            assert!(Storage::default_path(&single, dir.path()).is_err(), "{:?}", part);
+        }
+        let mut single = info(Files::Single { length: 1 });
+        single.name = "a..b".to_string();
+        assert_eq!(Storage::default_path(&single, dir.path()).unwrap(), dir.path().join("a..b"));
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, time::Duration};
+
+use super::{AnnounceRequest, AnnounceResponse, ScrapeStats, TrackerError, DEFAULT_INTERVAL};
+use crate::types::{ScrapeResponse, TrackerResponse};
+
+pub fn urlencode_u8_slice(slice: &[u8]) -> String {
+    let mut escaped_slice = String::with_capacity(slice.len() * 3);
+    for byte in slice {
+        escaped_slice.push_str(format!("%{:02X}", byte).as_str());
+    }
+    escaped_slice
+}
+
+pub fn announce(announce_url: &str, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+    // Build url with query parameters
+    let mut url = announce_url.to_string();
+    if url.contains('?') {
+        url.push('&');
+    } else {
+        url.push('?');
+    }
+    url.push_str(format!("info_hash={}", urlencode_u8_slice(request.info_hash)).as_str());
+    url.push_str(format!("&peer_id={}", urlencode_u8_slice(request.peer_id)).as_str());
+    url.push_str(format!("&port={}", request.port).as_str());
+    url.push_str(format!("&uploaded={}", request.uploaded).as_str());
+    url.push_str(format!("&downloaded={}", request.downloaded).as_str());
+    url.push_str(format!("&left={}", request.left).as_str());
+    url.push_str("&compact=1");
+    if let Some(event) = request.event.as_str() {
+        url.push_str(format!("&event={}", event).as_str());
+    }
+    if let Some(numwant) = request.numwant {
+        url.push_str(format!("&numwant={}", numwant).as_str());
+    }
+    url.push_str(format!("&key={:08X}", request.key).as_str());
+    if let Some(tracker_id) = request.tracker_id {
+        url.push_str(format!("&trackerid={}", urlencode_u8_slice(tracker_id.as_bytes())).as_str());
+    }
+
+    let response = reqwest::blocking::get(&url)?.bytes()?;
+    parse_response(&response)
+}
+
+pub fn scrape(scrape_url: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let mut url = scrape_url.to_string();
+    for info_hash in info_hashes {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str(format!("info_hash={}", urlencode_u8_slice(info_hash)).as_str());
+    }
+
+    let response = reqwest::blocking::get(&url)?.bytes()?;
+    parse_scrape_response(&response)
+}
+
+fn parse_scrape_response(response: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let response: ScrapeResponse = serde_bencode::from_bytes(response)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    Ok(response
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, file)| {
+            let info_hash: [u8; 20] = info_hash.as_slice().try_into().ok()?;
+            let stats = ScrapeStats {
+                seeders: file.complete as u32,
+                completed: file.downloaded as u32,
+                leechers: file.incomplete as u32,
+            };
+            Some((info_hash, stats))
+        })
+        .collect())
+}
+
+fn parse_response(response: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let response: TrackerResponse = serde_bencode::from_bytes(response)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    let peers = response.peers();
+    Ok(AnnounceResponse {
+        interval: response.interval.map(|secs| Duration::from_secs(secs as u64)).unwrap_or(DEFAULT_INTERVAL),
+        min_interval: response.min_interval.map(|secs| Duration::from_secs(secs as u64)),
+        tracker_id: response.tracker_id,
+        warning: response.warning_message,
+        seeders: response.complete.map(|n| n as u32),
+        leechers: response.incomplete.map(|n| n as u32),
+        peers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_failure_response() {
+        let err = parse_response(b"d14:failure reason17:unknown info hashe").unwrap_err();
+        assert!(matches!(err, TrackerError::Failure(reason) if reason == "unknown info hash"));
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let stats = parse_scrape_response(
+            b"d5:filesd20:\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xabd8:completei5e10:downloadedi50e10:incompletei10eeee",
+        )
+        .unwrap();
+        assert_eq!(stats.get(&[0xab; 20]), Some(&ScrapeStats { seeders: 5, completed: 50, leechers: 10 }));
+    }
+
+    #[test]
+    fn test_parse_full_response() {
+        let response = parse_response(
+            b"d8:completei4e10:incompletei2e8:intervali900e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!(response.interval, Duration::from_secs(900));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(60)));
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        assert_eq!(response.seeders, Some(4));
+        assert_eq!(response.leechers, Some(2));
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+}
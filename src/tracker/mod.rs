@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use thiserror::Error;
+
+mod http;
+pub mod server;
+mod session;
+mod tiers;
+pub mod udp;
+
+pub use self::{
+    session::{TrackerEvent, TrackerSession},
+    tiers::TrackerTiers,
+};
+
+pub const LISTEN_PORT: u16 = 6881;
+pub const DEFAULT_NUMWANT: u32 = 50;
+// Used when a tracker doesn't tell us how often to come back
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("Tracker failure: {0}")]
+    Failure(String),
+    #[error("Unsupported tracker URL: {0}")]
+    UnsupportedUrl(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Bencode(#[from] serde_bencode::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest<'a> {
+    pub info_hash: &'a [u8; 20],
+    pub peer_id: &'a [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub numwant: Option<u32>,
+    pub key: u32,
+    pub tracker_id: Option<&'a str>,
+}
+
+impl<'a> AnnounceRequest<'a> {
+    pub fn new(info_hash: &'a [u8; 20], peer_id: &'a [u8; 20], left: u64) -> Self {
+        AnnounceRequest {
+            info_hash,
+            peer_id,
+            port: LISTEN_PORT,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: AnnounceEvent::None,
+            numwant: None,
+            key: 0,
+            tracker_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceResponse {
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    pub tracker_id: Option<String>,
+    pub warning: Option<String>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddr>,
+}
+
+pub fn announce(announce_url: &str, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+    if announce_url.starts_with("udp://") {
+        let mut tracker = udp::UdpTracker::connect(announce_url)?;
+        let response = tracker.announce(request)?;
+        Ok(AnnounceResponse {
+            interval: Duration::from_secs(response.interval as u64),
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            seeders: Some(response.seeders),
+            leechers: Some(response.leechers),
+            peers: response.peers,
+        })
+    } else if announce_url.starts_with("http://") || announce_url.starts_with("https://") {
+        Ok(http::announce(announce_url, request)?)
+    } else {
+        Err(anyhow!(TrackerError::UnsupportedUrl(announce_url.to_string())))
+    }
+}
+
+// By convention the scrape URL is the announce URL with the "announce" at
+// the start of the last path component replaced by "scrape". Trackers
+// without such a component don't support scraping.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    if announce_url.starts_with("udp://") {
+        return Some(announce_url.to_string());
+    }
+    let path_end = announce_url.find('?').unwrap_or(announce_url.len());
+    let last_slash = announce_url[..path_end].rfind('/')?;
+    if !announce_url[last_slash + 1..path_end].starts_with("announce") {
+        return None;
+    }
+    Some(format!(
+        "{}scrape{}",
+        &announce_url[..last_slash + 1],
+        &announce_url[last_slash + 1 + "announce".len()..]
+    ))
+}
+
+pub fn scrape(announce_url: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let url = scrape_url(announce_url).ok_or_else(|| anyhow!("Tracker {} does not support scrape", announce_url))?;
+    if url.starts_with("udp://") {
+        let mut tracker = udp::UdpTracker::connect(&url)?;
+        let mut stats = HashMap::new();
+        for chunk in info_hashes.chunks(udp::MAX_SCRAPE_HASHES) {
+            stats.extend(chunk.iter().copied().zip(tracker.scrape(chunk)?));
+        }
+        Ok(stats)
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(http::scrape(&url, info_hashes)?)
+    } else {
+        Err(anyhow!(TrackerError::UnsupportedUrl(announce_url.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(scrape_url("http://example.com/announce").as_deref(), Some("http://example.com/scrape"));
+        assert_eq!(scrape_url("http://example.com/x/announce").as_deref(), Some("http://example.com/x/scrape"));
+        assert_eq!(scrape_url("http://example.com/announce.php").as_deref(), Some("http://example.com/scrape.php"));
+        assert_eq!(
+            scrape_url("http://example.com/announce?x2%0644").as_deref(),
+            Some("http://example.com/scrape?x2%0644")
+        );
+        assert_eq!(scrape_url("http://example.com/a").as_deref(), None);
+        assert_eq!(scrape_url("http://example.com/announce?x=2/4").as_deref(), Some("http://example.com/scrape?x=2/4"));
+        assert_eq!(scrape_url("http://example.com/x%064announce").as_deref(), None);
+        assert_eq!(scrape_url("udp://example.com:80/announce").as_deref(), Some("udp://example.com:80/announce"));
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::http_server::{self, Request, Response};
+
+const DEFAULT_SERVER_NUMWANT: usize = 50;
+const MAX_SERVER_NUMWANT: usize = 200;
+
+#[derive(Debug, Clone)]
+struct SwarmPeer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    completed: usize,
+}
+
+impl Swarm {
+    fn expire(&mut self, timeout: Duration) {
+        self.peers.retain(|_, peer| peer.last_seen.elapsed() < timeout);
+    }
+
+    fn seeders(&self) -> usize {
+        self.peers.values().filter(|peer| peer.left == 0).count()
+    }
+
+    fn leechers(&self) -> usize {
+        self.peers.len() - self.seeders()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AnnounceReply {
+    complete: usize,
+    incomplete: usize,
+    interval: u64,
+    #[serde(rename = "min interval")]
+    min_interval: u64,
+    peers: PeerList,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Full(Vec<PeerEntry>),
+}
+
+#[derive(Debug, Serialize)]
+struct PeerEntry {
+    ip: String,
+    #[serde(rename = "peer id", skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
+    port: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeReply {
+    files: HashMap<ByteBuf, ScrapeEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeEntry {
+    complete: usize,
+    downloaded: usize,
+    incomplete: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct FailureReply {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+// In-memory BitTorrent tracker speaking the HTTP protocol, answering in the
+// format types::TrackerResponse and types::ScrapeResponse parse.
+pub struct TrackerServer {
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    interval: Duration,
+    peer_timeout: Duration,
+    whitelist: Option<HashSet<[u8; 20]>>,
+}
+
+impl TrackerServer {
+    pub fn new(interval: Duration) -> Self {
+        TrackerServer {
+            swarms: Mutex::new(HashMap::new()),
+            interval,
+            // Give peers one missed announce before forgetting them
+            peer_timeout: interval * 2,
+            whitelist: None,
+        }
+    }
+
+    pub fn set_whitelist(&mut self, whitelist: HashSet<[u8; 20]>) {
+        self.whitelist = Some(whitelist);
+    }
+
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let server = Arc::new(self);
+        http_server::serve(listener, Arc::new(move |request| server.handle(request)))
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        if request.method != "GET" {
+            return Response::new(405, "text/plain", b"Method Not Allowed".to_vec());
+        }
+        let reply = match request.path.as_str() {
+            "/announce" => self.announce(request),
+            "/scrape" => self.scrape(request),
+            _ => return Response::not_found(),
+        };
+        let body = reply.unwrap_or_else(|e| {
+            serde_bencode::to_bytes(&FailureReply { failure_reason: e.to_string() }).unwrap_or_default()
+        });
+        Response::new(200, "text/plain", body)
+    }
+
+    fn announce(&self, request: &Request) -> Result<Vec<u8>> {
+        let info_hash = self.info_hash_param(request.query_value("info_hash"))?;
+        let peer_id: [u8; 20] = request
+            .query_value("peer_id")
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| anyhow!("missing or invalid peer_id"))?;
+        let port: u16 = parse_param(request, "port")?.ok_or_else(|| anyhow!("missing port"))?;
+        // Required by BEP 3, and without it there's no telling a seeder from a leecher
+        let left: u64 = parse_param(request, "left")?.ok_or_else(|| anyhow!("missing left"))?;
+        let numwant = parse_param::<usize>(request, "numwant")?.unwrap_or(DEFAULT_SERVER_NUMWANT).min(MAX_SERVER_NUMWANT);
+        let compact = request.query_str("compact") != Some("0");
+        let no_peer_id = request.query_str("no_peer_id") == Some("1");
+        let ip = match request.query_str("ip").map(str::parse::<IpAddr>) {
+            Some(Ok(ip)) => ip,
+            _ => request.remote_addr.ip(),
+        };
+
+        let mut swarms = self.swarms.lock().map_err(|_| anyhow!("tracker state poisoned"))?;
+        let swarm = swarms.entry(info_hash).or_default();
+        swarm.expire(self.peer_timeout);
+        match request.query_str("event") {
+            Some("stopped") => {
+                swarm.peers.remove(&peer_id);
+            }
+            event => {
+                // Only a peer we saw still downloading counts, so a repeated
+                // completed event doesn't count the same download twice
+                let was_leeching = swarm.peers.get(&peer_id).is_some_and(|peer| peer.left > 0);
+                if event == Some("completed") && was_leeching {
+                    swarm.completed += 1;
+                }
+                let peer = SwarmPeer { addr: SocketAddr::new(ip, port), left, last_seen: Instant::now() };
+                swarm.peers.insert(peer_id, peer);
+            }
+        }
+
+        let others = swarm.peers.iter().filter(|(id, _)| **id != peer_id).take(numwant);
+        let (peers, peers6) = if compact {
+            let mut peers = Vec::new();
+            let mut peers6 = Vec::new();
+            for (_, peer) in others {
+                match peer.addr.ip() {
+                    IpAddr::V4(ip) => {
+                        peers.extend_from_slice(&ip.octets());
+                        peers.extend_from_slice(&peer.addr.port().to_be_bytes());
+                    }
+                    IpAddr::V6(ip) => {
+                        peers6.extend_from_slice(&ip.octets());
+                        peers6.extend_from_slice(&peer.addr.port().to_be_bytes());
+                    }
+                }
+            }
+            let peers6 = if peers6.is_empty() { None } else { Some(ByteBuf::from(peers6)) };
+            (PeerList::Compact(ByteBuf::from(peers)), peers6)
+        } else {
+            let peers = others
+                .map(|(id, peer)| PeerEntry {
+                    ip: peer.addr.ip().to_string(),
+                    peer_id: if no_peer_id { None } else { Some(ByteBuf::from(id.to_vec())) },
+                    port: peer.addr.port(),
+                })
+                .collect();
+            (PeerList::Full(peers), None)
+        };
+
+        let reply = AnnounceReply {
+            complete: swarm.seeders(),
+            incomplete: swarm.leechers(),
+            interval: self.interval.as_secs(),
+            min_interval: self.interval.as_secs() / 2,
+            peers,
+            peers6,
+        };
+        Ok(serde_bencode::to_bytes(&reply)?)
+    }
+
+    fn scrape(&self, request: &Request) -> Result<Vec<u8>> {
+        let mut swarms = self.swarms.lock().map_err(|_| anyhow!("tracker state poisoned"))?;
+        let requested = request.query_values("info_hash");
+        let info_hashes: Vec<[u8; 20]> = if requested.is_empty() {
+            swarms.keys().copied().collect()
+        } else {
+            requested.into_iter().map(|v| self.info_hash_param(Some(v))).collect::<Result<_>>()?
+        };
+
+        let mut files = HashMap::new();
+        for info_hash in info_hashes {
+            let entry = match swarms.get_mut(&info_hash) {
+                Some(swarm) => {
+                    swarm.expire(self.peer_timeout);
+                    ScrapeEntry { complete: swarm.seeders(), downloaded: swarm.completed, incomplete: swarm.leechers() }
+                }
+                None => ScrapeEntry { complete: 0, downloaded: 0, incomplete: 0 },
+            };
+            files.insert(ByteBuf::from(info_hash.to_vec()), entry);
+        }
+        Ok(serde_bencode::to_bytes(&ScrapeReply { files })?)
+    }
+
+    fn info_hash_param(&self, value: Option<&[u8]>) -> Result<[u8; 20]> {
+        let info_hash: [u8; 20] =
+            value.and_then(|v| v.try_into().ok()).ok_or_else(|| anyhow!("missing or invalid info_hash"))?;
+        if let Some(whitelist) = &self.whitelist {
+            if !whitelist.contains(&info_hash) {
+                return Err(anyhow!("torrent not allowed on this tracker"));
+            }
+        }
+        Ok(info_hash)
+    }
+}
+
+fn parse_param<T: std::str::FromStr>(request: &Request, key: &str) -> Result<Option<T>> {
+    match request.query_str(key) {
+        Some(value) => value.parse::<T>().map(Some).map_err(|_| anyhow!("invalid {}", key)),
+        None => Ok(None),
+    }
+}
+
+// One hex-encoded info hash per line, blank lines and # comments ignored
+pub fn load_whitelist(path: &Path) -> Result<HashSet<[u8; 20]>> {
+    let mut whitelist = HashSet::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let info_hash: [u8; 20] = hex::decode(line)
+            .ok()
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| anyhow!("{}:{}: expected a 40 character hex info hash", path.display(), i + 1))?;
+        whitelist.insert(info_hash);
+    }
+    Ok(whitelist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_server::parse_query,
+        tracker::http::urlencode_u8_slice,
+        types::{ScrapeResponse, TrackerResponse},
+    };
+
+    fn request(path: &str, query: &str, remote_addr: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: parse_query(query),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote_addr: remote_addr.parse().unwrap(),
+        }
+    }
+
+    fn announce_query(peer_id: u8, port: u16, left: u64, extra: &str) -> String {
+        format!(
+            "info_hash={}&peer_id={}&port={}&left={}{}",
+            urlencode_u8_slice(&[1; 20]),
+            urlencode_u8_slice(&[peer_id; 20]),
+            port,
+            left,
+            extra
+        )
+    }
+
+    #[test]
+    fn test_announce_returns_other_peers() {
+        let server = TrackerServer::new(Duration::from_secs(60));
+        server.handle(&request("/announce", &announce_query(1, 6881, 0, "&event=started"), "10.0.0.1:5000"));
+        server.handle(&request("/announce", &announce_query(2, 6882, 0, ""), "[::1]:5000"));
+
+        let response = server.handle(&request("/announce", &announce_query(3, 6883, 0, ""), "10.0.0.3:5000"));
+        let response: TrackerResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        let mut peers = response.peers();
+        peers.sort();
+        assert_eq!(peers, vec!["10.0.0.1:6881".parse().unwrap(), "[::1]:6882".parse().unwrap()]);
+        assert_eq!(response.complete, Some(3));
+
+        let response = server.handle(&request("/announce", &announce_query(3, 6883, 0, "&compact=0"), "10.0.0.3:5000"));
+        let response: TrackerResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        assert_eq!(response.peers().len(), 2);
+    }
+
+    #[test]
+    fn test_stopped_and_scrape() {
+        let server = TrackerServer::new(Duration::from_secs(60));
+        server.handle(&request("/announce", &announce_query(1, 6881, 0, "&event=started"), "10.0.0.1:5000"));
+        server.handle(&request("/announce", &announce_query(2, 6882, 10, "&event=started"), "10.0.0.2:5000"));
+        server.handle(&request("/announce", &announce_query(2, 6882, 0, "&event=completed"), "10.0.0.2:5000"));
+        server.handle(&request("/announce", &announce_query(1, 6881, 0, "&event=stopped"), "10.0.0.1:5000"));
+
+        let query = format!("info_hash={}", urlencode_u8_slice(&[1; 20]));
+        let response = server.handle(&request("/scrape", &query, "10.0.0.3:5000"));
+        let response: ScrapeResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        let file = &response.files[&ByteBuf::from(vec![1; 20])];
+        assert_eq!((file.complete, file.downloaded, file.incomplete), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_completed_is_counted_once_per_download() {
+        let server = TrackerServer::new(Duration::from_secs(60));
+        server.handle(&request("/announce", &announce_query(1, 6881, 10, "&event=started"), "10.0.0.1:5000"));
+        server.handle(&request("/announce", &announce_query(1, 6881, 0, "&event=completed"), "10.0.0.1:5000"));
+        server.handle(&request("/announce", &announce_query(1, 6881, 0, "&event=completed"), "10.0.0.1:5000"));
+        // Never seen downloading
+        server.handle(&request("/announce", &announce_query(2, 6882, 0, "&event=completed"), "10.0.0.2:5000"));
+
+        let query = format!("info_hash={}", urlencode_u8_slice(&[1; 20]));
+        let response = server.handle(&request("/scrape", &query, "10.0.0.3:5000"));
+        let response: ScrapeResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        let file = &response.files[&ByteBuf::from(vec![1; 20])];
+        assert_eq!((file.complete, file.downloaded, file.incomplete), (2, 1, 0));
+    }
+
+    #[test]
+    fn test_announce_without_left_fails() {
+        let server = TrackerServer::new(Duration::from_secs(60));
+        let query = format!("info_hash={}&peer_id={}&port=6881", urlencode_u8_slice(&[1; 20]), urlencode_u8_slice(&[1; 20]));
+        let response = server.handle(&request("/announce", &query, "10.0.0.1:5000"));
+        let response: TrackerResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("missing left"));
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let mut server = TrackerServer::new(Duration::from_secs(60));
+        server.set_whitelist(HashSet::from([[2; 20]]));
+        let response = server.handle(&request("/announce", &announce_query(1, 6881, 0, ""), "10.0.0.1:5000"));
+        let response: TrackerResponse = serde_bencode::from_bytes(&response.body).unwrap();
+        assert_eq!(response.failure_reason.as_deref(), Some("torrent not allowed on this tracker"));
+    }
+}
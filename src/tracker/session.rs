@@ -0,0 +1,285 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use super::{
+    announce, AnnounceEvent, AnnounceRequest, TrackerTiers, DEFAULT_INTERVAL, DEFAULT_NUMWANT, LISTEN_PORT,
+};
+use crate::{ipfilter::IpFilter, random::random_u32};
+
+// After a failed announce the next one waits this long, doubling with every
+// failure in a row up to the announce interval
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerEvent {
+    Announced {
+        url: String,
+        event: AnnounceEvent,
+        peers: usize,
+        seeders: Option<u32>,
+        leechers: Option<u32>,
+        interval: Duration,
+        // How long the tracker took to answer
+        latency: Duration,
+    },
+    Warning { url: String, message: String },
+    // The tracker didn't answer or turned us down; the next one in its tier
+    // gets tried
+    Failed { url: String, error: String, latency: Duration },
+}
+
+// Keeps the state a client owes its trackers over the lifetime of a
+// download: transfer stats, the event sequence, tracker ids and the
+// re-announce schedule.
+pub struct TrackerSession {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    key: u32,
+    numwant: u32,
+    // Peers in its ranges are left out of what trackers give us
+    ip_filter: Arc<IpFilter>,
+    tiers: TrackerTiers,
+    tracker_ids: HashMap<String, String>,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    started: bool,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    last_announce: Option<Instant>,
+    // Announces that failed in a row, and when to try again
+    failures: u32,
+    retry_at: Option<Instant>,
+    events: Vec<TrackerEvent>,
+}
+
+impl TrackerSession {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], tiers: TrackerTiers, left: u64) -> Self {
+        TrackerSession {
+            info_hash,
+            peer_id,
+            port: LISTEN_PORT,
+            key: random_u32(),
+            numwant: DEFAULT_NUMWANT,
+            ip_filter: Arc::new(IpFilter::default()),
+            tiers,
+            tracker_ids: HashMap::new(),
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            started: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            last_announce: None,
+            failures: 0,
+            retry_at: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    pub fn set_numwant(&mut self, numwant: u32) {
+        self.numwant = numwant;
+    }
+
+    pub fn set_ip_filter(&mut self, ip_filter: Arc<IpFilter>) {
+        self.ip_filter = ip_filter;
+    }
+
+    pub fn add_uploaded(&mut self, bytes: u64) {
+        self.uploaded += bytes;
+    }
+
+    pub fn add_downloaded(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+        self.left = self.left.saturating_sub(bytes);
+    }
+
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.last_announce.map(|last| last + self.interval)
+    }
+
+    pub fn announce_due(&self) -> bool {
+        let now = Instant::now();
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            return false;
+        }
+        self.next_announce().is_none_or(|next| now >= next)
+    }
+
+    // Trackers may ask us not to come back earlier than `min interval`
+    pub fn can_announce(&self) -> bool {
+        match (self.last_announce, self.min_interval) {
+            (Some(last), Some(min_interval)) => last.elapsed() >= min_interval,
+            _ => true,
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<TrackerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn start(&mut self) -> Result<Vec<SocketAddr>> {
+        let peers = self.announce_event(AnnounceEvent::Started)?;
+        self.started = true;
+        Ok(peers)
+    }
+
+    // An announce out of schedule, which is refused while the tracker's
+    // `min interval` hasn't passed
+    pub fn announce(&mut self) -> Result<Vec<SocketAddr>> {
+        if !self.can_announce() {
+            let (last, min_interval) = (self.last_announce.unwrap_or_else(Instant::now), self.min_interval.unwrap_or_default());
+            let wait = min_interval.saturating_sub(last.elapsed());
+            return Err(anyhow!("The tracker asked not to be announced to again for another {}s", wait.as_secs_f64().ceil().max(1.0)));
+        }
+        self.announce_now()
+    }
+
+    pub fn announce_if_due(&mut self) -> Result<Option<Vec<SocketAddr>>> {
+        if !self.announce_due() {
+            return Ok(None);
+        }
+        self.announce_now().map(Some)
+    }
+
+    fn announce_now(&mut self) -> Result<Vec<SocketAddr>> {
+        if !self.started {
+            return self.start();
+        }
+        self.announce_event(AnnounceEvent::None)
+    }
+
+    pub fn complete(&mut self) -> Result<()> {
+        self.left = 0;
+        self.announce_event(AnnounceEvent::Completed)?;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        if !self.started {
+            return Ok(());
+        }
+        self.announce_event(AnnounceEvent::Stopped)?;
+        self.started = false;
+        Ok(())
+    }
+
+    fn announce_event(&mut self, event: AnnounceEvent) -> Result<Vec<SocketAddr>> {
+        let result = self.send_announce(event);
+        match &result {
+            Ok(_) => (self.failures, self.retry_at) = (0, None),
+            Err(_) => {
+                self.failures += 1;
+                let delay = RETRY_DELAY.saturating_mul(1 << (self.failures - 1).min(16)).min(self.interval);
+                self.retry_at = Some(Instant::now() + delay);
+            }
+        }
+        result
+    }
+
+    fn send_announce(&mut self, event: AnnounceEvent) -> Result<Vec<SocketAddr>> {
+        let numwant = if event == AnnounceEvent::Stopped { 0 } else { self.numwant };
+        let TrackerSession { info_hash, peer_id, tiers, tracker_ids, events, .. } = self;
+        let mut request = AnnounceRequest::new(info_hash, peer_id, self.left);
+        request.port = self.port;
+        request.uploaded = self.uploaded;
+        request.downloaded = self.downloaded;
+        request.event = event;
+        request.numwant = Some(numwant);
+        request.key = self.key;
+
+        let response = tiers.announce(|url| {
+            let mut request = request.clone();
+            request.tracker_id = tracker_ids.get(url).map(String::as_str);
+            let started = Instant::now();
+            let response = match announce(url, &request) {
+                Ok(response) => response,
+                Err(e) => {
+                    let (error, latency) = (format!("{:#}", e), started.elapsed());
+                    events.push(TrackerEvent::Failed { url: url.to_string(), error, latency });
+                    return Err(e);
+                }
+            };
+            let latency = started.elapsed();
+            if let Some(tracker_id) = &response.tracker_id {
+                tracker_ids.insert(url.to_string(), tracker_id.clone());
+            }
+            if let Some(message) = &response.warning {
+                events.push(TrackerEvent::Warning { url: url.to_string(), message: message.clone() });
+            }
+            events.push(TrackerEvent::Announced {
+                url: url.to_string(),
+                event,
+                peers: response.peers.len(),
+                seeders: response.seeders,
+                leechers: response.leechers,
+                interval: response.interval,
+                latency,
+            });
+            Ok(response)
+        })?;
+
+        self.interval = response.interval;
+        self.min_interval = response.min_interval;
+        self.last_announce = Some(Instant::now());
+        let mut peers = response.peers;
+        let before = peers.len();
+        peers.retain(|peer| !self.ip_filter.is_blocked(peer.ip()));
+        if peers.len() < before {
+            debug!("Left out {} peers blocked by the IP filter", before - peers.len());
+        }
+        Ok(peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_server, tracker::server::TrackerServer};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_manual_announce_waits_for_min_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        // Asks for a min interval of half the interval
+        let server = Arc::new(TrackerServer::new(Duration::from_secs(60)));
+        std::thread::spawn(move || http_server::serve(listener, Arc::new(move |request| server.handle(request))));
+
+        let mut tracker = TrackerSession::new([1; 20], [2; 20], TrackerTiers::new(vec![vec![url]]), 100);
+        assert!(tracker.can_announce());
+        tracker.start().unwrap();
+        assert!(!tracker.can_announce());
+        let error = tracker.announce().unwrap_err().to_string();
+        assert!(error.contains("for another 30s"), "{}", error);
+        // Nor is a scheduled one due yet
+        assert_eq!(tracker.announce_if_due().unwrap(), None);
+    }
+
+    #[test]
+    fn test_failed_announces_back_off() {
+        // Nothing listens there
+        let tiers = TrackerTiers::new(vec![vec!["http://127.0.0.1:1/announce".to_string()]]);
+        let mut tracker = TrackerSession::new([1; 20], [2; 20], tiers, 100);
+        assert!(tracker.announce_due());
+        assert!(tracker.start().is_err());
+        assert!(!tracker.announce_due());
+        assert_eq!(tracker.announce_if_due().unwrap(), None);
+        let first = tracker.retry_at.unwrap();
+        tracker.retry_at = Some(Instant::now());
+        assert!(tracker.announce_if_due().is_err());
+        assert!(tracker.retry_at.unwrap() >= first + RETRY_DELAY);
+        assert_eq!(tracker.failures, 2);
+    }
+}
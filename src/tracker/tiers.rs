@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use tracing::warn;
+
+use crate::{random::shuffle, types::Torrent};
+
+// Tracker list with the BEP 12 failover rules: tiers are tried in order,
+// trackers within a tier in random order, and a tracker that answers is
+// moved to the front of its tier so it's tried first next time.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        for tier in tiers.iter_mut() {
+            shuffle(tier);
+        }
+        TrackerTiers { tiers }
+    }
+
+    pub fn from_torrent(torrent: &Torrent) -> Self {
+        Self::new(torrent.announce_tiers())
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn announce<T, F>(&mut self, mut announce: F) -> Result<T>
+    where
+        F: FnMut(&str) -> Result<T>,
+    {
+        let mut last_err = None;
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                match announce(&tier[i]) {
+                    Ok(value) => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(value);
+                    }
+                    Err(e) => {
+                        warn!("Tracker {} failed: {}", tier[i], e);
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("Torrent has no trackers")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(urls: &[&[&str]]) -> Vec<Vec<String>> {
+        urls.iter().map(|tier| tier.iter().map(|url| url.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn test_announce_list_supersedes_announce() {
+        let torrent: Torrent = serde_bencode::from_bytes(
+            b"d8:announce5:http:13:announce-listll3:udp3:wsselel4:httpee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee",
+        )
+        .unwrap();
+        assert_eq!(torrent.announce_tiers(), tiers(&[&["udp", "wss"], &["http"]]));
+
+        let torrent: Torrent =
+            serde_bencode::from_bytes(b"d8:announce5:http:4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces0:ee").unwrap();
+        assert_eq!(torrent.announce_tiers(), tiers(&[&["http:"]]));
+    }
+
+    #[test]
+    fn test_announce_falls_back_to_next_tier() {
+        let mut trackers = TrackerTiers::new(tiers(&[&["dead1", "dead2"], &["alive"]]));
+        let mut attempts = Vec::new();
+        let result = trackers.announce(|url| {
+            attempts.push(url.to_string());
+            if url == "alive" { Ok(url.to_string()) } else { Err(anyhow!("down")) }
+        });
+        assert_eq!(result.unwrap(), "alive");
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[2], "alive");
+    }
+
+    #[test]
+    fn test_announce_promotes_working_tracker() {
+        let mut trackers = TrackerTiers::new(tiers(&[&["a", "b", "c"]]));
+        let working = trackers.tiers()[0][2].clone();
+        trackers.announce(|url| if url == working { Ok(()) } else { Err(anyhow!("down")) }).unwrap();
+        assert_eq!(trackers.tiers()[0][0], working);
+        assert_eq!(trackers.tiers()[0].len(), 3);
+    }
+
+    #[test]
+    fn test_announce_without_trackers() {
+        let mut trackers = TrackerTiers::new(Vec::new());
+        let err = trackers.announce(|_| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "Torrent has no trackers");
+    }
+}
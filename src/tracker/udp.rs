@@ -0,0 +1,422 @@
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use super::{AnnounceEvent, AnnounceRequest, ScrapeStats, TrackerError};
+use crate::{
+    random::random_u32,
+    types::{compact_peers_v4, compact_peers_v6},
+};
+
+// See BEP 15: https://www.bittorrent.org/beps/bep_0015.html
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// A connection id may be used for one minute after it was received
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+// Retransmit after 15 * 2 ^ n seconds, n going from 0 up to 8
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_RETRANSMITS: u32 = 8;
+
+// Trackers can return at most ~74 torrents per scrape in a single packet
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+#[derive(Debug, Clone)]
+pub struct UdpAnnounceResponse {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    connection_ttl: Duration,
+    base_timeout: Duration,
+    max_retransmits: u32,
+}
+
+impl UdpTracker {
+    pub fn connect(announce_url: &str) -> Result<Self> {
+        let url = Url::parse(announce_url)?;
+        if url.scheme() != "udp" {
+            return Err(anyhow!("Expected udp:// tracker URL, got {}", announce_url));
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("Tracker URL has no host: {}", announce_url))?;
+        let port = url.port().ok_or_else(|| anyhow!("Tracker URL has no port: {}", announce_url))?;
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Could not resolve tracker host {}", host))?;
+        Self::with_addr(addr)
+    }
+
+    pub fn with_addr(addr: SocketAddr) -> Result<Self> {
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        Ok(UdpTracker {
+            socket,
+            connection: None,
+            connection_ttl: CONNECTION_ID_TTL,
+            base_timeout: BASE_TIMEOUT,
+            max_retransmits: MAX_RETRANSMITS,
+        })
+    }
+
+    pub fn set_timeouts(&mut self, base_timeout: Duration, max_retransmits: u32) {
+        self.base_timeout = base_timeout;
+        self.max_retransmits = max_retransmits;
+    }
+
+    pub fn announce(&mut self, request: &AnnounceRequest) -> Result<UdpAnnounceResponse> {
+        let event = match request.event {
+            AnnounceEvent::None => 0u32,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        };
+        let response = self.exchange(ACTION_ANNOUNCE, |connection_id, transaction_id| {
+            let mut buf = Vec::with_capacity(98);
+            buf.extend_from_slice(&connection_id.to_be_bytes());
+            buf.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            buf.extend_from_slice(&transaction_id.to_be_bytes());
+            buf.extend_from_slice(request.info_hash);
+            buf.extend_from_slice(request.peer_id);
+            buf.extend_from_slice(&request.downloaded.to_be_bytes());
+            buf.extend_from_slice(&request.left.to_be_bytes());
+            buf.extend_from_slice(&request.uploaded.to_be_bytes());
+            buf.extend_from_slice(&event.to_be_bytes());
+            buf.extend_from_slice(&0u32.to_be_bytes()); // IP address, 0 means use the sender's
+            buf.extend_from_slice(&request.key.to_be_bytes());
+            // num_want, -1 is the tracker's default
+            buf.extend_from_slice(&request.numwant.map_or(-1, |n| n as i32).to_be_bytes());
+            buf.extend_from_slice(&request.port.to_be_bytes());
+            buf
+        })?;
+        if response.len() < 20 {
+            return Err(anyhow!("Announce response too short: {} bytes", response.len()));
+        }
+        // Trackers answer with IPv6 peers when we talk to them over IPv6
+        let peers = &response[20..];
+        let peers = if self.socket.peer_addr()?.is_ipv6() { compact_peers_v6(peers) } else { compact_peers_v4(peers) }
+            .ok_or_else(|| anyhow!("Invalid peer list of {} bytes in announce response", peers.len()))?;
+        Ok(UdpAnnounceResponse {
+            interval: read_u32(&response, 8),
+            leechers: read_u32(&response, 12),
+            seeders: read_u32(&response, 16),
+            peers,
+        })
+    }
+
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        if info_hashes.is_empty() || info_hashes.len() > MAX_SCRAPE_HASHES {
+            return Err(anyhow!(
+                "Can scrape between 1 and {} info hashes at once, got {}", MAX_SCRAPE_HASHES, info_hashes.len()
+            ));
+        }
+        let response = self.exchange(ACTION_SCRAPE, |connection_id, transaction_id| {
+            let mut buf = Vec::with_capacity(16 + 20 * info_hashes.len());
+            buf.extend_from_slice(&connection_id.to_be_bytes());
+            buf.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            buf.extend_from_slice(&transaction_id.to_be_bytes());
+            for info_hash in info_hashes {
+                buf.extend_from_slice(info_hash);
+            }
+            buf
+        })?;
+        if response.len() != 8 + 12 * info_hashes.len() {
+            return Err(anyhow!(
+                "Expected scrape response for {} torrents, got {} bytes", info_hashes.len(), response.len()
+            ));
+        }
+        Ok(response[8..]
+            .chunks(12)
+            .map(|chunk| ScrapeStats {
+                seeders: read_u32(chunk, 0),
+                completed: read_u32(chunk, 4),
+                leechers: read_u32(chunk, 8),
+            })
+            .collect())
+    }
+
+    fn connection_id(&mut self) -> Result<u64> {
+        if let Some((connection_id, received_at)) = self.connection {
+            if received_at.elapsed() < self.connection_ttl {
+                return Ok(connection_id);
+            }
+        }
+        let response = self.exchange(ACTION_CONNECT, |_, transaction_id| {
+            let mut buf = Vec::with_capacity(16);
+            buf.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+            buf.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            buf.extend_from_slice(&transaction_id.to_be_bytes());
+            buf
+        })?;
+        if response.len() < 16 {
+            return Err(anyhow!("Connect response too short: {} bytes", response.len()));
+        }
+        let connection_id = u64::from_be_bytes(response[8..16].try_into()?);
+        self.connection = Some((connection_id, Instant::now()));
+        Ok(connection_id)
+    }
+
+    // Sends the request built by `build` and waits for the matching response,
+    // retransmitting with a fresh transaction id on the BEP 15 schedule. The
+    // connection id is re-checked before every attempt since it may expire
+    // while we're waiting.
+    fn exchange<F>(&mut self, action: u32, build: F) -> Result<Vec<u8>>
+    where
+        F: Fn(u64, u32) -> Vec<u8>,
+    {
+        let mut buf = [0u8; 2048];
+        for n in 0..=self.max_retransmits {
+            let connection_id = if action == ACTION_CONNECT { PROTOCOL_ID } else { self.connection_id()? };
+            let transaction_id = random_u32();
+            self.socket.send(&build(connection_id, transaction_id))?;
+
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                if remaining.is_zero() {
+                    break;
+                }
+                self.socket.set_read_timeout(Some(remaining))?;
+                let len = match self.socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                    Err(e) => return Err(e.into()),
+                };
+                if len < 8 || read_u32(&buf, 4) != transaction_id {
+                    // Stale or unrelated packet, keep waiting
+                    continue;
+                }
+                let response_action = read_u32(&buf, 0);
+                if response_action == ACTION_ERROR {
+                    // The connection id may be why the tracker refused us
+                    self.connection = None;
+                    return Err(TrackerError::Failure(String::from_utf8_lossy(&buf[8..len]).into_owned()).into());
+                }
+                if response_action != action {
+                    return Err(anyhow!("Expected action {}, got {}", action, response_action));
+                }
+                return Ok(buf[..len].to_vec());
+            }
+        }
+        Err(anyhow!("Tracker did not respond after {} retransmissions", self.max_retransmits))
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    // Minimal tracker answering one connect and one follow-up request. The
+    // first follow-up is answered with a bogus transaction id first to check
+    // we skip packets that aren't ours.
+    fn spawn_tracker(follow_up: fn(&[u8]) -> Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(len, 16);
+            assert_eq!(&buf[0..8], &PROTOCOL_ID.to_be_bytes());
+            let mut response = Vec::new();
+            response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+            response.extend_from_slice(&buf[12..16]);
+            response.extend_from_slice(&0x1122334455667788u64.to_be_bytes());
+            socket.send_to(&response, from).unwrap();
+
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[0..8], &0x1122334455667788u64.to_be_bytes());
+            let mut bogus = buf[8..16].to_vec();
+            bogus[7] ^= 0xff;
+            socket.send_to(&bogus, from).unwrap();
+            socket.send_to(&follow_up(&buf[..len]), from).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_udp_announce() {
+        let addr = spawn_tracker(|request| {
+            assert_eq!(request.len(), 98);
+            assert_eq!(&request[16..36], &INFO_HASH);
+            assert_eq!(read_u32(request, 80), 2); // started
+            let mut response = Vec::new();
+            response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            response.extend_from_slice(&request[12..16]);
+            response.extend_from_slice(&1800u32.to_be_bytes());
+            response.extend_from_slice(&3u32.to_be_bytes());
+            response.extend_from_slice(&7u32.to_be_bytes());
+            response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+            response
+        });
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_timeouts(Duration::from_secs(1), 0);
+        let mut request = AnnounceRequest::new(&INFO_HASH, &[0x11; 20], 100);
+        request.event = AnnounceEvent::Started;
+        let response = tracker.announce(&request).unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.leechers, 3);
+        assert_eq!(response.seeders, 7);
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_udp_scrape() {
+        let addr = spawn_tracker(|request| {
+            assert_eq!(request.len(), 36);
+            let mut response = Vec::new();
+            response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            response.extend_from_slice(&request[12..16]);
+            response.extend_from_slice(&5u32.to_be_bytes());
+            response.extend_from_slice(&10u32.to_be_bytes());
+            response.extend_from_slice(&2u32.to_be_bytes());
+            response
+        });
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_timeouts(Duration::from_secs(1), 0);
+        let response = tracker.scrape(&[INFO_HASH]).unwrap();
+        assert_eq!(response, vec![ScrapeStats { seeders: 5, completed: 10, leechers: 2 }]);
+    }
+
+    #[test]
+    fn test_udp_tracker_error() {
+        let addr = spawn_tracker(|request| {
+            let mut response = Vec::new();
+            response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+            response.extend_from_slice(&request[12..16]);
+            response.extend_from_slice(b"torrent not registered");
+            response
+        });
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_timeouts(Duration::from_secs(1), 0);
+        let err = tracker.announce(&AnnounceRequest::new(&INFO_HASH, &[0x11; 20], 100)).unwrap_err();
+        assert_eq!(err.to_string(), "Tracker failure: torrent not registered");
+    }
+
+    type Received = Arc<Mutex<Vec<(Instant, Vec<u8>)>>>;
+
+    // Tracker that ignores the first `drop` packets and answers the rest,
+    // handing out connection ids 1, 2, ... and keeping every packet with
+    // when it arrived
+    fn spawn_lossy_tracker(drop: usize) -> (SocketAddr, Received) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let received = Received::default();
+        let log = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let mut connection_id = 0u64;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                log.lock().unwrap().push((Instant::now(), buf[..len].to_vec()));
+                if log.lock().unwrap().len() <= drop {
+                    continue;
+                }
+                let action = read_u32(&buf, 8);
+                let mut response = Vec::new();
+                response.extend_from_slice(&action.to_be_bytes());
+                response.extend_from_slice(&buf[12..16]);
+                if action == ACTION_CONNECT {
+                    connection_id += 1;
+                    response.extend_from_slice(&connection_id.to_be_bytes());
+                } else {
+                    response.extend_from_slice(&[0; 12]);
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+        (addr, received)
+    }
+
+    fn announce_request() -> AnnounceRequest<'static> {
+        AnnounceRequest::new(&INFO_HASH, &[0x11; 20], 100)
+    }
+
+    #[test]
+    fn test_udp_retransmits() {
+        let (addr, received) = spawn_lossy_tracker(2);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        let base = Duration::from_millis(100);
+        tracker.set_timeouts(base, 3);
+        tracker.announce(&announce_request()).unwrap();
+
+        // Three tries at connecting, then the announce
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 4);
+        assert!(received[..3].iter().all(|(_, packet)| packet[0..8] == PROTOCOL_ID.to_be_bytes()));
+        // Waiting base * 2 ^ n before the nth retransmission
+        assert!(received[1].0 - received[0].0 >= base);
+        assert!(received[2].0 - received[1].0 >= base * 2);
+        // Each with a transaction id of its own
+        assert_ne!(received[0].1[12..16], received[1].1[12..16]);
+        assert_ne!(received[1].1[12..16], received[2].1[12..16]);
+    }
+
+    #[test]
+    fn test_udp_gives_up_after_max_retransmits() {
+        let (addr, received) = spawn_lossy_tracker(usize::MAX);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        let base = Duration::from_millis(20);
+        tracker.set_timeouts(base, 2);
+        let started = Instant::now();
+        let err = tracker.announce(&announce_request()).unwrap_err();
+        assert_eq!(err.to_string(), "Tracker did not respond after 2 retransmissions");
+        assert!(started.elapsed() >= base * (1 + 2 + 4));
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_udp_reconnects_when_connection_id_expires() {
+        let (addr, received) = spawn_lossy_tracker(0);
+        let mut tracker = UdpTracker::with_addr(addr).unwrap();
+        tracker.set_timeouts(Duration::from_secs(1), 0);
+        tracker.connection_ttl = Duration::from_millis(100);
+        tracker.announce(&announce_request()).unwrap();
+        tracker.announce(&announce_request()).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        tracker.announce(&announce_request()).unwrap();
+
+        let actions: Vec<(u64, u32)> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, packet)| (u64::from_be_bytes(packet[0..8].try_into().unwrap()), read_u32(packet, 8)))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                (PROTOCOL_ID, ACTION_CONNECT),
+                (1, ACTION_ANNOUNCE),
+                // Still fresh, so no new connect
+                (1, ACTION_ANNOUNCE),
+                (PROTOCOL_ID, ACTION_CONNECT),
+                (2, ACTION_ANNOUNCE),
+            ]
+        );
+    }
+}
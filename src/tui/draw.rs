@@ -0,0 +1,241 @@
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table, TableState, Tabs},
+    Frame,
+};
+use std::time::Instant;
+
+use bittorrent_starter_rust::{
+    download::piece_length,
+    types::{Files, Info},
+    TorrentHandle, TorrentState,
+};
+
+use super::{App, Prompt, Tab, Watched};
+use crate::progress::{format_bytes, format_percent, format_rate, piece_map};
+
+const HELP: &str = "q quit  ↑↓ select  tab view  a add  p pause/resume  d remove  D remove+data  +/- queue  l limits";
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [list, detail, footer] =
+        Layout::vertical([Constraint::Percentage(40), Constraint::Fill(1), Constraint::Length(2)]).areas(frame.area());
+    draw_list(frame, app, list);
+    draw_detail(frame, app, detail);
+    draw_footer(frame, app, footer);
+}
+
+fn draw_list(frame: &mut Frame, app: &App, area: Rect) {
+    let (download, upload) = app.watched.values().fold((0, 0), |(down, up), watched| {
+        (down + watched.rates.0, up + watched.rates.1)
+    });
+    let (download_limit, upload_limit) = app.session().rate_limits();
+    let limit = |limit: Option<u64>| limit.map_or("unlimited".to_string(), format_rate);
+    let title = format!(
+        " Torrents  down {} ({})  up {} ({}) ",
+        format_rate(download),
+        limit(download_limit),
+        format_rate(upload),
+        limit(upload_limit)
+    );
+    let block = Block::bordered().title(title);
+    if app.torrents.is_empty() {
+        frame.render_widget(Paragraph::new("No torrents. Press a to add one.").block(block), area);
+        return;
+    }
+
+    let rows = app.torrents.iter().enumerate().map(|(position, handle)| {
+        let progress = handle.progress();
+        let watched = app.watched.get(&handle.info_hash());
+        let rate = |rate: fn(&Watched) -> u64| watched.map_or(0, rate);
+        let state = handle.state();
+        let style = match state {
+            TorrentState::Failed(_) => Style::new().fg(Color::Red),
+            TorrentState::Seeding | TorrentState::Completed => Style::new().fg(Color::Green),
+            TorrentState::Paused => Style::new().fg(Color::DarkGray),
+            _ => Style::new(),
+        };
+        Row::new(vec![
+            (position + 1).to_string(),
+            handle.torrent().info.name.clone(),
+            state.name().to_string(),
+            format_percent(progress.downloaded, progress.total),
+            format_bytes(progress.total),
+            format_rate(rate(|watched| watched.rates.0)),
+            format_rate(rate(|watched| watched.rates.1)),
+            progress.peers.to_string(),
+        ])
+        .style(style)
+    });
+    let widths = [
+        Constraint::Length(3),
+        Constraint::Fill(1),
+        Constraint::Length(11),
+        Constraint::Length(7),
+        Constraint::Length(10),
+        Constraint::Length(12),
+        Constraint::Length(12),
+        Constraint::Length(5),
+    ];
+    let table = Table::new(rows, widths)
+        .header(header(&["#", "Name", "State", "Done", "Size", "Down", "Up", "Peers"]))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(block);
+    let mut state = TableState::default().with_selected(Some(app.selected));
+    frame.render_stateful_widget(table, area, &mut state);
+}
+
+fn draw_detail(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(handle) = app.selected() else {
+        frame.render_widget(Block::bordered(), area);
+        return;
+    };
+    let block = Block::bordered().title(format!(" {} ", handle.torrent().info.name));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [tabs, body] = Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(inner);
+    let titles = Tab::ALL.iter().enumerate().map(|(index, tab)| format!("{} {}", index + 1, tab.title()));
+    let selected = Tab::ALL.iter().position(|tab| *tab == app.tab).unwrap_or(0);
+    frame.render_widget(Tabs::new(titles).select(selected).highlight_style(Style::new().add_modifier(Modifier::REVERSED)), tabs);
+
+    let watched = app.watched.get(&handle.info_hash());
+    match app.tab {
+        Tab::Files => draw_files(frame, handle, body),
+        Tab::Peers => draw_peers(frame, handle, watched, body),
+        Tab::Trackers => draw_trackers(frame, handle, watched, body),
+        Tab::Pieces => draw_pieces(frame, handle, body),
+    }
+    if let TorrentState::Failed(error) = handle.state() {
+        let line = Rect { y: body.bottom().saturating_sub(1), height: 1.min(body.height), ..body };
+        frame.render_widget(Paragraph::new(format!("Failed: {}", error)).style(Style::new().fg(Color::Red)), line);
+    }
+}
+
+fn draw_files(frame: &mut Frame, handle: &TorrentHandle, area: Rect) {
+    let info = &handle.torrent().info;
+    let pieces = handle.pieces();
+    let rows = files(info).into_iter().map(|(path, offset, length)| {
+        Row::new(vec![path, format_bytes(length), format_percent(done_in(info, &pieces, offset, length), length)])
+    });
+    let widths = [Constraint::Fill(1), Constraint::Length(10), Constraint::Length(7)];
+    frame.render_widget(Table::new(rows, widths).header(header(&["Path", "Size", "Done"])), area);
+}
+
+fn draw_peers(frame: &mut Frame, handle: &TorrentHandle, watched: Option<&Watched>, area: Rect) {
+    let rows = handle.peers().into_iter().map(|peer| {
+        let (download, upload) = watched
+            .and_then(|watched| watched.peers.get(&peer.addr))
+            .map_or((0, 0), |(download, upload)| (download.rate(), upload.rate()));
+        Row::new(vec![
+            peer.addr.to_string(),
+            peer.client,
+            format_rate(download),
+            format_rate(upload),
+            format_bytes(peer.downloaded),
+            format_bytes(peer.uploaded),
+            if peer.incoming { "in" } else { "out" }.to_string(),
+        ])
+    });
+    let widths = [
+        Constraint::Length(22),
+        Constraint::Length(20),
+        Constraint::Length(12),
+        Constraint::Length(12),
+        Constraint::Length(11),
+        Constraint::Length(11),
+        Constraint::Length(4),
+    ];
+    let header = header(&["Address", "Client", "Down", "Up", "Downloaded", "Uploaded", "Dir"]);
+    frame.render_widget(Table::new(rows, widths).header(header), area);
+}
+
+fn draw_trackers(frame: &mut Frame, handle: &TorrentHandle, watched: Option<&Watched>, area: Rect) {
+    let now = Instant::now();
+    let mut rows: Vec<Row> = Vec::new();
+    for (tier, urls) in handle.torrent().announce_tiers().into_iter().enumerate() {
+        for url in urls {
+            let status = match watched.and_then(|watched| watched.trackers.get(&url)) {
+                Some((at, status)) => format!("{}, {}s ago", status, now.duration_since(*at).as_secs()),
+                None => "not contacted".to_string(),
+            };
+            rows.push(Row::new(vec![(tier + 1).to_string(), url, status]));
+        }
+    }
+    if let Some(error) = watched.and_then(|watched| watched.tracker_error.as_ref()) {
+        rows.push(Row::new(vec![String::new(), "last announce".to_string(), format!("failed: {}", error)]).style(Style::new().fg(Color::Red)));
+    }
+    let widths = [Constraint::Length(4), Constraint::Percentage(50), Constraint::Fill(1)];
+    frame.render_widget(Table::new(rows, widths).header(header(&["Tier", "URL", "Status"])), area);
+}
+
+fn draw_pieces(frame: &mut Frame, handle: &TorrentHandle, area: Rect) {
+    let pieces = handle.pieces();
+    let done = pieces.iter().filter(|have| **have).count();
+    let [summary, map] = Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(area);
+    frame.render_widget(Paragraph::new(format!("{} of {} pieces", done, pieces.len())), summary);
+
+    // One cell per piece when they fit, otherwise squeezed
+    let width = map.width.max(1) as usize;
+    let map_text = piece_map(&pieces, width * map.height as usize);
+    let lines: Vec<Line> = map_text.chars().collect::<Vec<_>>().chunks(width).map(|row| Line::from(row.iter().collect::<String>())).collect();
+    frame.render_widget(Paragraph::new(lines).style(Style::new().fg(Color::Cyan)), map);
+}
+
+fn draw_footer(frame: &mut Frame, app: &App, area: Rect) {
+    let [status, help] = Layout::vertical([Constraint::Length(1), Constraint::Length(1)]).areas(area);
+    let line = match &app.input {
+        Some(input) => {
+            let name = app.selected().map_or(String::new(), |handle| handle.torrent().info.name.clone());
+            let prompt = match input.prompt {
+                Prompt::Add => "Add torrent file: ".to_string(),
+                Prompt::Limits => "Download and upload limits in KiB/s, 0 for none: ".to_string(),
+                Prompt::Remove { delete_data: false } => format!("Remove {}? (y/n) ", name),
+                Prompt::Remove { delete_data: true } => format!("Remove {} and delete its data? (y/n) ", name),
+            };
+            Line::from(format!("{}{}█", prompt, input.text))
+        }
+        None => Line::from(app.last_message().unwrap_or_default()).style(Style::new().fg(Color::Yellow)),
+    };
+    frame.render_widget(Paragraph::new(line), status);
+    frame.render_widget(Paragraph::new(HELP).style(Style::new().fg(Color::DarkGray)), help);
+}
+
+fn header<'a>(titles: &[&'a str]) -> Row<'a> {
+    Row::new(titles.to_vec()).style(Style::new().add_modifier(Modifier::BOLD))
+}
+
+// Each file's path, where it starts in the torrent's data and its length
+fn files(info: &Info) -> Vec<(String, u64, u64)> {
+    match &info.files {
+        Files::Single { length } => vec![(info.name.clone(), 0, *length as u64)],
+        Files::Multiple { files } => {
+            let mut offset = 0;
+            files
+                .iter()
+                .map(|file| {
+                    let entry = (file.path.join("/"), offset, file.length as u64);
+                    offset += file.length as u64;
+                    entry
+                })
+                .collect()
+        }
+    }
+}
+
+// Bytes of the range that fall in pieces we have
+fn done_in(info: &Info, pieces: &[bool], offset: u64, length: u64) -> u64 {
+    let piece_len = info.piece_length as u64;
+    let end = offset + length;
+    let first = offset / piece_len;
+    let last = end.div_ceil(piece_len);
+    (first..last)
+        .filter(|&index| pieces.get(index as usize).copied().unwrap_or(false))
+        .map(|index| {
+            let start = index * piece_len;
+            let stop = start + piece_length(info, index as u32) as u64;
+            stop.min(end) - start.max(offset)
+        })
+        .sum()
+}
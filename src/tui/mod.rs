@@ -0,0 +1,423 @@
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{log, ratemeter::RateMeter, storage::Storage, Event, Session, Torrent, TorrentHandle, TorrentState};
+
+
+mod draw;
+
+// How often the screen is refreshed when no keys are pressed
+const TICK: Duration = Duration::from_millis(250);
+// Log lines and action results kept for the footer
+const MAX_MESSAGES: usize = 100;
+// Limits are entered in KiB/s
+const LIMIT_UNIT: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tab {
+    Files,
+    Peers,
+    Trackers,
+    Pieces,
+}
+
+impl Tab {
+    pub const ALL: [Tab; 4] = [Tab::Files, Tab::Peers, Tab::Trackers, Tab::Pieces];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Tab::Files => "Files",
+            Tab::Peers => "Peers",
+            Tab::Trackers => "Trackers",
+            Tab::Pieces => "Pieces",
+        }
+    }
+
+    fn index(self) -> usize {
+        Tab::ALL.iter().position(|tab| *tab == self).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    Add,
+    Limits,
+    Remove { delete_data: bool },
+}
+
+// A line the user is typing into the footer
+#[derive(Debug)]
+pub struct Input {
+    pub prompt: Prompt,
+    pub text: String,
+}
+
+// What we keep track of per torrent between refreshes
+#[derive(Default)]
+pub struct Watched {
+    events: Option<mpsc::Receiver<Event>>,
+    // The torrent's (download, upload) rates
+    pub rates: (u64, u64),
+    // Download and upload rates of each peer
+    pub peers: HashMap<SocketAddr, (RateMeter, RateMeter)>,
+    // The last word from each tracker, and when it came
+    pub trackers: HashMap<String, (Instant, String)>,
+    pub tracker_error: Option<String>,
+}
+
+// The TUI's state: the session it drives, what's selected and whatever the
+// user is in the middle of typing
+pub struct App {
+    session: Arc<Session>,
+    output_dir: PathBuf,
+    pub torrents: Vec<TorrentHandle>,
+    pub watched: HashMap<[u8; 20], Watched>,
+    pub selected: usize,
+    pub tab: Tab,
+    pub input: Option<Input>,
+    pub messages: Arc<Mutex<VecDeque<String>>>,
+    pub quit: bool,
+}
+
+impl App {
+    pub fn new(session: Arc<Session>, output_dir: PathBuf) -> Self {
+        let mut app = App {
+            session,
+            output_dir,
+            torrents: Vec::new(),
+            watched: HashMap::new(),
+            selected: 0,
+            tab: Tab::Files,
+            input: None,
+            messages: Arc::new(Mutex::new(VecDeque::new())),
+            quit: false,
+        };
+        app.update();
+        app
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn selected(&self) -> Option<&TorrentHandle> {
+        self.torrents.get(self.selected)
+    }
+
+    pub fn last_message(&self) -> Option<String> {
+        self.messages.lock().unwrap_or_else(|e| e.into_inner()).back().cloned()
+    }
+
+    fn message(&self, message: String) {
+        push_message(&self.messages, message);
+    }
+
+    // Picks up torrents added or removed since the last refresh, what they
+    // reported and how fast they're going
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        self.torrents = self.session.torrents();
+        self.selected = self.selected.min(self.torrents.len().saturating_sub(1));
+        self.watched.retain(|info_hash, _| self.torrents.iter().any(|handle| handle.info_hash() == *info_hash));
+
+        for handle in &self.torrents {
+            let watched = self.watched.entry(handle.info_hash()).or_default();
+            let events = watched.events.get_or_insert_with(|| handle.subscribe());
+            for event in events.try_iter() {
+                match event {
+                    Event::TrackerAnnounced { url, peers } => {
+                        watched.trackers.insert(url, (now, format!("{} peers", peers)));
+                        watched.tracker_error = None;
+                    }
+                    Event::TrackerWarning { url, message } => {
+                        watched.trackers.insert(url, (now, format!("warning: {}", message)));
+                    }
+                    Event::TrackerError(error) => watched.tracker_error = Some(error),
+                    _ => {}
+                }
+            }
+
+            watched.rates = handle.rates();
+            let peers = handle.peers();
+            watched.peers.retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+            for peer in peers {
+                let (download, upload) = watched.peers.entry(peer.addr).or_default();
+                download.record(now, peer.downloaded);
+                upload.record(now, peer.uploaded);
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        if self.input.is_some() {
+            self.handle_input_key(key);
+            return;
+        }
+
+        let tab = self.tab.index();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.torrents.len().saturating_sub(1));
+            }
+            KeyCode::Tab | KeyCode::Right => self.tab = Tab::ALL[(tab + 1) % Tab::ALL.len()],
+            KeyCode::BackTab | KeyCode::Left => self.tab = Tab::ALL[(tab + Tab::ALL.len() - 1) % Tab::ALL.len()],
+            KeyCode::Char(digit @ '1'..='4') => self.tab = Tab::ALL[digit as usize - '1' as usize],
+            KeyCode::Char('a') => self.input = Some(Input { prompt: Prompt::Add, text: String::new() }),
+            KeyCode::Char('l') => {
+                let (download, upload) = self.session.rate_limits();
+                let limit = |rate: Option<u64>| rate.map_or(0, |rate| rate / LIMIT_UNIT);
+                let text = format!("{} {}", limit(download), limit(upload));
+                self.input = Some(Input { prompt: Prompt::Limits, text });
+            }
+            KeyCode::Char(key @ ('d' | 'D')) if self.selected().is_some() => {
+                let prompt = Prompt::Remove { delete_data: key == 'D' };
+                self.input = Some(Input { prompt, text: String::new() });
+            }
+            KeyCode::Char('p') => self.act(Self::toggle_pause),
+            KeyCode::Char('+') => self.act(|app| app.move_selected(-1)),
+            KeyCode::Char('-') => self.act(|app| app.move_selected(1)),
+            _ => {}
+        }
+    }
+
+    fn handle_input_key(&mut self, key: KeyEvent) {
+        let Some(input) = &mut self.input else { return };
+        match (input.prompt, key.code) {
+            (_, KeyCode::Esc) => self.input = None,
+            (Prompt::Remove { delete_data }, KeyCode::Char('y')) => {
+                self.input = None;
+                self.act(|app| app.remove_selected(delete_data));
+            }
+            // Anything but yes is no
+            (Prompt::Remove { .. }, _) => self.input = None,
+            (_, KeyCode::Backspace) => {
+                input.text.pop();
+            }
+            (_, KeyCode::Char(c)) => input.text.push(c),
+            (prompt, KeyCode::Enter) => {
+                let text = input.text.trim().to_string();
+                self.input = None;
+                match prompt {
+                    Prompt::Add => self.act(|app| app.add(Path::new(&text))),
+                    Prompt::Limits => self.act(|app| app.set_limits(&text)),
+                    Prompt::Remove { .. } => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Runs an action, reporting how it went in the footer
+    fn act(&mut self, action: impl FnOnce(&mut Self) -> Result<String>) {
+        let message = match action(self) {
+            Ok(message) => message,
+            Err(e) => format!("Error: {:#}", e),
+        };
+        self.message(message);
+        self.update();
+    }
+
+    pub fn add(&mut self, path: &Path) -> Result<String> {
+        let torrent = Torrent::read(path).map_err(|e| anyhow!("Can't read {}: {:#}", path.display(), e))?;
+        let name = torrent.info.name.clone();
+        let output = Storage::default_path(&torrent.info, &self.output_dir)?;
+        let handle = self.session.add_torrent(torrent, &output)?;
+        self.update();
+        self.selected = self.torrents.iter().position(|other| other.info_hash() == handle.info_hash()).unwrap_or(0);
+        Ok(format!("Added {}", name))
+    }
+
+    fn toggle_pause(&mut self) -> Result<String> {
+        let handle = self.selected().ok_or_else(|| anyhow!("No torrent selected"))?.clone();
+        let name = &handle.torrent().info.name;
+        match handle.state() {
+            TorrentState::Paused | TorrentState::Failed(_) => {
+                self.session.resume(&handle.info_hash())?;
+                Ok(format!("Resumed {}", name))
+            }
+            _ => {
+                self.session.pause(&handle.info_hash())?;
+                Ok(format!("Paused {}", name))
+            }
+        }
+    }
+
+    fn remove_selected(&mut self, delete_data: bool) -> Result<String> {
+        let handle = self.selected().ok_or_else(|| anyhow!("No torrent selected"))?.clone();
+        self.session.remove(&handle.info_hash(), delete_data)?;
+        let name = &handle.torrent().info.name;
+        Ok(match delete_data {
+            true => format!("Removed {} and deleted its data", name),
+            false => format!("Removed {}", name),
+        })
+    }
+
+    // Moves the selected torrent up (negative) or down the queue, keeping it
+    // selected
+    fn move_selected(&mut self, by: isize) -> Result<String> {
+        let handle = self.selected().ok_or_else(|| anyhow!("No torrent selected"))?.clone();
+        let position = self.selected.saturating_add_signed(by).min(self.torrents.len().saturating_sub(1));
+        self.session.move_torrent(&handle.info_hash(), position)?;
+        self.selected = position;
+        Ok(format!("Moved {} to position {} in the queue", handle.torrent().info.name, position + 1))
+    }
+
+    // "<download> <upload>" in KiB/s, 0 for unlimited
+    fn set_limits(&mut self, text: &str) -> Result<String> {
+        let limits: Vec<u64> = text
+            .split_whitespace()
+            .map(|limit| limit.parse().map_err(|_| anyhow!("Not a number: {}", limit)))
+            .collect::<Result<_>>()?;
+        let [download, upload] = limits[..] else {
+            return Err(anyhow!("Expected a download and an upload limit, like \"500 100\""));
+        };
+        let limit = |rate: u64| Some(rate * LIMIT_UNIT).filter(|&rate| rate > 0);
+        self.session.set_rate_limits(limit(download), limit(upload));
+        let show = |rate: u64| if rate == 0 { "unlimited".to_string() } else { format!("{} KiB/s", rate) };
+        Ok(format!("Limits set to {} down, {} up", show(download), show(upload)))
+    }
+}
+
+fn push_message(messages: &Mutex<VecDeque<String>>, message: String) {
+    let mut messages = messages.lock().unwrap_or_else(|e| e.into_inner());
+    if messages.len() == MAX_MESSAGES {
+        messages.pop_front();
+    }
+    messages.push_back(message);
+}
+
+// Takes over the terminal until the user quits. Log lines show up in the
+// footer meanwhile, since writing them to stderr would wreck the screen.
+pub fn run(app: &mut App) -> Result<()> {
+    let messages = app.messages.clone();
+    log::set_sink(Some(Box::new(move |line| push_message(&messages, line))));
+    let mut terminal = ratatui::init();
+    let result = (|| -> Result<()> {
+        while !app.quit {
+            app.update();
+            terminal.draw(|frame| draw::draw(frame, app))?;
+            if event::poll(TICK)? {
+                if let TermEvent::Key(key) = event::read()? {
+                    app.handle_key(key);
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::restore();
+    log::set_sink(None);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bittorrent_starter_rust::{SessionConfig, TorrentBuilder};
+    use ratatui::{backend::TestBackend, Terminal};
+    use std::fs;
+
+    fn press(app: &mut App, code: KeyCode) {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, KeyCode::Char(c));
+        }
+        press(app, KeyCode::Enter);
+    }
+
+    fn screen(app: &App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(120, 30)).unwrap();
+        terminal.draw(|frame| draw::draw(frame, app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().chunks(buffer.area.width as usize).map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>() + "\n").collect()
+    }
+
+    // A session holding nothing, and a torrent file to add to it whose
+    // tracker never answers
+    fn setup(dir: &Path) -> (App, PathBuf) {
+        let data = dir.join("data.bin");
+        fs::write(&data, vec![7u8; 40_000]).unwrap();
+        let torrent = TorrentBuilder::new(&data).piece_length(16_384).tier(vec!["http://127.0.0.1:1/announce".to_string()]).build().unwrap();
+        let torrent_path = dir.join("data.bin.torrent");
+        fs::write(&torrent_path, torrent.to_bytes().unwrap()).unwrap();
+
+        let config = SessionConfig { listen_port: 0, max_active_downloads: 0, ..Default::default() };
+        let session = Arc::new(Session::new(config).unwrap());
+        (App::new(session, dir.join("downloads")), torrent_path)
+    }
+
+    #[test]
+    fn test_add_pause_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, torrent_path) = setup(dir.path());
+        assert!(screen(&app).contains("No torrents"));
+
+        press(&mut app, KeyCode::Char('a'));
+        type_text(&mut app, torrent_path.to_str().unwrap());
+        assert_eq!(app.torrents.len(), 1);
+        assert_eq!(app.last_message().unwrap(), "Added data.bin");
+        let screen = screen(&app);
+        assert!(screen.contains("data.bin") && screen.contains("queued"), "{}", screen);
+
+        press(&mut app, KeyCode::Char('p'));
+        assert_eq!(app.torrents[0].state(), TorrentState::Paused);
+        press(&mut app, KeyCode::Char('p'));
+        assert_eq!(app.torrents[0].state(), TorrentState::Queued);
+
+        // Anything but y backs out
+        press(&mut app, KeyCode::Char('d'));
+        press(&mut app, KeyCode::Char('n'));
+        assert_eq!(app.torrents.len(), 1);
+        press(&mut app, KeyCode::Char('d'));
+        press(&mut app, KeyCode::Char('y'));
+        assert!(app.torrents.is_empty());
+    }
+
+    #[test]
+    fn test_limits_and_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, _) = setup(dir.path());
+        press(&mut app, KeyCode::Char('l'));
+        assert_eq!(app.input.as_ref().unwrap().text, "0 0");
+        press(&mut app, KeyCode::Backspace);
+        type_text(&mut app, "20");
+        assert_eq!(app.session().rate_limits(), (None, Some(20 * 1024)));
+
+        press(&mut app, KeyCode::Char('a'));
+        type_text(&mut app, "missing.torrent");
+        assert!(app.last_message().unwrap().starts_with("Error: Can't read missing.torrent"));
+        assert!(screen(&app).contains("Error: Can't read missing.torrent"));
+    }
+
+    #[test]
+    fn test_tabs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, torrent_path) = setup(dir.path());
+        app.add(&torrent_path).unwrap();
+        assert!(screen(&app).contains("data.bin"));
+        press(&mut app, KeyCode::Tab);
+        assert_eq!(app.tab, Tab::Peers);
+        press(&mut app, KeyCode::Char('3'));
+        assert!(screen(&app).contains("http://127.0.0.1:1/announce"));
+        press(&mut app, KeyCode::BackTab);
+        press(&mut app, KeyCode::BackTab);
+        press(&mut app, KeyCode::BackTab);
+        assert_eq!(app.tab, Tab::Pieces);
+        assert!(screen(&app).contains("0 of 3 pieces"));
+    }
+}
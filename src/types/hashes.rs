@@ -34,7 +34,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: serde::de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!(
                 "expected a byte string with a length divisible by 20, got {}",
                 v.len()
@@ -1,17 +1,57 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use sha1::Digest;
 
 mod hashes;
 mod peers;
-use self::{hashes::Hashes, peers::Peers};
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path};
+
+use self::peers::{Peers, Peers6};
+pub use self::{
+    hashes::Hashes,
+    peers::{compact_peers_v4, compact_peers_v6},
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
+    #[serde(default)]
     pub announce: String,
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: Info,
 }
 
+impl Torrent {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let torrent: Torrent = serde_bencode::from_bytes(bytes)?;
+        torrent.info.check_pieces()?;
+        Ok(torrent)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    // Per BEP 12 the announce-list, when present, supersedes announce
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() && !self.announce.is_empty() {
+            return vec![vec![self.announce.clone()]];
+        }
+        tiers
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
     pub name: String,
@@ -23,6 +63,19 @@ pub struct Info {
 }
 
 impl Info {
+    // Everything that works out piece lengths relies on there being a hash
+    // for every piece the length covers and no more
+    fn check_pieces(&self) -> Result<()> {
+        if self.piece_length == 0 {
+            return Err(anyhow!("Torrent has a piece length of 0"));
+        }
+        let expected = self.files.length().div_ceil(self.piece_length);
+        if self.pieces.0.len() != expected {
+            return Err(anyhow!("Torrent has {} piece hashes but its length needs {}", self.pieces.0.len(), expected));
+        }
+        Ok(())
+    }
+
     pub fn calculate_info_hash(&self) -> Result<[u8; 20]> {
         let encoded_info = serde_bencode::to_bytes(self)?;
         let mut hasher = sha1::Sha1::new();
@@ -55,6 +108,60 @@ pub struct File {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
-    pub interval: usize,
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+    pub interval: Option<usize>,
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<usize>,
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+    pub complete: Option<usize>,
+    pub incomplete: Option<usize>,
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default)]
+    pub peers6: Peers6,
+}
+
+impl TrackerResponse {
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.0.iter().chain(self.peers6.0.iter()).copied().collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(default)]
+    pub files: HashMap<serde_bytes::ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScrapeFile {
+    pub complete: usize,
+    pub downloaded: usize,
+    pub incomplete: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_bytes(length: usize, piece_length: usize, pieces: usize) -> Vec<u8> {
+        let info = Info { name: "test".to_string(), piece_length, pieces: Hashes(vec![[0; 20]; pieces]), files: Files::Single { length } };
+        serde_bencode::to_bytes(&Torrent { announce: String::new(), announce_list: None, info }).unwrap()
+    }
+
+    #[test]
+    fn test_piece_count_must_match_length() {
+        assert!(Torrent::from_bytes(&torrent_bytes(10, 4, 3)).is_ok());
+        assert!(Torrent::from_bytes(&torrent_bytes(0, 4, 0)).is_ok());
+        let error = Torrent::from_bytes(&torrent_bytes(10, 4, 4)).unwrap_err();
+        assert_eq!(error.to_string(), "Torrent has 4 piece hashes but its length needs 3");
+        assert!(Torrent::from_bytes(&torrent_bytes(10, 4, 2)).is_err());
+        assert!(Torrent::from_bytes(&torrent_bytes(10, 0, 0)).is_err());
+    }
 }
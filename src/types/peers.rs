@@ -1,43 +1,143 @@
-use serde::{Deserialize, Deserializer, de::Visitor};
-use std::{fmt, net::{Ipv4Addr, SocketAddrV4}};
+use serde::{Deserialize, Deserializer, de::{SeqAccess, Visitor}};
+use std::{fmt, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
 
-#[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+// Peer list from a tracker, either the compact form (BEP 23) or the
+// original list of dictionaries
+#[derive(Debug, Clone, Default)]
+pub struct Peers(pub Vec<SocketAddr>);
 impl<'de> Deserialize<'de> for Peers {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_any(PeersVisitor)
     }
 }
 
+// Compact IPv6 peer list from the peers6 key (BEP 7)
+#[derive(Debug, Clone, Default)]
+pub struct Peers6(pub Vec<SocketAddr>);
+impl<'de> Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PeerDict {
+    ip: String,
+    port: u16,
+}
+
+pub fn compact_peers_v4(v: &[u8]) -> Option<Vec<SocketAddr>> {
+    if !v.len().is_multiple_of(6) {
+        return None;
+    }
+    Some(
+        v.chunks(6)
+            .map(|chunk| {
+                let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                SocketAddr::new(IpAddr::V4(ip), port)
+            })
+            .collect(),
+    )
+}
+
+pub fn compact_peers_v6(v: &[u8]) -> Option<Vec<SocketAddr>> {
+    if !v.len().is_multiple_of(18) {
+        return None;
+    }
+    Some(
+        v.chunks(18)
+            .map(|chunk| {
+                let octets: [u8; 16] = chunk[0..16].try_into().expect("chunk is always 18 bytes");
+                let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+            })
+            .collect(),
+    )
+}
+
 struct PeersVisitor;
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a byte string with a length divisible by 6")
+        formatter.write_str("a byte string with a length divisible by 6 or a list of peer dictionaries")
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
-            return Err(E::custom(format!(
-                "expected a byte string with a length divisible by 6, got {}",
-                v.len()
-            )));
+        compact_peers_v4(v).map(Peers).ok_or_else(|| {
+            E::custom(format!("expected a byte string with a length divisible by 6, got {}", v.len()))
+        })
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        while let Some(peer) = seq.next_element::<PeerDict>()? {
+            // The ip may also be a DNS name, we only take addresses
+            if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+                peers.push(SocketAddr::new(ip, peer.port));
+            }
         }
-        Ok(Peers(
-            v.chunks(6)
-                .map(|chunk| {
-                    let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
-                    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                    SocketAddrV4::new(ip, port)
-                })
-                .collect(),
-        ))
+        Ok(Peers(peers))
+    }
+}
+
+struct Peers6Visitor;
+impl<'de> Visitor<'de> for Peers6Visitor {
+    type Value = Peers6;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte string with a length divisible by 18")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        compact_peers_v6(v).map(Peers6).ok_or_else(|| {
+            E::custom(format!("expected a byte string with a length divisible by 18, got {}", v.len()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::TrackerResponse;
+
+    #[test]
+    fn test_compact_peers() {
+        let response: TrackerResponse =
+            serde_bencode::from_bytes(b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e").unwrap();
+        assert_eq!(response.peers(), vec!["127.0.0.1:6881".parse().unwrap(), "10.0.0.2:6882".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_dictionary_peers() {
+        let response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali60e5:peersld2:ip9:127.0.0.17:peer id20:-XX0100-0123456789014:porti6881eed2:ip3:::14:porti6882eed2:ip11:example.com4:porti1eeee",
+        )
+        .unwrap();
+        assert_eq!(response.peers(), vec!["127.0.0.1:6881".parse().unwrap(), "[::1]:6882".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_compact_ipv6_peers() {
+        let response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e",
+        )
+        .unwrap();
+        assert_eq!(response.peers(), vec!["127.0.0.1:6881".parse().unwrap(), "[::1]:6882".parse().unwrap()]);
     }
 }
@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::{
+    download::piece_length,
+    peer::{PeerConnection, Timeouts},
+    peer_id::generate_peer_id,
+    protocol::{PeerStream, CHUNK_LEN, MSG_REQUEST},
+    types::Info,
+};
+
+// Recordings of peer connections, so a peer that trips us up can be played
+// back through the same parsing code as often as we like. A recording is a
+// JSON lines file: a header naming the peer, then every chunk of bytes read
+// or written, in order, with when it happened.
+
+// Where new connections are recorded, if anywhere
+static CAPTURE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// Records every peer connection made or accepted from now on into a file in
+// `dir`, until switched off again with None
+pub fn set_capture_dir(dir: Option<PathBuf>) {
+    *CAPTURE_DIR.lock().unwrap_or_else(|e| e.into_inner()) = dir;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // Bytes the peer sent us
+    In,
+    // Bytes we sent the peer
+    Out,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    peer: SocketAddr,
+    // Whether the peer connected to us
+    incoming: bool,
+    // Unix time in milliseconds
+    started: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    // Microseconds since the connection was made
+    pub at: u64,
+    pub dir: Direction,
+    #[serde(with = "hex::serde")]
+    pub data: Vec<u8>,
+}
+
+// A stream that writes everything going through it to a recording, when
+// capturing is switched on, and is just the stream otherwise
+pub struct Captured<S> {
+    stream: S,
+    recorder: Option<Recorder>,
+}
+
+struct Recorder {
+    file: File,
+    path: PathBuf,
+    started: Instant,
+}
+
+// Starts recording `stream` if capturing is switched on. Failing to start a
+// recording is logged rather than failing the connection.
+pub fn capture<S: PeerStream>(stream: S, incoming: bool) -> Captured<S> {
+    let dir = CAPTURE_DIR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let recorder = dir.and_then(|dir| match Recorder::create(&dir, &stream, incoming) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            warn!("Failed to start recording peer connection in {}: {:#}", dir.display(), e);
+            None
+        }
+    });
+    Captured { stream, recorder }
+}
+
+impl Recorder {
+    fn create(dir: &Path, stream: &impl PeerStream, incoming: bool) -> Result<Self> {
+        let peer = stream.peer_addr()?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64);
+        fs::create_dir_all(dir)?;
+        // Sorts by when the connection was made; ':' is kept out for the
+        // sake of filesystems that don't allow it
+        let name = format!("{}-{}.wire", started, peer.to_string().replace([':', '[', ']'], "_"));
+        let path = dir.join(name);
+        let mut recorder = Recorder { file: File::create(&path)?, path, started: Instant::now() };
+        recorder.write_line(&Header { peer, incoming, started })?;
+        Ok(recorder)
+    }
+
+    fn record(&mut self, dir: Direction, data: &[u8]) -> io::Result<()> {
+        let at = self.started.elapsed().as_micros() as u64;
+        self.write_line(&Chunk { at, dir, data: data.to_vec() })
+    }
+
+    // One write per line, so whatever happened before a crash is kept
+    fn write_line(&mut self, value: &impl Serialize) -> io::Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+impl<S> Captured<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn record(&mut self, dir: Direction, data: &[u8]) {
+        let Some(recorder) = self.recorder.as_mut() else { return };
+        if let Err(e) = recorder.record(dir, data) {
+            warn!("Failed to record peer connection to {}, giving up on it: {}", recorder.path.display(), e);
+            self.recorder = None;
+        }
+    }
+}
+
+impl<S: Read> Read for Captured<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if n > 0 {
+            self.record(Direction::In, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Captured<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.record(Direction::Out, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: PeerStream> PeerStream for Captured<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+// A recording read back from disk
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub peer: SocketAddr,
+    pub incoming: bool,
+    // Unix time in milliseconds
+    pub started: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open recording {}", path.display()))?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().ok_or_else(|| anyhow!("Recording {} is empty", path.display()))??;
+        let header: Header = serde_json::from_str(&header).with_context(|| format!("Recording {} has no header", path.display()))?;
+        let chunks = lines
+            .enumerate()
+            .map(|(number, line)| {
+                let line = line?;
+                serde_json::from_str(&line).with_context(|| format!("Bad chunk on line {} of recording {}", number + 2, path.display()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Recording { peer: header.peer, incoming: header.incoming, started: header.started, chunks })
+    }
+
+    // Everything that went one way, in one piece
+    pub fn bytes(&self, dir: Direction) -> Vec<u8> {
+        self.chunks.iter().filter(|chunk| chunk.dir == dir).flat_map(|chunk| chunk.data.iter().copied()).collect()
+    }
+
+    // The index, offset and length of every block we asked the peer for
+    pub fn requests(&self) -> Vec<(u32, u32, u32)> {
+        let sent = self.bytes(Direction::Out);
+        let field = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        // Past our handshake
+        let mut rest = sent.get(68..).unwrap_or_default();
+        let mut requests = Vec::new();
+        while rest.len() >= 4 {
+            let len = field(rest) as usize;
+            let Some(message) = rest.get(4..4 + len) else { break };
+            if len == 13 && message[0] == MSG_REQUEST {
+                requests.push((field(&message[1..]), field(&message[5..]), field(&message[9..])));
+            }
+            rest = &rest[4 + len..];
+        }
+        requests
+    }
+
+    // The pieces we asked the peer for, in the order we first asked
+    pub fn requested_pieces(&self) -> Vec<u32> {
+        let mut pieces = Vec::new();
+        for (index, _, _) in self.requests() {
+            if !pieces.contains(&index) {
+                pieces.push(index);
+            }
+        }
+        pieces
+    }
+
+    // The block size the connection asked for pieces in, which is the
+    // largest request it made
+    pub fn block_len(&self) -> u32 {
+        self.requests().into_iter().map(|(_, _, length)| length).max().unwrap_or(CHUNK_LEN)
+    }
+}
+
+// Plays the peer's side of a recording: reads get what the peer sent, in
+// the same order, and whatever we send goes nowhere. Once the recording
+// runs out the peer looks like it hung up.
+pub struct Replay {
+    peer: SocketAddr,
+    received: Vec<u8>,
+    position: usize,
+}
+
+impl Replay {
+    pub fn new(recording: &Recording) -> Self {
+        Replay { peer: recording.peer, received: recording.bytes(Direction::In), position: 0 }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.received[self.position..]).read(buf)?;
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PeerStream for Replay {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    // Recorded bytes are all there already
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// How far a replayed connection got
+#[derive(Debug)]
+pub struct Replayed {
+    // Pieces that downloaded and verified, in order
+    pub pieces: Vec<u32>,
+    // What stopped it, if anything did
+    pub result: Result<()>,
+}
+
+// Goes through a recorded connection again as the downloading side: the
+// handshake and bitfield, then each piece the recording asked for, stopping
+// at the first error just like the real connection would have
+pub fn replay(recording: &Recording, info: &Info, info_hash: &[u8; 20]) -> Replayed {
+    let mut pieces = Vec::new();
+    let result = (|| {
+        if recording.incoming {
+            return Err(anyhow!("Only recordings of connections we made can be replayed, this peer connected to us"));
+        }
+        // Whatever we send goes nowhere, our peer id included
+        let peer_id = generate_peer_id();
+        let mut connection = PeerConnection::from_stream(recording.peer, Replay::new(recording), info_hash, &peer_id, info.pieces.0.len(), Timeouts::default())?;
+        connection.block_len = recording.block_len();
+        for index in recording.requested_pieces() {
+            let hash = info.pieces.0.get(index as usize).ok_or_else(|| anyhow!("Recording asks for piece {} which the torrent doesn't have", index))?;
+            connection.download_piece(index, piece_length(info, index), hash)?;
+            pieces.push(index);
+        }
+        Ok(())
+    })();
+    Replayed { pieces, result }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{read_message, send_message, MSG_UNCHOKE};
+
+    fn recording(chunks: Vec<Chunk>) -> Recording {
+        Recording { peer: "127.0.0.1:6881".parse().unwrap(), incoming: false, started: 0, chunks }
+    }
+
+    #[test]
+    fn test_replay_reads_what_the_peer_sent() {
+        let chunks = vec![
+            Chunk { at: 0, dir: Direction::Out, data: vec![0, 0, 0, 1, MSG_UNCHOKE] },
+            Chunk { at: 1, dir: Direction::In, data: vec![0, 0, 0, 5, MSG_REQUEST] },
+            Chunk { at: 2, dir: Direction::In, data: vec![1, 2, 3, 4] },
+        ];
+        let mut replay = Replay::new(&recording(chunks));
+        send_message(&mut replay, MSG_UNCHOKE, &[]).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(read_message(&mut replay, &mut buf).unwrap(), Some((MSG_REQUEST, 5)));
+        assert_eq!(&buf[1..5], &[1, 2, 3, 4]);
+        // Nothing left, so the peer is gone
+        assert!(read_message(&mut replay, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_requested_pieces() {
+        let mut sent = vec![0u8; 68];
+        for index in [3u32, 3, 1] {
+            sent.extend_from_slice(&[0, 0, 0, 13, MSG_REQUEST]);
+            sent.extend_from_slice(&index.to_be_bytes());
+            sent.extend_from_slice(&[0; 4]);
+            sent.extend_from_slice(&8192u32.to_be_bytes());
+        }
+        sent.extend_from_slice(&[0, 0, 0, 1, MSG_UNCHOKE]);
+        // Split mid-message like a real socket would
+        let (first, second) = sent.split_at(80);
+        let chunks = vec![
+            Chunk { at: 0, dir: Direction::Out, data: first.to_vec() },
+            Chunk { at: 5, dir: Direction::Out, data: second.to_vec() },
+        ];
+        let recording = recording(chunks);
+        assert_eq!(recording.requested_pieces(), vec![3, 1]);
+        assert_eq!(recording.block_len(), 8192);
+    }
+
+    #[test]
+    fn test_chunk_format() {
+        let chunk = Chunk { at: 12, dir: Direction::In, data: vec![0x13, 0xff] };
+        let line = serde_json::to_string(&chunk).unwrap();
+        assert_eq!(line, r#"{"at":12,"dir":"in","data":"13ff"}"#);
+        assert_eq!(serde_json::from_str::<Chunk>(&line).unwrap(), chunk);
+    }
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    run, TestTorrent, Tracker, BIN,
+};
+
+fn stdout(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_help_lists_commands() {
+    let output = run(&["--help"]);
+    assert!(output.status.success());
+    for command in ["decode", "info", "peers", "scrape", "handshake", "download_piece", "download", "tracker", "daemon", "tui", "remote"] {
+        assert!(stdout(&output).contains(command), "{} missing from help", command);
+    }
+}
+
+#[test]
+fn test_wrong_arity_is_rejected() {
+    for args in [&["download_piece", "-o", "out", "sample.torrent"][..], &["info"], &["decode", "i1e", "i2e"]] {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(2), "{:?} was accepted", args);
+    }
+}
+
+#[test]
+fn test_completions_and_man_page() {
+    let output = run(&["completions", "bash"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("download_piece"));
+
+    let output = run(&["man"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with(".ie"), "{}", stdout(&output));
+    assert!(stdout(&output).contains(".TH"));
+}
+
+#[test]
+fn test_download_into_output_dir() {
+    let torrent = TestTorrent::generate(50_000, 16_384, 21);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, MockPeerConfig::default());
+    peer.announce(&tracker.announce_url(), &torrent);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = torrent.write(dir.path(), &tracker.announce_url());
+    let output_dir = dir.path().join("out");
+    fs::create_dir(&output_dir).unwrap();
+
+    let output = run(&["--output-dir", output_dir.to_str().unwrap(), "download", torrent_path.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(output_dir.join("test-21.bin")).unwrap(), torrent.data);
+
+    let output = run(&["download_piece", torrent_path.to_str().unwrap(), "1", "--output-dir", output_dir.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read(output_dir.join("test-21.bin.piece-1")).unwrap(), torrent.piece(1));
+}
+
+#[test]
+fn test_download_refuses_unsafe_name() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().join("out");
+    fs::create_dir(&output_dir).unwrap();
+    for name in ["../escaped.bin", "/tmp/escaped.bin", ".."] {
+        let torrent = TestTorrent::generate(1000, 16_384, 22).with_name(name);
+        let torrent_path = torrent.write(dir.path(), "http://127.0.0.1:1/announce");
+        let output = run(&["--output-dir", output_dir.to_str().unwrap(), "download", torrent_path.to_str().unwrap()]);
+        assert!(!output.status.success(), "{}", name);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Unsafe path component in torrent"), "{}", name);
+    }
+    assert!(!dir.path().join("escaped.bin").exists());
+}
+
+#[test]
+fn test_create_then_info() {
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("hello.txt");
+    fs::write(&content, "hello world\n").unwrap();
+    let torrent_path = dir.path().join("hello.torrent");
+
+    let output = run(&[
+        "create",
+        "-o",
+        torrent_path.to_str().unwrap(),
+        "-t",
+        "http://a/announce,http://b/announce",
+        "-t",
+        "udp://c:6969",
+        content.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run(&["info", torrent_path.to_str().unwrap()]);
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("Tracker URL: http://a/announce\nTracker Tiers:\n0: "), "{}", stdout);
+    assert!(stdout.contains("1: udp://c:6969\nLength: 12\n"), "{}", stdout);
+}
+
+#[test]
+fn test_ip_filter_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let filter = dir.path().join("blocklist.txt");
+    fs::write(&filter, "# Test list\n010.000.000.000 - 010.255.255.255 , 000 , Private\nDocs:192.0.2.0-192.0.2.255\n2001:db8::/32\nnonsense\n").unwrap();
+    let filter = filter.to_str().unwrap();
+
+    let output = run(&["--ip-filter", filter, "ip_filter", "10.1.2.3", "192.0.2.9", "192.0.3.1", "2001:db8::7"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout(&output), "10.1.2.3: blocked\n192.0.2.9: blocked\n192.0.3.1: allowed\n2001:db8::7: blocked\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Skipped 1 lines"));
+
+    // Without a filter there's nothing to check against
+    let output = run(&["ip_filter", "10.1.2.3"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--ip-filter"));
+}
+
+#[test]
+fn test_config_file_and_environment() {
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("hello.txt");
+    fs::write(&content, "hello world\n").unwrap();
+    let config_home = dir.path().join("config");
+    fs::create_dir_all(config_home.join("bittorrent-starter-rust")).unwrap();
+    let from_file = dir.path().join("from-file");
+    let config = format!("[storage]\noutput_dir = {:?}\n", from_file.to_str().unwrap());
+    fs::write(config_home.join("bittorrent-starter-rust/config.toml"), config).unwrap();
+
+    let create = |env: &[(&str, &str)]| {
+        Command::new(BIN)
+            .args(["create", "-t", "http://a/announce", content.to_str().unwrap()])
+            .env("XDG_CONFIG_HOME", &config_home)
+            .envs(env.iter().copied())
+            .output()
+            .unwrap()
+    };
+    // The file in the default location picks the output directory...
+    fs::create_dir(&from_file).unwrap();
+    let output = create(&[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(from_file.join("hello.txt.torrent").exists());
+
+    // ...unless the environment says otherwise
+    let from_env = dir.path().join("from-env");
+    fs::create_dir(&from_env).unwrap();
+    let output = create(&[("BITTORRENT_STORAGE_OUTPUT_DIR", from_env.to_str().unwrap())]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(from_env.join("hello.txt.torrent").exists());
+
+    let output = create(&[("BITTORRENT_NETWORK_BLOCK_SIZE", "100")]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("BITTORRENT_NETWORK_BLOCK_SIZE") && stderr.contains("network.block_size"), "{}", stderr);
+}
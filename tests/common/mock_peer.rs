@@ -0,0 +1,253 @@
+use std::{
+    collections::HashSet,
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{urlencode, TestTorrent};
+
+const MSG_CHOKE: u8 = 0;
+const MSG_UNCHOKE: u8 = 1;
+const MSG_INTERESTED: u8 = 2;
+const MSG_HAVE: u8 = 4;
+const MSG_BITFIELD: u8 = 5;
+const MSG_REQUEST: u8 = 6;
+const MSG_PIECE: u8 = 7;
+
+// Knobs for misbehaving. The defaults give a well-behaved seeder.
+#[derive(Clone, Default)]
+pub struct MockPeerConfig {
+    // Never unchoke the client
+    pub never_unchoke: bool,
+    // Choke for a moment after serving this many blocks, dropping
+    // outstanding requests like a real peer would
+    pub choke_after_blocks: Option<usize>,
+    // Close the connection after serving this many blocks
+    pub drop_after_blocks: Option<usize>,
+    // Ignore every request after serving this many blocks, without closing
+    pub stall_after_blocks: Option<usize>,
+    // Flip a byte in every block of these pieces
+    pub corrupt_pieces: HashSet<u32>,
+    // Sleep before answering each block
+    pub reply_delay: Duration,
+    // Send keep-alives and haves around the bitfield and unchoke
+    pub chatty: bool,
+    // Answer each batch of requests in reverse order
+    pub reverse_blocks: bool,
+    // Pieces we claim to have, all of them if None
+    pub pieces: Option<HashSet<u32>>,
+    // Upload cap in bytes per second, per connection
+    pub bandwidth: Option<usize>,
+    // Go away this long after the first connection, closing every connection
+    pub lifetime: Option<Duration>,
+    // Loopback address to listen on instead of 127.0.0.1, so peers can be
+    // told apart by address
+    pub ip: Option<Ipv4Addr>,
+}
+
+#[derive(Default)]
+pub struct MockPeerStats {
+    pub connections: AtomicUsize,
+    pub blocks_served: AtomicUsize,
+    pub bytes_served: AtomicUsize,
+    // The peer id of every client that handshook, in order
+    pub peer_ids: Mutex<Vec<[u8; 20]>>,
+}
+
+pub struct MockPeer {
+    pub addr: SocketAddr,
+    pub peer_id: [u8; 20],
+    pub stats: Arc<MockPeerStats>,
+}
+
+impl MockPeer {
+    pub fn spawn(torrent: &TestTorrent, config: MockPeerConfig) -> Self {
+        let listener = TcpListener::bind((config.ip.unwrap_or(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peer_id = *b"-MK0001-000000000000";
+        peer_id[8..].copy_from_slice(format!("{:012}", addr.port()).as_bytes());
+        let stats = Arc::new(MockPeerStats::default());
+
+        let torrent = Arc::new(torrent.clone());
+        let thread_stats = stats.clone();
+        thread::spawn(move || {
+            let mut deadline = None;
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let deadline = *deadline.get_or_insert_with(|| config.lifetime.map(|lifetime| Instant::now() + lifetime));
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    continue;
+                }
+                let torrent = torrent.clone();
+                let config = config.clone();
+                let stats = thread_stats.clone();
+                stats.connections.fetch_add(1, Ordering::Relaxed);
+                thread::spawn(move || {
+                    let _ = serve(stream, &torrent, &config, &stats, &peer_id, deadline);
+                });
+            }
+        });
+        MockPeer { addr, peer_id, stats }
+    }
+
+    // Registers this peer as a seeder with the tracker
+    pub fn announce(&self, announce_url: &str, torrent: &TestTorrent) {
+        let url = format!(
+            "{}?info_hash={}&peer_id={}&ip={}&port={}&uploaded=0&downloaded=0&left=0&event=started&compact=1",
+            announce_url,
+            urlencode(&torrent.info_hash),
+            urlencode(&self.peer_id),
+            self.addr.ip(),
+            self.addr.port()
+        );
+        reqwest::blocking::get(url).unwrap().bytes().unwrap();
+    }
+
+    pub fn blocks_served(&self) -> usize {
+        self.stats.blocks_served.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_served(&self) -> usize {
+        self.stats.bytes_served.load(Ordering::Relaxed)
+    }
+
+    pub fn peer_ids(&self) -> Vec<[u8; 20]> {
+        self.stats.peer_ids.lock().unwrap().clone()
+    }
+}
+
+fn send(stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    buf.push(id);
+    buf.extend_from_slice(payload);
+    stream.write_all(&buf)
+}
+
+fn keep_alive(stream: &mut TcpStream) -> std::io::Result<()> {
+    stream.write_all(&[0, 0, 0, 0])
+}
+
+fn read_message(stream: &mut TcpStream) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(Some((buf[0], buf[1..].to_vec())))
+}
+
+fn serve(
+    mut stream: TcpStream,
+    torrent: &TestTorrent,
+    config: &MockPeerConfig,
+    stats: &MockPeerStats,
+    peer_id: &[u8; 20],
+    deadline: Option<Instant>,
+) -> std::io::Result<()> {
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake)?;
+    if handshake[28..48] != torrent.info_hash {
+        return Ok(());
+    }
+    stats.peer_ids.lock().unwrap().push(handshake[48..68].try_into().unwrap());
+    stream.write_all(&[19])?;
+    stream.write_all(b"BitTorrent protocol")?;
+    stream.write_all(&[0; 8])?;
+    stream.write_all(&torrent.info_hash)?;
+    stream.write_all(peer_id)?;
+
+    let has_piece = |index: u32| config.pieces.as_ref().is_none_or(|pieces| pieces.contains(&index));
+    let mut bitfield = vec![0u8; torrent.num_pieces().div_ceil(8)];
+    for index in 0..torrent.num_pieces() {
+        if has_piece(index as u32) {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+    if config.chatty {
+        keep_alive(&mut stream)?;
+    }
+    send(&mut stream, MSG_BITFIELD, &bitfield)?;
+    if config.chatty {
+        send(&mut stream, MSG_HAVE, &0u32.to_be_bytes())?;
+        keep_alive(&mut stream)?;
+    }
+
+    let mut served = 0usize;
+    let mut choked = false;
+    let mut requests: Vec<(u32, u32, u32)> = Vec::new();
+    loop {
+        // Batch up whatever requests are already waiting so they can be
+        // answered out of order
+        stream.set_read_timeout(if requests.is_empty() { None } else { Some(Duration::from_millis(20)) })?;
+        match read_message(&mut stream) {
+            Ok(Some((MSG_INTERESTED, _))) => {
+                if !config.never_unchoke {
+                    send(&mut stream, MSG_UNCHOKE, &[])?;
+                    if config.chatty {
+                        send(&mut stream, MSG_HAVE, &0u32.to_be_bytes())?;
+                    }
+                }
+                continue;
+            }
+            Ok(Some((MSG_REQUEST, payload))) if payload.len() == 12 => {
+                let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+                requests.push((field(0), field(4), field(8)));
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+
+        if config.reverse_blocks {
+            requests.reverse();
+        }
+        for (index, begin, length) in std::mem::take(&mut requests) {
+            if !has_piece(index) {
+                continue;
+            }
+            if config.drop_after_blocks == Some(served) || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(());
+            }
+            if config.stall_after_blocks.is_some_and(|stall| served >= stall) {
+                break;
+            }
+            if config.choke_after_blocks == Some(served) && !choked {
+                // Outstanding requests are discarded on choke
+                choked = true;
+                send(&mut stream, MSG_CHOKE, &[])?;
+                thread::sleep(Duration::from_millis(50));
+                send(&mut stream, MSG_UNCHOKE, &[])?;
+                break;
+            }
+            thread::sleep(config.reply_delay);
+            if let Some(bandwidth) = config.bandwidth {
+                thread::sleep(Duration::from_secs_f64(length as f64 / bandwidth as f64));
+            }
+
+            let piece = torrent.piece(index as usize);
+            let mut block = piece[begin as usize..(begin + length) as usize].to_vec();
+            if config.corrupt_pieces.contains(&index) {
+                block[0] ^= 0xff;
+            }
+            let mut payload = Vec::with_capacity(8 + block.len());
+            payload.extend_from_slice(&index.to_be_bytes());
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(&block);
+            send(&mut stream, MSG_PIECE, &payload)?;
+            served += 1;
+            stats.blocks_served.fetch_add(1, Ordering::Relaxed);
+            stats.bytes_served.fetch_add(block.len(), Ordering::Relaxed);
+        }
+    }
+}
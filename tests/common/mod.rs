@@ -0,0 +1,170 @@
+#![allow(dead_code)]
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+};
+
+pub mod mock_peer;
+pub mod swarm;
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_bittorrent-starter-rust");
+
+#[derive(Serialize)]
+struct TorrentFile<'a> {
+    announce: &'a str,
+    info: &'a TorrentInfo,
+}
+
+#[derive(Clone, Serialize)]
+struct TorrentInfo {
+    length: usize,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: ByteBuf,
+}
+
+// A single-file torrent over generated content
+#[derive(Clone)]
+pub struct TestTorrent {
+    pub data: Vec<u8>,
+    pub piece_length: usize,
+    pub info_hash: [u8; 20],
+    pub piece_hashes: Vec<[u8; 20]>,
+    info: TorrentInfo,
+}
+
+impl TestTorrent {
+    pub fn generate(len: usize, piece_length: usize, seed: u32) -> Self {
+        // xorshift, so every test gets reproducible but non-repeating bytes
+        let mut state = seed.max(1);
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let piece_hashes: Vec<[u8; 20]> = data.chunks(piece_length).map(|piece| Sha1::digest(piece).into()).collect();
+        let info = TorrentInfo {
+            length: len,
+            name: format!("test-{}.bin", seed),
+            piece_length,
+            pieces: ByteBuf::from(piece_hashes.concat()),
+        };
+        let info_bytes = serde_bencode::to_bytes(&info).unwrap();
+        let info_hash = Sha1::digest(&info_bytes).into();
+        TestTorrent { data, piece_length, info_hash, piece_hashes, info }
+    }
+
+    // The same content under another name, which changes the info hash
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.info.name = name.to_string();
+        self.info_hash = Sha1::digest(serde_bencode::to_bytes(&self.info).unwrap()).into();
+        self
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    pub fn piece(&self, index: usize) -> &[u8] {
+        let start = index * self.piece_length;
+        let end = (start + self.piece_length).min(self.data.len());
+        &self.data[start..end]
+    }
+
+    pub fn write(&self, dir: &Path, announce: &str) -> PathBuf {
+        let torrent = TorrentFile { announce, info: &self.info };
+        let path = dir.join(format!("{}.torrent", hex::encode(self.info_hash)));
+        std::fs::write(&path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        path
+    }
+}
+
+// The client's own `tracker` command, killed when dropped
+pub struct Tracker {
+    child: Child,
+    pub addr: String,
+}
+
+impl Tracker {
+    pub fn spawn() -> Self {
+        let mut child = Command::new(BIN)
+            .args(["tracker", "--bind", "127.0.0.1", "--port", "0", "--interval", "60"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let addr = line.trim().rsplit(' ').next().unwrap().to_string();
+        Tracker { child, addr }
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// The client's `daemon` command over a fresh output directory, killed when
+// dropped unless it was shut down
+pub struct Daemon {
+    child: Child,
+    pub rpc_url: String,
+}
+
+impl Daemon {
+    pub fn spawn(output_dir: &Path) -> Self {
+        let mut child = Command::new(BIN)
+            .args(["--json", "--listen-port", "0", "--output-dir", output_dir.to_str().unwrap(), "daemon", "--rpc-port", "0"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let started: serde_json::Value = serde_json::from_str(&line).unwrap();
+        let rpc_url = format!("http://{}/rpc", started["rpc_addr"].as_str().unwrap());
+        Daemon { child, rpc_url }
+    }
+
+    // Runs `remote` against this daemon with --json, returning the document
+    pub fn remote(&self, args: &[&str]) -> serde_json::Value {
+        let output = run(&[&["--json", "remote", "--url", &self.rpc_url], args].concat());
+        assert!(output.status.success(), "remote {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+
+    pub fn wait(mut self) -> std::process::ExitStatus {
+        self.child.wait().unwrap()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn run(args: &[&str]) -> Output {
+    Command::new(BIN).args(args).output().unwrap()
+}
+
+pub fn urlencode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("%{:02X}", b)).collect()
+}
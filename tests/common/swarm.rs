@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    process::Output,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{tracker, Session, SessionConfig, Torrent, TorrentState};
+use sha1::{Digest, Sha1};
+
+use super::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    run, TestTorrent, Tracker,
+};
+
+// An embedded tracker, a set of seeders and a number of leechers running this
+// client, all on localhost. Seeders are mock peers, or sessions of this client
+// seeding the complete data.
+pub struct Swarm {
+    torrent: TestTorrent,
+    seeders: Vec<MockPeerConfig>,
+    client_seeders: usize,
+    leechers: usize,
+}
+
+impl Swarm {
+    pub fn new(torrent: TestTorrent) -> Self {
+        Swarm { torrent, seeders: Vec::new(), client_seeders: 0, leechers: 1 }
+    }
+
+    pub fn seeder(mut self, config: MockPeerConfig) -> Self {
+        self.seeders.push(config);
+        self
+    }
+
+    pub fn seeders(mut self, count: usize, config: MockPeerConfig) -> Self {
+        self.seeders.extend((0..count).map(|_| config.clone()));
+        self
+    }
+
+    pub fn client_seeders(mut self, count: usize) -> Self {
+        self.client_seeders = count;
+        self
+    }
+
+    pub fn leechers(mut self, count: usize) -> Self {
+        self.leechers = count;
+        self
+    }
+
+    // Starts everything, runs every leecher to completion at the same time and
+    // reports on how it went
+    pub fn run(self) -> SwarmReport {
+        let tracker = Tracker::spawn();
+        let seeders: Vec<MockPeer> =
+            self.seeders.into_iter().map(|config| MockPeer::spawn(&self.torrent, config)).collect();
+        for seeder in &seeders {
+            seeder.announce(&tracker.announce_url(), &self.torrent);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let torrent_path = self.torrent.write(dir.path(), &tracker.announce_url());
+        let client_seeders: Vec<Session> = (0..self.client_seeders)
+            .map(|i| {
+                let output_path = dir.path().join(format!("seeder-{}.bin", i));
+                fs::write(&output_path, &self.torrent.data).unwrap();
+                let session = Session::new(SessionConfig { listen_port: 0, max_active_seeds: 1, ..Default::default() }).unwrap();
+                session.add_torrent(Torrent::read(&torrent_path).unwrap(), &output_path).unwrap();
+                session
+            })
+            .collect();
+        wait_for_client_seeders(&client_seeders, &tracker.announce_url(), self.torrent.info_hash);
+
+        // Seeders that leave are tried again quickly, so a dead swarm is noticed soon
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[network]\nreconnect_backoff = 0.05\n").unwrap();
+        let handles: Vec<_> = (0..self.leechers)
+            .map(|i| {
+                let output_path = dir.path().join(format!("leecher-{}.bin", i));
+                let (torrent_path, config_path) = (torrent_path.clone(), config_path.clone());
+                thread::spawn(move || {
+                    let started = Instant::now();
+                    let output = run(&["--config", config_path.to_str().unwrap(), "download", "-o", output_path.to_str().unwrap(), torrent_path.to_str().unwrap()]);
+                    let elapsed = started.elapsed();
+                    LeecherResult { output, data: fs::read(&output_path).unwrap_or_default(), elapsed }
+                })
+            })
+            .collect();
+        let leechers = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        // Uploads are counted once sent, which can be a moment after the
+        // leecher has the block, so wait for the counts to settle
+        let uploaded = || -> Vec<u64> { client_seeders.iter().map(|session| session.torrents()[0].progress().uploaded).collect() };
+        let mut client_bytes_served = uploaded();
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(50));
+            let now = uploaded();
+            if now == client_bytes_served {
+                break;
+            }
+            client_bytes_served = now;
+        }
+        for session in &client_seeders {
+            session.shutdown();
+        }
+        SwarmReport {
+            torrent: self.torrent,
+            leechers,
+            bytes_served: seeders.iter().map(MockPeer::bytes_served).collect(),
+            client_bytes_served,
+        }
+    }
+}
+
+// Until every client seeder has checked its data and the tracker lists it, so
+// leechers find them in their first announce
+fn wait_for_client_seeders(sessions: &[Session], announce_url: &str, info_hash: [u8; 20]) {
+    for _ in 0..500 {
+        let seeding = sessions.iter().all(|session| session.torrents()[0].state() == TorrentState::Seeding);
+        let listed = tracker::scrape(announce_url, &[info_hash])
+            .ok()
+            .and_then(|stats| stats.get(&info_hash).map(|stats| stats.seeders as usize))
+            .unwrap_or(0);
+        if seeding && listed >= sessions.len() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("client seeders never got going");
+}
+
+pub struct LeecherResult {
+    pub output: Output,
+    pub data: Vec<u8>,
+    pub elapsed: Duration,
+}
+
+impl LeecherResult {
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.output.stderr).into_owned()
+    }
+}
+
+pub struct SwarmReport {
+    pub torrent: TestTorrent,
+    pub leechers: Vec<LeecherResult>,
+    // Indexed like the seeders were added
+    pub bytes_served: Vec<usize>,
+    // What each client seeder uploaded
+    pub client_bytes_served: Vec<u64>,
+}
+
+impl SwarmReport {
+    // Pieces of the leecher's output that don't hash to what the torrent says
+    pub fn bad_pieces(&self, leecher: usize) -> Vec<usize> {
+        let data = &self.leechers[leecher].data;
+        (0..self.torrent.num_pieces())
+            .filter(|&index| {
+                let start = index * self.torrent.piece_length;
+                let end = (start + self.torrent.piece_length).min(self.torrent.data.len());
+                data.get(start..end).is_none_or(|piece| <[u8; 20]>::from(Sha1::digest(piece)) != self.torrent.piece_hashes[index])
+            })
+            .collect()
+    }
+
+    // Every leecher exited cleanly with every piece intact
+    pub fn assert_complete(&self) {
+        for (i, leecher) in self.leechers.iter().enumerate() {
+            assert!(leecher.output.status.success(), "leecher {} failed: {}", i, leecher.stderr());
+            assert_eq!(self.bad_pieces(i), Vec::<usize>::new(), "leecher {} has bad pieces", i);
+            assert_eq!(leecher.data.len(), self.torrent.data.len(), "leecher {} has extra data", i);
+        }
+    }
+
+    pub fn total_served(&self) -> usize {
+        self.bytes_served.iter().sum()
+    }
+
+    // Jain's fairness index over the given seeders' uploads: 1.0 when they all
+    // served the same amount, 1/n when one of them did all the work
+    pub fn fairness(&self, seeders: &[usize]) -> f64 {
+        let served: Vec<f64> = seeders.iter().map(|&i| self.bytes_served[i] as f64).collect();
+        let sum: f64 = served.iter().sum();
+        let sum_of_squares: f64 = served.iter().map(|x| x * x).sum();
+        if sum_of_squares == 0.0 {
+            return 1.0;
+        }
+        sum * sum / (served.len() as f64 * sum_of_squares)
+    }
+}
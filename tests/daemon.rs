@@ -0,0 +1,115 @@
+mod common;
+
+use std::{fs, thread, time::Duration};
+
+use serde_json::{json, Value};
+
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    Daemon, TestTorrent, Tracker,
+};
+
+fn wait_for_state(daemon: &Daemon, info_hash: &str, states: &[&str]) -> Value {
+    for _ in 0..500 {
+        let status = daemon.remote(&["show", info_hash]);
+        if states.contains(&status["state"].as_str().unwrap()) {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("{} never got to {:?}", info_hash, states);
+}
+
+#[test]
+fn test_remote_controls_daemon() {
+    let torrent = TestTorrent::generate(100_000, 16_384, 71);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, MockPeerConfig::default());
+    peer.announce(&tracker.announce_url(), &torrent);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = torrent.write(dir.path(), &tracker.announce_url());
+    let daemon = Daemon::spawn(dir.path());
+
+    let added = daemon.remote(&["add", torrent_path.to_str().unwrap()]);
+    let info_hash = hex::encode(torrent.info_hash);
+    assert_eq!(added["info_hash"], info_hash.as_str());
+    assert_eq!(added["name"], "test-71.bin");
+
+    let status = wait_for_state(&daemon, &info_hash, &["seeding", "completed"]);
+    assert_eq!(status["pieces_done"], torrent.num_pieces());
+    let output = dir.path().join("test-71.bin");
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+    assert_eq!(daemon.remote(&["list"])["torrents"].as_array().unwrap().len(), 1);
+
+    assert_eq!(daemon.remote(&["pause", &info_hash]), json!({ "action": "paused", "info_hash": info_hash }));
+    assert_eq!(daemon.remote(&["show", &info_hash])["state"], "paused");
+    daemon.remote(&["resume", &info_hash]);
+    wait_for_state(&daemon, &info_hash, &["seeding", "completed"]);
+
+    let limits = daemon.remote(&["limits", "--download", "1000000"]);
+    assert_eq!((limits["download_rate"].as_u64(), limits["upload_rate"].as_u64()), (Some(1_000_000), None));
+    let limits = daemon.remote(&["limits", "--peer-upload", "50000"]);
+    assert_eq!((limits["download_rate"].as_u64(), limits["peer_upload_rate"].as_u64()), (Some(1_000_000), Some(50_000)));
+    let torrent_limits = daemon.remote(&["limits", "--torrent", &info_hash, "--upload", "2000"]);
+    assert_eq!((torrent_limits["download_rate"].as_u64(), torrent_limits["upload_rate"].as_u64()), (None, Some(2000)));
+
+    daemon.remote(&["remove", "--delete-data", &info_hash]);
+    assert_eq!(daemon.remote(&["list"]), json!({ "torrents": [] }));
+    assert!(!output.exists());
+
+    daemon.remote(&["shutdown"]);
+    assert!(daemon.wait().success());
+}
+
+#[test]
+fn test_daemon_keeps_torrents_across_restarts() {
+    let torrent = TestTorrent::generate(50_000, 16_384, 72);
+    let tracker = Tracker::spawn();
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = torrent.write(dir.path(), &tracker.announce_url());
+    // There is nobody to download from, but the pause is what gets remembered
+    let info_hash = hex::encode(torrent.info_hash);
+    let daemon = Daemon::spawn(dir.path());
+    daemon.remote(&["add", torrent_path.to_str().unwrap()]);
+    daemon.remote(&["pause", &info_hash]);
+    daemon.remote(&["shutdown"]);
+    assert!(daemon.wait().success());
+
+    let daemon = Daemon::spawn(dir.path());
+    let torrents = daemon.remote(&["list"]);
+    assert_eq!(torrents["torrents"][0]["info_hash"], info_hash.as_str());
+    assert_eq!(torrents["torrents"][0]["state"], "paused");
+    assert!(dir.path().join(".session").join(format!("{}.torrent", info_hash)).exists());
+}
+
+#[test]
+fn test_rpc_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let daemon = Daemon::spawn(dir.path());
+    let client = reqwest::blocking::Client::new();
+
+    let response: Value =
+        client.post(&daemon.rpc_url).header("Content-Type", "application/json").body("{not json").send().unwrap().json().unwrap();
+    assert_eq!(response["error"]["code"], -32700);
+    let request = json!({ "jsonrpc": "2.0", "method": "torrent.get", "params": { "info_hash": "00".repeat(20) }, "id": "a" });
+    let response: Value = client.post(&daemon.rpc_url).json(&request).send().unwrap().json().unwrap();
+    assert_eq!(response["error"]["code"], -32000);
+    assert_eq!(response["id"], "a");
+    assert_eq!(client.get(&daemon.rpc_url).send().unwrap().status(), 405);
+    // What a web page can send without a CORS preflight
+    let shutdown = json!({ "jsonrpc": "2.0", "method": "session.shutdown", "id": 2 }).to_string();
+    let response = client.post(&daemon.rpc_url).header("Content-Type", "text/plain").body(shutdown.clone()).send().unwrap();
+    assert_eq!(response.status(), 415);
+    assert_eq!(client.post(&daemon.rpc_url).body(shutdown).send().unwrap().status(), 415);
+
+    // The default output path would be outside the output directory
+    let torrent = TestTorrent::generate(1000, 16_384, 73).with_name("../escaped.bin");
+    let torrent_path = torrent.write(dir.path(), "http://127.0.0.1:1/announce");
+    let request = json!({ "jsonrpc": "2.0", "method": "torrent.add", "params": { "path": torrent_path }, "id": 1 });
+    let response: Value = client.post(&daemon.rpc_url).json(&request).send().unwrap().json().unwrap();
+    assert!(response["error"]["message"].as_str().unwrap().contains("Unsafe path component"), "{}", response);
+
+    let output = common::run(&["remote", "--url", &daemon.rpc_url, "resume", &"00".repeat(20)]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not in the session"));
+}
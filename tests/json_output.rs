@@ -0,0 +1,122 @@
+mod common;
+
+use serde_json::{json, Value};
+use std::process::Output;
+
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    run, TestTorrent, Tracker,
+};
+
+fn json_output(output: &Output) -> Value {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1, "expected a single line: {}", stdout);
+    serde_json::from_str(&stdout).unwrap()
+}
+
+fn keys(value: &Value) -> Vec<&str> {
+    let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn test_info() {
+    let value = json_output(&run(&["--json", "info", "sample.torrent"]));
+    let announce = "http://bittorrent-test-tracker.codecrafters.io/announce";
+    assert_eq!(
+        value,
+        json!({
+            "name": "sample.txt",
+            "announce": announce,
+            "tracker_tiers": [[announce]],
+            "info_hash": "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            "length": 92063,
+            "piece_length": 32768,
+            "piece_hashes": [
+                "e876f67a2a8886e8f36b136726c30fa29703022d",
+                "6e2275e604a0766656736e81ff10b55204ad8d35",
+                "f00d937a0213df1982bc8d097227ad9e909acc17"
+            ],
+            "files": [{"path": ["sample.txt"], "length": 92063}]
+        })
+    );
+}
+
+#[test]
+fn test_decode_is_unchanged() {
+    let value = json_output(&run(&["--json", "decode", "l5:helloi52ee"]));
+    assert_eq!(value, json!(["hello", 52]));
+}
+
+#[test]
+fn test_ip_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let filter = dir.path().join("blocklist.txt");
+    std::fs::write(&filter, "10.0.0.0/8\n10.0.0.0/16\n").unwrap();
+    let value = json_output(&run(&["--json", "--ip-filter", filter.to_str().unwrap(), "ip_filter", "10.0.0.1", "::1"]));
+    assert_eq!(
+        value,
+        json!({
+            "filter": filter,
+            "ranges": 1,
+            "addresses": [{"address": "10.0.0.1", "blocked": true}, {"address": "::1", "blocked": false}]
+        })
+    );
+}
+
+#[test]
+fn test_swarm_commands() {
+    let torrent = TestTorrent::generate(40_000, 16_384, 31);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, MockPeerConfig::default());
+    peer.announce(&tracker.announce_url(), &torrent);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = torrent.write(dir.path(), &tracker.announce_url());
+    let torrent_path = torrent_path.to_str().unwrap();
+    let info_hash = hex::encode(torrent.info_hash);
+
+    let value = json_output(&run(&["--json", "peers", torrent_path]));
+    assert_eq!(value, json!({"info_hash": info_hash, "peers": [{"ip": "127.0.0.1", "port": peer.addr.port()}]}));
+
+    // The peers command above is still registered as a leecher
+    let value = json_output(&run(&["--json", "scrape", torrent_path]));
+    assert_eq!(
+        value,
+        json!({"tracker": tracker.announce_url(), "info_hash": info_hash, "seeders": 1, "leechers": 1, "completed": 0})
+    );
+
+    let value = json_output(&run(&["--json", "handshake", torrent_path, &peer.addr.to_string()]));
+    assert_eq!(
+        value,
+        json!({
+            "peer": peer.addr.to_string(),
+            "peer_id": hex::encode(peer.peer_id),
+            "client": "MK 0.0.0.1",
+            "reserved": "0000000000000000",
+            "extensions": {"dht": false, "fast": false, "extension_protocol": false}
+        })
+    );
+
+    let piece_path = dir.path().join("piece");
+    let value = json_output(&run(&["--json", "download_piece", "-o", piece_path.to_str().unwrap(), torrent_path, "2"]));
+    assert_eq!(
+        value,
+        json!({
+            "output": piece_path,
+            "piece": 2,
+            "length": 40_000 - 2 * 16_384,
+            "hash": hex::encode(torrent.piece_hashes[2])
+        })
+    );
+
+    let output_path = dir.path().join("download");
+    let value = json_output(&run(&["download", "-o", output_path.to_str().unwrap(), torrent_path, "--json"]));
+    assert_eq!(keys(&value), ["elapsed_secs", "info_hash", "length", "output", "pieces"]);
+    assert_eq!(value["output"], json!(output_path));
+    assert_eq!(value["info_hash"], json!(info_hash));
+    assert_eq!(value["length"], json!(40_000));
+    assert_eq!(value["pieces"], json!(3));
+    assert!(value["elapsed_secs"].is_f64());
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::fs;
+
+use bittorrent_starter_rust::{Event, Session, SessionConfig, Torrent, TorrentBuilder, TorrentState};
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    TestTorrent, Tracker,
+};
+
+#[test]
+fn test_builder_matches_existing_torrent() {
+    let torrent = TestTorrent::generate(100_000, 32_768, 41);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test-41.bin");
+    fs::write(&path, &torrent.data).unwrap();
+
+    let built = TorrentBuilder::new(&path).piece_length(32_768).tier(vec!["http://t/announce".to_string()]).build().unwrap();
+    assert_eq!(built.info.calculate_info_hash().unwrap(), torrent.info_hash);
+
+    let parsed = Torrent::from_bytes(&built.to_bytes().unwrap()).unwrap();
+    assert_eq!(parsed.announce, "http://t/announce");
+    assert_eq!(parsed.info.calculate_info_hash().unwrap(), torrent.info_hash);
+}
+
+#[test]
+fn test_session_download_reports_progress() {
+    let torrent = TestTorrent::generate(100_000, 32_768, 42);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, MockPeerConfig::default());
+    peer.announce(&tracker.announce_url(), &torrent);
+    let dir = tempfile::tempdir().unwrap();
+    let parsed = Torrent::read(&torrent.write(dir.path(), &tracker.announce_url())).unwrap();
+
+    let session = Session::new(SessionConfig { listen_port: 0, max_active_seeds: 0, ..Default::default() }).unwrap();
+    let output = dir.path().join("out.bin");
+    let handle = session.add_torrent(parsed.clone(), &output).unwrap();
+    assert!(session.add_torrent(parsed, &output).is_err());
+    let mut events = Vec::new();
+    for event in handle.subscribe() {
+        let done = event == Event::Completed;
+        events.push(event);
+        if done {
+            break;
+        }
+    }
+    handle.wait().unwrap();
+
+    assert_eq!(handle.state(), TorrentState::Completed);
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+    assert_eq!(events[0], Event::StateChanged(TorrentState::Downloading));
+    assert!(matches!(events[1], Event::TrackerAnnounced { peers: 1, .. }), "{:?}", events);
+    assert!(events.contains(&Event::PeerConnected(peer.addr)));
+    let verified: Vec<u32> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::PieceVerified { index, .. } => Some(*index),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(verified.len(), torrent.num_pieces());
+
+    let progress = handle.progress();
+    assert_eq!((progress.pieces_done, progress.downloaded), (torrent.num_pieces(), 100_000));
+    // Subscribing afterwards replays the whole download
+    let replayed: Vec<Event> = handle.subscribe().try_iter().collect();
+    assert_eq!(replayed[..events.len()], events[..]);
+    assert_eq!(replayed.last(), Some(&Event::StateChanged(TorrentState::Completed)));
+}
+
+#[test]
+fn test_session_multi_file_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("content");
+    fs::create_dir_all(content.join("nested")).unwrap();
+    let data = TestTorrent::generate(70_000, 16_384, 43).data;
+    fs::write(content.join("first.bin"), &data[..30_000]).unwrap();
+    fs::write(content.join("nested/second.bin"), &data[30_000..]).unwrap();
+
+    let tracker = Tracker::spawn();
+    let built = TorrentBuilder::new(&content).piece_length(16_384).tier(vec![tracker.announce_url()]).build().unwrap();
+    // The mock peer serves the concatenated stream under the built torrent's info hash
+    let mut seed = TestTorrent::generate(70_000, 16_384, 43);
+    seed.info_hash = built.info.calculate_info_hash().unwrap();
+    let peer = MockPeer::spawn(&seed, MockPeerConfig::default());
+    peer.announce(&tracker.announce_url(), &seed);
+
+    let session = Session::new(SessionConfig::default()).unwrap();
+    let output = dir.path().join("download");
+    let handle = session.add_torrent(built, &output).unwrap();
+    handle.wait().unwrap();
+    assert_eq!(fs::read(output.join("first.bin")).unwrap(), &data[..30_000]);
+    assert_eq!(fs::read(output.join("nested/second.bin")).unwrap(), &data[30_000..]);
+}
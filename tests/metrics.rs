@@ -0,0 +1,79 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, TcpListener},
+    sync::Arc,
+    thread,
+};
+
+use bittorrent_starter_rust::{metrics, Session, SessionConfig, Torrent};
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    TestTorrent, Tracker,
+};
+
+// The value of the sample with exactly this name and these labels
+fn sample(text: &str, series: &str) -> f64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(series).and_then(|rest| rest.strip_prefix(' ')))
+        .unwrap_or_else(|| panic!("No {} in:\n{}", series, text))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn test_metrics_endpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(20 * 16_384, 16_384, 91);
+    let tracker = Tracker::spawn();
+    // One peer that's slow but honest, and one that gets every piece wrong
+    let good = MockPeer::spawn(&torrent, MockPeerConfig { bandwidth: Some(256 * 1024), ..Default::default() });
+    let bad_pieces: HashSet<u32> = (0..torrent.num_pieces() as u32).collect();
+    // On an address of its own, so banning it leaves the good one alone
+    let bad_ip = Some(Ipv4Addr::new(127, 0, 0, 2));
+    let bad = MockPeer::spawn(&torrent, MockPeerConfig { corrupt_pieces: bad_pieces, ip: bad_ip, ..Default::default() });
+    good.announce(&tracker.announce_url(), &torrent);
+    bad.announce(&tracker.announce_url(), &torrent);
+    let parsed = Torrent::read(&torrent.write(dir.path(), &tracker.announce_url())).unwrap();
+
+    let session = Arc::new(Session::new(SessionConfig { listen_port: 0, max_active_seeds: 0, ..Default::default() }).unwrap());
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let url = format!("http://{}{}", listener.local_addr().unwrap(), metrics::METRICS_PATH);
+    let serving = session.clone();
+    thread::spawn(move || metrics::serve(serving, listener));
+
+    let handle = session.add_torrent(parsed, &dir.path().join("out.bin")).unwrap();
+    handle.wait().unwrap();
+
+    let response = reqwest::blocking::get(&url).unwrap();
+    assert_eq!(response.status(), 200);
+    let text = response.text().unwrap();
+    let labels = format!("info_hash=\"{}\",name=\"test-91.bin\"", hex::encode(torrent.info_hash));
+    let torrent_sample = |name: &str| sample(&text, &format!("bittorrent_torrent_{}{{{}}}", name, labels));
+
+    let pieces = torrent.num_pieces() as f64;
+    assert_eq!(torrent_sample("pieces_verified_total"), pieces);
+    assert_eq!(sample(&text, "bittorrent_pieces_verified_total"), pieces);
+    let hash_failures = torrent_sample("hash_failures_total");
+    assert!(hash_failures >= 1.0, "{}", text);
+    assert_eq!(torrent_sample("pieces_failed_total"), hash_failures);
+    // Bad pieces count as downloaded too
+    assert_eq!(torrent_sample("downloaded_bytes_total"), (pieces + hash_failures) * 16_384.0);
+    // Started and completed, and maybe stopped by now
+    let announces = |result: &str| sample(&text, &format!("bittorrent_torrent_tracker_announces_total{{{},result=\"{}\"}}", labels, result));
+    assert!(announces("success") >= 2.0, "{}", text);
+    assert_eq!(announces("failure"), 0.0);
+    assert_eq!(sample(&text, &format!("bittorrent_torrent_disk_write_duration_seconds_count{{{}}}", labels)), pieces);
+    assert!(sample(&text, "bittorrent_tracker_announce_duration_seconds_bucket{le=\"+Inf\"}") >= 2.0);
+    assert_eq!(sample(&text, "bittorrent_torrents{state=\"completed\"}"), 1.0);
+
+    // Totals remember torrents that are gone
+    session.remove(&handle.info_hash(), false).unwrap();
+    let text = reqwest::blocking::get(&url).unwrap().text().unwrap();
+    assert_eq!(sample(&text, "bittorrent_pieces_verified_total"), pieces);
+    assert!(!text.contains("bittorrent_torrent_pieces_verified_total{"));
+
+    let response = reqwest::blocking::get(url.replace("/metrics", "/other")).unwrap();
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,282 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::Path,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{wire, Torrent};
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    run, TestTorrent, Tracker,
+};
+
+// 3.5 pieces of two blocks each, so the last piece and block are short
+const TORRENT_LEN: usize = 3 * 32_768 + 20_000;
+const PIECE_LEN: usize = 32_768;
+
+struct Setup {
+    torrent: TestTorrent,
+    peer: MockPeer,
+    torrent_path: String,
+    dir: tempfile::TempDir,
+    _tracker: Tracker,
+}
+
+fn setup(seed: u32, config: MockPeerConfig) -> Setup {
+    let torrent = TestTorrent::generate(TORRENT_LEN, PIECE_LEN, seed);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, config);
+    peer.announce(&tracker.announce_url(), &torrent);
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = torrent.write(dir.path(), &tracker.announce_url()).to_string_lossy().into_owned();
+    Setup { torrent, peer, torrent_path, dir, _tracker: tracker }
+}
+
+fn download(setup: &Setup) -> (std::process::Output, Vec<u8>) {
+    download_with_config(setup, "")
+}
+
+// Failed peers are tried again quickly, so tests that fail don't wait long
+fn download_with_config(setup: &Setup, config: &str) -> (std::process::Output, Vec<u8>) {
+    let config_path = setup.dir.path().join("config.toml");
+    fs::write(&config_path, format!("[network]\nreconnect_backoff = 0.05\n{}", config)).unwrap();
+    let output_path = setup.dir.path().join("download.bin");
+    let output = run(&["--config", config_path.to_str().unwrap(), "download", "-o", output_path.to_str().unwrap(), &setup.torrent_path]);
+    (output, fs::read(&output_path).unwrap_or_default())
+}
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_handshake_reports_peer_id() {
+    let setup = setup(1, MockPeerConfig::default());
+    let output = run(&["handshake", &setup.torrent_path, &setup.peer.addr.to_string()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, format!("Peer ID: {}\nClient: MK 0.0.0.1\n", hex::encode(setup.peer.peer_id)));
+}
+
+#[test]
+fn test_peer_id_is_ours_and_fresh_per_run() {
+    let setup = setup(17, MockPeerConfig::default());
+    for _ in 0..2 {
+        let output = run(&["handshake", &setup.torrent_path, &setup.peer.addr.to_string()]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let (output, _) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let peer_ids = setup.peer.peer_ids();
+    assert!(peer_ids.len() >= 3);
+    assert!(peer_ids.iter().all(|peer_id| peer_id.starts_with(b"-SR0100-")), "{:?}", peer_ids);
+    // Each run makes its own, and every connection of a run uses it
+    assert_ne!(peer_ids[0], peer_ids[1]);
+    assert!(peer_ids[2..].iter().all(|peer_id| *peer_id == peer_ids[2]));
+}
+
+#[test]
+fn test_peers_lists_mock_peer() {
+    let setup = setup(2, MockPeerConfig::default());
+    let output = run(&["peers", &setup.torrent_path]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), setup.peer.addr.to_string());
+}
+
+#[test]
+fn test_download_piece() {
+    let setup = setup(3, MockPeerConfig::default());
+    for piece in [0, 3] {
+        let output_path = setup.dir.path().join(format!("piece-{}", piece));
+        let output = run(&["download_piece", "-o", output_path.to_str().unwrap(), &setup.torrent_path, &piece.to_string()]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(fs::read(&output_path).unwrap(), setup.torrent.piece(piece));
+    }
+}
+
+#[test]
+fn test_download_piece_in_smaller_blocks() {
+    let setup = setup(16, MockPeerConfig::default());
+    let config = setup.dir.path().join("config.toml");
+    fs::write(&config, "[network]\nblock_size = 4096\n").unwrap();
+    let output_path = setup.dir.path().join("piece-3");
+    let args = ["--config", config.to_str().unwrap(), "--wire-trace", "download_piece", "-o", output_path.to_str().unwrap(), &setup.torrent_path, "3"];
+    let output = run(&args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(&output_path).unwrap(), setup.torrent.piece(3));
+    // 20000 bytes is four full blocks and a short one
+    let stderr = stderr(&output);
+    let requests: Vec<&str> = stderr.lines().filter(|line| line.contains("wire: request")).collect();
+    assert_eq!(requests.len(), 5, "{}", stderr);
+    assert!(requests[..4].iter().all(|line| line.ends_with("length=4096")), "{}", stderr);
+    assert!(requests[4].ends_with("begin=16384 length=3616"), "{}", stderr);
+}
+
+#[test]
+fn test_download() {
+    let setup = setup(4, MockPeerConfig::default());
+    let (output, data) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(data, setup.torrent.data);
+    assert_eq!(setup.peer.bytes_served(), TORRENT_LEN);
+    // Not a terminal, so progress is only logged, finishing with a summary
+    assert!(stderr(&output).contains("100.0% (4/4 pieces)"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_download_with_slow_replies() {
+    let config = MockPeerConfig { reply_delay: Duration::from_millis(20), ..Default::default() };
+    let setup = setup(5, config);
+    let (output, data) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(data, setup.torrent.data);
+}
+
+#[test]
+fn test_download_with_odd_message_order() {
+    let config = MockPeerConfig { chatty: true, reverse_blocks: true, ..Default::default() };
+    let setup = setup(6, config);
+    let (output, data) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(data, setup.torrent.data);
+}
+
+#[test]
+fn test_download_survives_choke() {
+    let config = MockPeerConfig { choke_after_blocks: Some(3), ..Default::default() };
+    let setup = setup(7, config);
+    let (output, data) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(data, setup.torrent.data);
+}
+
+#[test]
+fn test_download_rejects_corrupt_piece() {
+    let config = MockPeerConfig { corrupt_pieces: HashSet::from([1]), ..Default::default() };
+    let setup = setup(8, config);
+    let (output, _) = download(&setup);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Piece hash mismatch"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_download_reconnects_when_peer_drops() {
+    // A piece per connection, so every reconnect gets further
+    let config = MockPeerConfig { drop_after_blocks: Some(2), ..Default::default() };
+    let setup = setup(9, config);
+    let (output, data) = download(&setup);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(data, setup.torrent.data);
+    assert!(setup.peer.stats.connections.load(Ordering::Relaxed) >= 4);
+}
+
+#[test]
+fn test_download_gives_up_on_failing_peer() {
+    // Never a whole piece, so the peer fails every time
+    let config = MockPeerConfig { drop_after_blocks: Some(1), ..Default::default() };
+    let setup = setup(14, config);
+    let (output, _) = download_with_config(&setup, "max_peer_failures = 3\n");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("after 3 failed attempts"), "{}", stderr(&output));
+    assert_eq!(setup.peer.stats.connections.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn test_download_times_out_stalled_peer() {
+    let config = MockPeerConfig { stall_after_blocks: Some(3), ..Default::default() };
+    let setup = setup(15, config);
+    let started = Instant::now();
+    let (output, _) = download_with_config(&setup, "request_timeout = 0.3\nmax_peer_failures = 1\n");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Peer sent nothing for 300ms"), "{}", stderr(&output));
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_ip_filter_blocks_peers() {
+    let setup = setup(10, MockPeerConfig::default());
+    let filter = setup.dir.path().join("blocklist.txt");
+    fs::write(&filter, "Loopback:127.0.0.0-127.255.255.255\n").unwrap();
+    let filter = filter.to_str().unwrap();
+
+    let output = run(&["--ip-filter", filter, "peers", &setup.torrent_path]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    let output = run(&["--ip-filter", filter, "handshake", &setup.torrent_path, &setup.peer.addr.to_string()]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("blocked by the IP filter"), "{}", stderr(&output));
+    let (output, _) = download_with_config(&setup, &format!("ip_filter = {:?}\n", filter));
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No peers to download from"), "{}", stderr(&output));
+    assert_eq!(setup.peer.stats.connections.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_wire_trace() {
+    let setup = setup(12, MockPeerConfig::default());
+    let output_path = setup.dir.path().join("piece-1");
+    let output = run(&["--wire-trace", "download_piece", "-o", output_path.to_str().unwrap(), &setup.torrent_path, "1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    let peer = format!("peer=\"{}\"", setup.peer.addr);
+    let traced = |message: &str, fields: &str| {
+        stderr.lines().any(|line| line.contains(&format!("wire: {} {}", message, peer)) && line.contains(fields))
+    };
+    assert!(traced("handshake", "direction=\"received\""), "{}", stderr);
+    assert!(traced("bitfield", "direction=\"received\""), "{}", stderr);
+    // Both blocks of the piece, asked for and received
+    for begin in [0, 16_384] {
+        let block = format!("index=1 begin={} length=16384", begin);
+        assert!(traced("request", &format!("direction=\"sent\" {}", block)), "{}", stderr);
+        assert!(traced("piece", &format!("direction=\"received\" {}", block)), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_capture_and_replay() {
+    let setup = setup(13, MockPeerConfig { chatty: true, choke_after_blocks: Some(1), corrupt_pieces: HashSet::from([2]), ..Default::default() });
+    let capture_dir = setup.dir.path().join("capture");
+    let capture = capture_dir.to_str().unwrap();
+    let download = |piece: &str| {
+        let output_path = setup.dir.path().join(format!("piece-{}", piece));
+        run(&["--capture", capture, "download_piece", "-o", output_path.to_str().unwrap(), &setup.torrent_path, piece])
+    };
+    let output = download("1");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let good: Vec<_> = fs::read_dir(&capture_dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(good.len(), 1);
+    assert!(!download("2").status.success());
+    let bad = fs::read_dir(&capture_dir).unwrap().map(|entry| entry.unwrap().path()).find(|path| !good.contains(path)).unwrap();
+
+    // The peer isn't needed any more
+    drop(setup.peer);
+    let replay = |recording: &Path| {
+        let output = run(&["--json", "replay", recording.to_str().unwrap(), &setup.torrent_path]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+    let replayed = replay(&good[0]);
+    assert_eq!(replayed["pieces"], serde_json::json!([1]));
+    assert_eq!(replayed["error"], serde_json::Value::Null);
+    let replayed = replay(&bad);
+    assert_eq!(replayed["pieces"], serde_json::json!([]));
+    assert_eq!(replayed["error"], "Piece hash mismatch");
+}
+
+// A recording of a peer sending keep-alives and haves around its bitfield
+// and unchoke, kept so the same bytes go through the parser on every run
+#[test]
+fn test_replay_recorded_chatty_peer() {
+    let torrent = TestTorrent::generate(6_000, 4_096, 15);
+    let dir = tempfile::tempdir().unwrap();
+    let parsed = Torrent::read(&torrent.write(dir.path(), "http://127.0.0.1:1/announce")).unwrap();
+    let recording = wire::Recording::load(Path::new("tests/fixtures/wire/chatty-piece-1.wire")).unwrap();
+    assert!(!recording.incoming);
+    let replayed = wire::replay(&recording, &parsed.info, &torrent.info_hash);
+    replayed.result.unwrap();
+    assert_eq!(replayed.pieces, vec![1]);
+}
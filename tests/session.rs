@@ -0,0 +1,296 @@
+mod common;
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{
+    download::ReconnectPolicy,
+    ipfilter::IpFilter,
+    peer::{PeerConnection, Timeouts},
+    peer_id::generate_peer_id,
+    Event, Session, SessionConfig, Torrent, TorrentHandle, TorrentState,
+};
+use common::{
+    mock_peer::{MockPeer, MockPeerConfig},
+    TestTorrent, Tracker,
+};
+
+fn config() -> SessionConfig {
+    SessionConfig { listen_port: 0, max_active_seeds: 0, ..Default::default() }
+}
+
+// A tracker and a slow seeder for a fresh torrent, so downloads take long
+// enough to catch them halfway
+fn seeded(seed: u32, dir: &Path) -> (TestTorrent, Torrent, Tracker, MockPeer) {
+    let torrent = TestTorrent::generate(20 * 16_384, 16_384, seed);
+    let tracker = Tracker::spawn();
+    let peer = MockPeer::spawn(&torrent, MockPeerConfig { bandwidth: Some(256 * 1024), ..Default::default() });
+    peer.announce(&tracker.announce_url(), &torrent);
+    let parsed = Torrent::read(&torrent.write(dir, &tracker.announce_url())).unwrap();
+    (torrent, parsed, tracker, peer)
+}
+
+fn wait_for_pieces(handle: &TorrentHandle, count: usize) {
+    let pieces = handle
+        .subscribe()
+        .into_iter()
+        .filter(|event| matches!(event, Event::PieceVerified { .. }))
+        .take(count)
+        .count();
+    assert_eq!(pieces, count);
+}
+
+#[test]
+fn test_pause_resume_and_remove() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, parsed, _tracker, _peer) = seeded(61, dir.path());
+    let session = Session::new(config()).unwrap();
+    let output = dir.path().join("out.bin");
+    let handle = session.add_torrent(parsed, &output).unwrap();
+
+    wait_for_pieces(&handle, 2);
+    session.pause(&handle.info_hash()).unwrap();
+    assert_eq!(handle.state(), TorrentState::Paused);
+    assert!(handle.wait().is_err());
+    let paused_at = handle.progress().pieces_done;
+    assert!(paused_at < torrent.num_pieces(), "{}", paused_at);
+
+    session.resume(&handle.info_hash()).unwrap();
+    handle.wait().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+
+    session.remove(&handle.info_hash(), true).unwrap();
+    assert!(session.torrents().is_empty());
+    assert!(!output.exists());
+    assert!(session.resume(&handle.info_hash()).is_err());
+}
+
+#[test]
+fn test_queue_limits_active_downloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let (first_torrent, first, _first_tracker, _first_peer) = seeded(62, dir.path());
+    let (second_torrent, second, _second_tracker, _second_peer) = seeded(63, dir.path());
+    let session = Session::new(SessionConfig { max_active_downloads: 1, ..config() }).unwrap();
+
+    let first = session.add_torrent(first, &dir.path().join("first.bin")).unwrap();
+    let second = session.add_torrent(second, &dir.path().join("second.bin")).unwrap();
+    assert_eq!(first.state(), TorrentState::Downloading);
+    assert_eq!(second.state(), TorrentState::Queued);
+
+    first.wait().unwrap();
+    second.wait().unwrap();
+    assert_eq!(fs::read(dir.path().join("first.bin")).unwrap(), first_torrent.data);
+    assert_eq!(fs::read(dir.path().join("second.bin")).unwrap(), second_torrent.data);
+    let order: Vec<[u8; 20]> = session.torrents().iter().map(TorrentHandle::info_hash).collect();
+    assert_eq!(order, [first.info_hash(), second.info_hash()]);
+}
+
+#[test]
+fn test_state_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, parsed, _tracker, _peer) = seeded(64, dir.path());
+    let state_dir = dir.path().join("state");
+    let output = dir.path().join("out.bin");
+
+    let session = Session::new(SessionConfig { state_dir: Some(state_dir.clone()), ..config() }).unwrap();
+    let handle = session.add_torrent(parsed, &output).unwrap();
+    wait_for_pieces(&handle, 2);
+    session.pause(&handle.info_hash()).unwrap();
+    drop(session);
+    // A piece in flight when we paused may still have made it in
+    let paused_at = handle.progress().pieces_done;
+
+    let session = Session::new(SessionConfig { state_dir: Some(state_dir), ..config() }).unwrap();
+    let restored = session.torrent(&handle.info_hash()).unwrap();
+    assert_eq!(restored.state(), TorrentState::Paused);
+    assert_eq!(restored.output(), output);
+    assert_eq!(restored.progress().pieces_done, paused_at);
+
+    session.resume(&restored.info_hash()).unwrap();
+    restored.wait().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+    // Only the pieces missing at the restart were downloaded again
+    let downloaded = restored
+        .subscribe()
+        .try_iter()
+        .filter(|event| matches!(event, Event::PieceVerified { .. }))
+        .count();
+    assert_eq!(downloaded, torrent.num_pieces() - paused_at);
+}
+
+#[test]
+fn test_bans_peer_that_sent_corrupt_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(8 * 16_384, 16_384, 67);
+    let tracker = Tracker::spawn();
+    // The good seeder is slow, so the bad one gets to send pieces first
+    let good = MockPeer::spawn(&torrent, MockPeerConfig { bandwidth: Some(64 * 1024), ..Default::default() });
+    let bad_ip = Ipv4Addr::new(127, 0, 0, 2);
+    let corrupt_pieces = HashSet::from_iter(0..torrent.num_pieces() as u32);
+    let bad = MockPeer::spawn(&torrent, MockPeerConfig { ip: Some(bad_ip), corrupt_pieces, ..Default::default() });
+    for peer in [&good, &bad] {
+        peer.announce(&tracker.announce_url(), &torrent);
+    }
+    let parsed = Torrent::read(&torrent.write(dir.path(), &tracker.announce_url())).unwrap();
+    let state_dir = dir.path().join("state");
+    let output = dir.path().join("out.bin");
+    let reconnect = ReconnectPolicy { backoff: Duration::from_millis(50), ..Default::default() };
+    let config = SessionConfig { state_dir: Some(state_dir.clone()), reconnect, ..config() };
+
+    let session = Session::new(config.clone()).unwrap();
+    let handle = session.add_torrent(parsed, &output).unwrap();
+    handle.wait().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+    assert!(bad.blocks_served() > 0);
+    let bad_ip = IpAddr::from(bad_ip);
+    assert!(handle.subscribe().try_iter().any(|event| matches!(event, Event::PeerBanned(ip) if ip == bad_ip)));
+    assert_eq!(session.banned_peers(), vec![bad_ip]);
+    drop(session);
+
+    let session = Session::new(config).unwrap();
+    assert_eq!(session.banned_peers(), vec![bad_ip]);
+}
+
+#[test]
+fn test_torrent_rate_limits() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, parsed, _tracker, _peer) = seeded(66, dir.path());
+    let state_dir = dir.path().join("state");
+    let output = dir.path().join("out.bin");
+
+    let session = Session::new(SessionConfig { state_dir: Some(state_dir.clone()), ..config() }).unwrap();
+    let handle = session.add_torrent_paused(parsed, &output).unwrap();
+    session.set_torrent_rate_limits(&handle.info_hash(), Some(64 * 1024), None).unwrap();
+    session.resume(&handle.info_hash()).unwrap();
+    // A second's burst and then 64 KiB/s, where the seeder alone would finish in under 1.5s
+    thread::sleep(Duration::from_millis(1500));
+    let pieces_done = handle.progress().pieces_done;
+    assert!(pieces_done < 14, "{}", pieces_done);
+
+    session.set_torrent_rate_limits(&handle.info_hash(), None, Some(1000)).unwrap();
+    handle.wait().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), torrent.data);
+    drop(session);
+
+    // The torrent's limits are saved along with it
+    let session = Session::new(SessionConfig { state_dir: Some(state_dir), ..config() }).unwrap();
+    assert_eq!(session.torrent(&handle.info_hash()).unwrap().rate_limits(), (None, Some(1000)));
+    session.set_peer_rate_limits(Some(5000), None);
+    assert_eq!((session.rate_limits(), session.peer_rate_limits()), ((None, None), (Some(5000), None)));
+}
+
+#[test]
+fn test_seeds_existing_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(50_000, 16_384, 65);
+    let tracker = Tracker::spawn();
+    let parsed = Torrent::read(&torrent.write(dir.path(), &tracker.announce_url())).unwrap();
+    let output = dir.path().join("seed.bin");
+    fs::write(&output, &torrent.data).unwrap();
+
+    let session = Session::new(SessionConfig { max_active_seeds: 1, ..config() }).unwrap();
+    let handle = session.add_torrent(parsed, &output).unwrap();
+    handle.wait().unwrap();
+    while handle.state() != TorrentState::Seeding {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(handle.progress().pieces_done, torrent.num_pieces());
+    assert_eq!((handle.rates(), handle.eta()), ((0, 0), None));
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, session.listen_addr().port()));
+    let mut connection = PeerConnection::connect(addr, &torrent.info_hash, &generate_peer_id(), torrent.num_pieces(), Vec::new(), Timeouts::default()).unwrap();
+    assert!((0..torrent.num_pieces() as u32).all(|index| connection.has_piece(index)));
+    let last = torrent.num_pieces() - 1;
+    let piece = connection.download_piece(last as u32, torrent.piece(last).len() as u32, &torrent.piece_hashes[last]).unwrap();
+    assert_eq!(piece, torrent.piece(last));
+    // Counted once it's sent, which may be a moment after it arrived
+    while handle.progress().uploaded < piece.len() as u64 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(handle.progress().uploaded, piece.len() as u64);
+    let (download_rate, upload_rate) = handle.rates();
+    assert!(download_rate == 0 && upload_rate > 0, "{:?}", handle.rates());
+    let peers = handle.peers();
+    assert_eq!(peers.len(), 1);
+    assert!(peers[0].incoming);
+    assert_eq!(peers[0].uploaded, piece.len() as u64);
+}
+
+#[test]
+fn test_carries_on_without_tracker() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(50_000, 16_384, 67);
+    // Nothing listens there
+    let parsed = Torrent::read(&torrent.write(dir.path(), "http://127.0.0.1:1/announce")).unwrap();
+    let seed_path = dir.path().join("seed.bin");
+    fs::write(&seed_path, &torrent.data).unwrap();
+    let session = Session::new(SessionConfig { max_active_seeds: 1, ..config() }).unwrap();
+
+    // A complete torrent seeds anyway
+    let seed = session.add_torrent(parsed.clone(), &seed_path).unwrap();
+    let events = seed.subscribe();
+    assert!(events.iter().any(|event| matches!(event, Event::TrackerError(_))));
+    while seed.state() != TorrentState::Seeding {
+        thread::sleep(Duration::from_millis(10));
+    }
+    session.remove(&seed.info_hash(), false).unwrap();
+
+    // An incomplete one waits for the tracker rather than failing
+    let leech = session.add_torrent(parsed, &dir.path().join("leech.bin")).unwrap();
+    let events = leech.subscribe();
+    assert!(events.iter().any(|event| matches!(event, Event::TrackerError(_))));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(leech.state(), TorrentState::Downloading);
+    session.remove(&leech.info_hash(), false).unwrap();
+}
+
+#[test]
+fn test_ip_filter_turns_away_incoming_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(50_000, 16_384, 68);
+    let tracker = Tracker::spawn();
+    let parsed = Torrent::read(&torrent.write(dir.path(), &tracker.announce_url())).unwrap();
+    let output = dir.path().join("seed.bin");
+    fs::write(&output, &torrent.data).unwrap();
+
+    let ip_filter = Arc::new(IpFilter::parse("127.0.0.1\n").0);
+    let session = Session::new(SessionConfig { max_active_seeds: 1, ip_filter, ..config() }).unwrap();
+    let handle = session.add_torrent(parsed, &output).unwrap();
+    handle.wait().unwrap();
+    while handle.state() != TorrentState::Seeding {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, session.listen_addr().port()));
+    let connection = PeerConnection::connect(addr, &torrent.info_hash, &generate_peer_id(), torrent.num_pieces(), Vec::new(), Timeouts::default());
+    assert!(connection.is_err());
+    assert!(handle.peers().is_empty());
+}
+
+#[test]
+fn test_move_torrent_in_queue() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config()).unwrap();
+    let handles: Vec<TorrentHandle> = (66..69)
+        .map(|seed| {
+            let torrent = TestTorrent::generate(16_384, 16_384, seed);
+            let parsed = Torrent::read(&torrent.write(dir.path(), "http://127.0.0.1:1/announce")).unwrap();
+            session.add_torrent_paused(parsed, &dir.path().join(format!("{}.bin", seed))).unwrap()
+        })
+        .collect();
+    let order = || session.torrents().iter().map(TorrentHandle::info_hash).collect::<Vec<_>>();
+
+    session.move_torrent(&handles[2].info_hash(), 0).unwrap();
+    assert_eq!(order(), [handles[2].info_hash(), handles[0].info_hash(), handles[1].info_hash()]);
+    // Past the end means last
+    session.move_torrent(&handles[2].info_hash(), 10).unwrap();
+    assert_eq!(order(), [handles[0].info_hash(), handles[1].info_hash(), handles[2].info_hash()]);
+    assert!(session.move_torrent(&[0; 20], 0).is_err());
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use std::{collections::HashSet, time::Duration};
+
+use common::{mock_peer::MockPeerConfig, swarm::Swarm, TestTorrent};
+
+// 40 single-block pieces, enough to spread over a handful of seeders
+const TORRENT_LEN: usize = 40 * 16_384 - 5_000;
+const PIECE_LEN: usize = 16_384;
+
+fn torrent(seed: u32) -> TestTorrent {
+    TestTorrent::generate(TORRENT_LEN, PIECE_LEN, seed)
+}
+
+fn throttled(bandwidth: usize) -> MockPeerConfig {
+    MockPeerConfig { bandwidth: Some(bandwidth), ..Default::default() }
+}
+
+#[test]
+fn test_load_spreads_across_seeders() {
+    let report = Swarm::new(torrent(101)).seeders(4, throttled(512 * 1024)).run();
+    report.assert_complete();
+    assert_eq!(report.total_served(), TORRENT_LEN);
+    assert!(report.bytes_served.iter().all(|&served| served > 0), "{:?}", report.bytes_served);
+    let fairness = report.fairness(&[0, 1, 2, 3]);
+    assert!(fairness > 0.8, "fairness {} for {:?}", fairness, report.bytes_served);
+}
+
+#[test]
+fn test_concurrent_leechers() {
+    let report = Swarm::new(torrent(102)).seeders(3, throttled(1024 * 1024)).leechers(3).run();
+    report.assert_complete();
+    assert_eq!(report.total_served(), 3 * TORRENT_LEN);
+}
+
+#[test]
+fn test_seeder_leaving_mid_download() {
+    let leaving = MockPeerConfig { lifetime: Some(Duration::from_millis(300)), ..throttled(64 * 1024) };
+    let report = Swarm::new(torrent(103)).seeder(leaving).seeder(throttled(256 * 1024)).run();
+    report.assert_complete();
+    assert!(report.bytes_served[0] > 0 && report.bytes_served[0] < TORRENT_LEN, "{:?}", report.bytes_served);
+}
+
+#[test]
+fn test_partial_seeders() {
+    // Nobody has everything, and piece 7 only exists on the last seeder
+    let even = (0..40).filter(|i| i % 2 == 0).collect();
+    let odd = (0..40).filter(|i| i % 2 == 1 && *i != 7).collect();
+    let seeders = [even, odd, HashSet::from([7, 8, 9])];
+    let swarm = seeders.into_iter().fold(Swarm::new(torrent(104)), |swarm, pieces| {
+        swarm.seeder(MockPeerConfig { pieces: Some(pieces), ..Default::default() })
+    });
+    let report = swarm.run();
+    report.assert_complete();
+    assert_eq!(report.total_served(), TORRENT_LEN);
+    assert!(report.bytes_served[2] >= PIECE_LEN, "{:?}", report.bytes_served);
+}
+
+#[test]
+fn test_fast_seeder_serves_more() {
+    let slow = MockPeerConfig { reply_delay: Duration::from_millis(20), ..throttled(64 * 1024) };
+    let report = Swarm::new(torrent(105)).seeder(slow).seeder(MockPeerConfig::default()).run();
+    report.assert_complete();
+    assert!(report.bytes_served[1] > 2 * report.bytes_served[0], "{:?}", report.bytes_served);
+}
+
+#[test]
+fn test_download_from_client_seeders() {
+    let report = Swarm::new(torrent(107)).client_seeders(2).leechers(2).run();
+    report.assert_complete();
+    // Each leecher completed with only this client to download from. The
+    // leechers trade pieces too, so the seeders sent at least one copy and
+    // the other leecher may have sent part of the second.
+    assert_eq!(report.leechers.len(), 2);
+    assert!(report.bytes_served.is_empty());
+    let served: u64 = report.client_bytes_served.iter().sum();
+    assert!(served >= TORRENT_LEN as u64 && served <= 2 * TORRENT_LEN as u64, "{:?}", report.client_bytes_served);
+}
+
+#[test]
+fn test_download_fails_when_swarm_dies() {
+    let leaving = MockPeerConfig { lifetime: Some(Duration::from_millis(200)), ..throttled(64 * 1024) };
+    let report = Swarm::new(torrent(106)).seeders(2, leaving).run();
+    assert!(!report.leechers[0].output.status.success());
+    assert!(report.leechers[0].stderr().contains("pieces missing"), "{}", report.leechers[0].stderr());
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use bittorrent_starter_rust::{
+    http_server::{Request, Response},
+    rpc::RpcServer,
+    Session, SessionConfig, Torrent, TorrentState,
+};
+use common::TestTorrent;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/transmission");
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+fn server(download_dir: &Path) -> (Arc<Session>, RpcServer) {
+    let session = Arc::new(Session::new(SessionConfig { listen_port: 0, max_active_seeds: 0, ..Default::default() }).unwrap());
+    let server = RpcServer::new(session.clone(), download_dir.to_path_buf()).with_transmission();
+    (session, server)
+}
+
+fn post(server: &RpcServer, session_id: Option<&str>, body: &Value) -> Response {
+    let request = Request {
+        method: "POST".to_string(),
+        path: "/transmission/rpc".to_string(),
+        query: Vec::new(),
+        headers: session_id.map(|id| (SESSION_ID_HEADER.to_string(), id.to_string())).into_iter().collect(),
+        body: body.to_string().into_bytes(),
+        remote_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 40000)),
+    };
+    server.handle_http(&request)
+}
+
+// Gets a session id the way clients do, from the 409 a request without one
+// is answered with
+fn session_id(server: &RpcServer) -> String {
+    let response = post(server, None, &json!({ "method": "session-get" }));
+    assert_eq!(response.status, 409);
+    response.headers.iter().find(|(name, _)| name == SESSION_ID_HEADER).unwrap().1.clone()
+}
+
+// Whether `actual` is what the fixture expects, where "{{*}}" matches
+// anything
+fn matches(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        (Value::String(wildcard), _) if wildcard == "{{*}}" => true,
+        (Value::Object(expected), Value::Object(actual)) => {
+            expected.len() == actual.len() && expected.iter().all(|(key, value)| actual.get(key).is_some_and(|other| matches(value, other)))
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            expected.len() == actual.len() && expected.iter().zip(actual).all(|(expected, actual)| matches(expected, actual))
+        }
+        _ => expected == actual,
+    }
+}
+
+// Replays a fixture of recorded exchanges, filling in its placeholders
+fn replay(server: &RpcServer, fixture: &str, placeholders: &[(&str, String)]) {
+    let mut text = fs::read_to_string(Path::new(FIXTURES).join(fixture)).unwrap();
+    for (name, value) in placeholders {
+        text = text.replace(&format!("{{{{{}}}}}", name), value);
+    }
+    let exchanges: Vec<Value> = serde_json::from_str(&text).unwrap();
+    let session_id = session_id(server);
+    for exchange in exchanges {
+        let response = post(server, Some(&session_id), &exchange["request"]);
+        assert_eq!(response.status, 200);
+        let actual: Value = serde_json::from_slice(&response.body).unwrap();
+        assert!(matches(&exchange["response"], &actual), "{} answered with {}", exchange["request"], actual);
+    }
+}
+
+#[test]
+fn test_session_fixture() {
+    let dir = tempfile::tempdir().unwrap();
+    let (session, server) = server(dir.path());
+    replay(&server, "session.json", &[("download_dir", dir.path().display().to_string())]);
+    assert_eq!(session.rate_limits(), (Some(500_000), None));
+}
+
+#[test]
+fn test_torrents_fixture() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::generate(4 * 16_384, 16_384, 71);
+    // Nothing listens there, so the torrent never gets anywhere once started
+    let metainfo = fs::read(torrent.write(dir.path(), "http://127.0.0.1:1/announce")).unwrap();
+    let download_dir = dir.path().join("downloads");
+    let (session, server) = server(dir.path());
+    replay(
+        &server,
+        "torrents.json",
+        &[
+            ("metainfo", BASE64.encode(metainfo)),
+            ("download_dir", download_dir.display().to_string()),
+            ("hash", hex::encode(torrent.info_hash)),
+        ],
+    );
+    assert!(session.torrents().is_empty());
+}
+
+#[test]
+fn test_ids_cover_torrents_it_has_not_listed() {
+    let dir = tempfile::tempdir().unwrap();
+    let (session, server) = server(dir.path());
+    // Added behind the endpoint's back, like a torrent restored from the state dir
+    let torrent = TestTorrent::generate(16_384, 16_384, 74);
+    let parsed = Torrent::read(&torrent.write(dir.path(), "http://127.0.0.1:1/announce")).unwrap();
+    let handle = session.add_torrent_paused(parsed, &dir.path().join("74.bin")).unwrap();
+
+    let id = session_id(&server);
+    let response = post(&server, Some(&id), &json!({ "method": "torrent-start", "arguments": { "ids": [1] } }));
+    assert_eq!(serde_json::from_slice::<Value>(&response.body).unwrap()["result"], "success");
+    assert_ne!(handle.state(), TorrentState::Paused);
+}
+
+#[test]
+fn test_requires_session_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_session, server) = server(dir.path());
+    let request = json!({ "method": "session-get" });
+    assert_eq!(post(&server, Some("stale"), &request).status, 409);
+
+    let id = session_id(&server);
+    let response = post(&server, Some(&id), &request);
+    assert_eq!(response.status, 200);
+    // Same id for the whole run
+    assert_eq!(session_id(&server), id);
+}